@@ -1,32 +1,130 @@
-use std::{io::Write, net::TcpStream};
+use std::{collections::VecDeque, error::Error, time::Duration};
 
+use futures::{Stream, StreamExt};
 use iced::{
-    Background, Border, Color, Font, Shadow, Theme,
-    widget::{Button, Column, Text, button::Style, slider},
+    Background, Border, Color, Font, Shadow, Subscription, Task, Theme,
+    widget::{Button, Column, Row, Text, button::Style, slider},
 };
-use otus_iced::{power::Power, socket::Socket, state::DeviceState};
+use otus_iced::{
+    client::{ClientEvent, ClientHandle, ConnectionStatus, DeliveryError, DeviceClient},
+    device::{Capability, DeviceConfig, DeviceKind, SensorData},
+    power::Power,
+    protocol::{Hello, PROTOCOL_VERSIONS},
+    server::DEFAULT_ADDRESS,
+    socket::Socket,
+    state::DeviceState,
+    status::StatusResponder,
+};
+use tokio::net::TcpListener;
+
+// How many of the latest readings are listed with their delivery status.
+const HISTORY: usize = 5;
+
+// Reporting settings used until the server pushes its own.
+const DEFAULT_INTERVAL: &str = "5";
+const DEFAULT_DEADBAND: &str = "10";
+
+const USAGE: &str = "Использование: cli_socket [--interval <с>] [--deadband <Вт>] \
+                     [--max-power <Вт>] [--listen <адрес>]";
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let (config, listen) = local_config(std::env::args().skip(1))?;
 
-pub fn main() -> iced::Result {
     iced::application("Розетка", SocketApp::update, SocketApp::view)
-        .window_size(iced::Size::new(450f32, 225f32))
+        .subscription(SocketApp::subscription)
+        .window_size(iced::Size::new(450f32, 400f32))
         .theme(|_| iced::Theme::GruvboxDark)
-        .run()
+        .run_with(move || (SocketApp::new(config, listen), Task::none()))?;
+
+    Ok(())
+}
+
+// The reporting settings, and where to wait for the server with --listen.
+fn local_config(
+    mut args: impl Iterator<Item = String>,
+) -> Result<(DeviceConfig, Option<String>), String> {
+    let mut interval = DEFAULT_INTERVAL.to_string();
+    let mut deadband = DEFAULT_DEADBAND.to_string();
+    let mut listen = None;
+    let mut max_power = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--interval" => interval = args.next().ok_or(USAGE)?,
+            "--deadband" => deadband = args.next().ok_or(USAGE)?,
+            "--listen" => listen = Some(args.next().ok_or(USAGE)?),
+            "--max-power" => max_power = Some(args.next().ok_or(USAGE)?),
+            _ => return Err(format!("Неизвестный аргумент: {arg}\n{USAGE}")),
+        }
+    }
+
+    let mut line = format!("interval={interval} deadband={deadband}");
+
+    if let Some(max_power) = max_power {
+        line.push_str(&format!(" max_power={max_power}"));
+    }
+
+    let config = line
+        .parse()
+        .map_err(|e| format!("Неверные настройки: {e}\n{USAGE}"))?;
+
+    Ok((config, listen))
 }
 
 #[derive(Debug, Clone)]
 enum Message {
     TogglePower,
     SliderChanged(f32),
+    Ready(ClientHandle),
+    Listening(StatusResponder),
+    ListenFailed(String),
+    Client(ClientEvent),
+    Delivered(u64, Result<(), DeliveryError>),
+    Report,
+}
+
+#[derive(Debug, Clone)]
+enum Delivery {
+    Pending,
+    Sent,
+    Failed(DeliveryError),
+}
+
+struct Outgoing {
+    id: u64,
+    reading: String,
+    delivery: Delivery,
 }
 
 #[derive(Default)]
 struct SocketApp {
     state: bool,
     power: f32,
+    // The value last handed to the client, for the deadband.
+    reported: Option<f32>,
+    config: DeviceConfig,
+    // Whether `config` came from the server rather than the command line.
+    configured: bool,
+    client: Option<ClientHandle>,
+    connection: Option<ConnectionStatus>,
+    // With --listen the server polls the simulator instead.
+    listen: Option<String>,
+    responder: Option<StatusResponder>,
+    listen_failure: Option<String>,
+    next_id: u64,
+    outgoing: VecDeque<Outgoing>,
 }
 
 impl SocketApp {
-    fn update(&mut self, message: Message) {
+    fn new(config: DeviceConfig, listen: Option<String>) -> Self {
+        Self {
+            config,
+            listen,
+            ..Self::default()
+        }
+    }
+
+    fn update(&mut self, message: Message) -> Task<Message> {
         match message {
             Message::TogglePower => {
                 self.state = !self.state;
@@ -35,19 +133,71 @@ impl SocketApp {
                     self.power = 0f32;
                 }
 
-                self.notify();
+                self.notify()
             }
             Message::SliderChanged(value) => {
                 if self.state {
-                    self.power = value;
+                    self.power = self.config.max_power.map_or(value, |max| value.min(max));
                 }
 
-                self.notify();
+                self.notify_changed()
+            }
+            Message::Ready(client) => {
+                self.client = Some(client);
+                self.notify()
+            }
+            Message::Listening(responder) => {
+                self.responder = Some(responder);
+                self.notify()
+            }
+            Message::ListenFailed(reason) => {
+                self.listen_failure = Some(reason);
+
+                Task::none()
+            }
+            Message::Client(ClientEvent::Status(status)) => {
+                self.connection = Some(status);
+
+                Task::none()
+            }
+            Message::Client(ClientEvent::Command(request)) => {
+                self.state = request.state.get();
+
+                if !self.state {
+                    self.power = 0f32;
+                }
+
+                if let Some(client) = &self.client {
+                    client.reply(&request, Ok(DeviceState::new(self.state)));
+                }
+
+                self.notify()
+            }
+            Message::Client(ClientEvent::Config(config)) => {
+                self.config = config;
+                self.configured = true;
+
+                if let Some(max) = config.max_power {
+                    self.power = self.power.min(max);
+                }
+
+                self.notify()
+            }
+            Message::Report => self.notify(),
+            Message::Delivered(id, result) => {
+                if let Some(outgoing) = self.outgoing.iter_mut().find(|o| o.id == id) {
+                    outgoing.delivery = match result {
+                        Ok(()) => Delivery::Sent,
+                        Err(e) => Delivery::Failed(e),
+                    };
+                }
+
+                Task::none()
             }
         }
     }
 
-    fn view(&self) -> Column<Message> {
+    fn view(&self) -> Column<'_, Message> {
         let roboto = Font::with_name("Roboto");
 
         let power_button = Button::new(
@@ -88,22 +238,179 @@ impl SocketApp {
             .font(roboto)
             .size(24);
 
-        let content = Column::new()
+        let config_display = Text::new(self.config_details()).font(roboto).size(14);
+
+        let connection = self
+            .connection
+            .as_ref()
+            .unwrap_or(&ConnectionStatus::Connecting);
+
+        let indicator = match connection {
+            ConnectionStatus::Connected => Color::from_rgb8(0x98, 0x97, 0x1a),
+            ConnectionStatus::Connecting => Color::from_rgb8(0xd7, 0x99, 0x21),
+            ConnectionStatus::Unreachable { .. } => Color::from_rgb8(0xcc, 0x24, 0x1d),
+        };
+
+        let (indicator, connection) = match (&self.listen, &self.listen_failure) {
+            (Some(_), Some(reason)) => (Color::from_rgb8(0xcc, 0x24, 0x1d), reason.clone()),
+            (Some(address), None) => (
+                Color::from_rgb8(0x98, 0x97, 0x1a),
+                format!("Ожидает опроса на {address}"),
+            ),
+            (None, _) => (indicator, connection.to_string()),
+        };
+
+        let connection_display = Row::new()
+            .spacing(6)
+            .push(Text::new("●").color(indicator).size(14))
+            .push(Text::new(connection).font(roboto).size(14));
+
+        let deliveries = self
+            .outgoing
+            .iter()
+            .fold(Column::new().spacing(2), |column, outgoing| {
+                let status = match &outgoing.delivery {
+                    Delivery::Pending => "ожидает".to_string(),
+                    Delivery::Sent => "отправлено".to_string(),
+                    Delivery::Failed(e) => format!("не отправлено: {e}"),
+                };
+
+                column.push(
+                    Text::new(format!("{} — {status}", outgoing.reading))
+                        .font(roboto)
+                        .size(12),
+                )
+            });
+
+        Column::new()
             .spacing(10)
             .padding(20)
             .push(power_label)
             .push(power_display)
             .push(power_slider)
-            .push(power_button);
+            .push(power_button)
+            .push(config_display)
+            .push(connection_display)
+            .push(deliveries)
+    }
+
+    fn subscription(&self) -> Subscription<Message> {
+        let client = match &self.listen {
+            Some(address) => Subscription::run_with_id(address.clone(), listen(address.clone())),
+            None => Subscription::run(connect),
+        };
+
+        match self.config.interval {
+            0 => client,
+            interval => Subscription::batch([
+                client,
+                iced::time::every(Duration::from_secs(interval.into())).map(|_| Message::Report),
+            ]),
+        }
+    }
+
+    fn config_details(&self) -> String {
+        let mut details = match self.config.interval {
+            0 => "Отчёт при изменении".to_string(),
+            interval => format!("Отчёт каждые {interval} с"),
+        };
+        details.push_str(&format!(", порог {}", self.config.deadband));
+
+        if let Some(max_power) = self.config.max_power {
+            details.push_str(&format!(", предел {max_power} Вт"));
+        }
+
+        if self.configured {
+            details.push_str(" (задано сервером)");
+        }
+
+        details
+    }
 
-        content.into()
+    // Slider moves smaller than the deadband are not worth a reading; the
+    // next periodic report carries them.
+    fn notify_changed(&mut self) -> Task<Message> {
+        match self.reported {
+            Some(reported) if (self.power - reported).abs() < self.config.deadband => Task::none(),
+            _ => self.notify(),
+        }
     }
 
-    fn notify(&self) {
+    // Queues the current state and tracks it until the client reports whether
+    // it reached the server, or leaves it for the server to poll. Nothing here
+    // waits for the network.
+    fn notify(&mut self) -> Task<Message> {
         let socket = Socket::new(Power::new(self.power), DeviceState::new(self.state));
+        let reading = SensorData::SocketIndicator(socket);
+
+        // Polled by the server: the reading waits for the next "status?".
+        if let Some(responder) = &self.responder {
+            self.reported = Some(self.power);
+            responder.set(reading);
+
+            return Task::none();
+        }
+
+        let Some(client) = self.client.clone() else {
+            return Task::none();
+        };
+
+        self.reported = Some(self.power);
+        self.next_id += 1;
+        let id = self.next_id;
+
+        self.outgoing.push_front(Outgoing {
+            id,
+            reading: reading.to_string(),
+            delivery: Delivery::Pending,
+        });
+        self.outgoing.truncate(HISTORY);
+
+        Task::perform(client.deliver(reading), move |result| {
+            Message::Delivered(id, result)
+        })
+    }
+}
 
-        let mut tcp_stream = TcpStream::connect("localhost:8080").expect("Unable to connect");
+// Readings written while the server is down are kept by the client and sent
+// after it reconnects.
+fn connect() -> impl Stream<Item = Message> {
+    async_stream::stream! {
+        let hello = Hello {
+            kind: DeviceKind::Socket,
+            id: "socket-1".into(),
+            firmware: env!("CARGO_PKG_VERSION").into(),
+            protocols: PROTOCOL_VERSIONS.to_vec(),
+            capabilities: vec![Capability::Switch, Capability::ReportsPower],
+        };
 
-        tcp_stream.write_all(socket.to_string().as_bytes()).unwrap();
+        let (client, mut events) = DeviceClient::new(DEFAULT_ADDRESS).hello(hello).start();
+
+        yield Message::Ready(client);
+
+        while let Some(event) = events.next().await {
+            yield Message::Client(event);
+        }
+    }
+}
+
+// Waits for the server on `address` instead of connecting to it and answers
+// its "status?" with the latest reading.
+fn listen(address: String) -> impl Stream<Item = Message> {
+    async_stream::stream! {
+        let listener = match TcpListener::bind(&address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                yield Message::ListenFailed(format!("Не удалось слушать {address}: {e}"));
+                return;
+            }
+        };
+
+        let responder = StatusResponder::new();
+        yield Message::Listening(responder.clone());
+
+        if let Err(e) = responder.serve(listener).await {
+            yield Message::ListenFailed(format!("Ожидание опроса прервано: {e}"));
+        }
     }
 }
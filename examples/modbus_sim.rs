@@ -0,0 +1,75 @@
+use std::{error::Error, time::Duration};
+
+use otus_iced::{device::DeviceKind, modbus::ModbusSimulator};
+use tokio::net::TcpListener;
+
+const DEFAULT_BIND: &str = "127.0.0.1:5020";
+const USAGE: &str = "Использование: modbus_sim [--bind <адрес>] [--device socket|termometer]";
+
+// Register map the server is told about with --modbus-register/--modbus-coil:
+// the on/off state in coil 0, a socket's power in watts in holding register 0
+// and a termometer's temperature in tenths of a degree in input register 0.
+const COIL: u16 = 0;
+const POWER: u16 = 0;
+const TEMPERATURE: u16 = 0;
+
+const TICK: Duration = Duration::from_secs(1);
+
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+    let mut bind = DEFAULT_BIND.to_string();
+    let mut device = DeviceKind::Socket;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bind" => bind = args.next().ok_or(USAGE)?,
+            "--device" => device = args.next().ok_or(USAGE)?.parse()?,
+            _ => return Err(format!("Неизвестный аргумент: {arg}\n{USAGE}").into()),
+        }
+    }
+
+    let simulator = ModbusSimulator::new();
+    simulator.set_coil(COIL, true);
+
+    let listener = TcpListener::bind(&bind).await?;
+    println!(
+        "Modbus-симулятор ({device}) слушает {}",
+        listener.local_addr()?
+    );
+
+    tokio::spawn(simulate(simulator.clone(), device));
+
+    Ok(simulator.serve(listener).await?)
+}
+
+// Keeps the registers moving like a real meter would, and prints the state
+// whenever a client switches the coil.
+async fn simulate(simulator: ModbusSimulator, device: DeviceKind) {
+    let mut ticks = tokio::time::interval(TICK);
+    let mut step: u16 = 0;
+    let mut was_on = None;
+
+    loop {
+        ticks.tick().await;
+        step = step.wrapping_add(1);
+
+        let on = simulator.coil(COIL);
+
+        if was_on != Some(on) {
+            println!("Состояние: {}", if on { "on" } else { "off" });
+        }
+        was_on = Some(on);
+
+        match device {
+            DeviceKind::Socket => {
+                let power = if on { 1450 + step % 100 } else { 0 };
+                simulator.set_holding(POWER, power);
+            }
+            DeviceKind::Termometer => {
+                let temperature = 200 + step % 50;
+                simulator.set_input(TEMPERATURE, temperature);
+            }
+        }
+    }
+}
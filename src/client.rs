@@ -0,0 +1,626 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt::Display,
+    future::Future,
+    pin::Pin,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures::Stream;
+use tokio::{
+    io::{self, AsyncWriteExt},
+    net::{
+        TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::{Notify, mpsc, oneshot},
+};
+
+use crate::{
+    device::{DeviceConfig, SensorData},
+    lines::LineReader,
+    protocol::{CommandReply, Envelope, Hello, HelloReply, NackCode, Reply},
+    server::DEFAULT_ADDRESS,
+    state::DeviceState,
+};
+
+const MAX_LINE_LENGTH: usize = 1024;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Readings written but not acknowledged yet; newer ones wait for acks.
+const WINDOW: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connecting,
+    Connected,
+    /// The last attempt failed or the connection was lost; the client keeps
+    /// retrying after `retry_in`.
+    Unreachable {
+        reason: String,
+        retry_in: Duration,
+    },
+}
+
+impl Display for ConnectionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionStatus::Connecting => write!(f, "Подключение..."),
+            ConnectionStatus::Connected => write!(f, "Подключено"),
+            ConnectionStatus::Unreachable { reason, retry_in } => write!(
+                f,
+                "Сервер недоступен ({reason}), повтор через {:.1} с",
+                retry_in.as_secs_f32()
+            ),
+        }
+    }
+}
+
+/// Why a reading passed to `ClientHandle::deliver` never reached the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryError {
+    /// Newer readings pushed it out of a full buffer.
+    Dropped,
+    /// The client was stopped before the server acknowledged the reading.
+    Closed,
+    /// The server answered with NACK.
+    Rejected { code: NackCode, reason: String },
+}
+
+impl Display for DeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryError::Dropped => write!(f, "вытеснено из буфера"),
+            DeliveryError::Closed => write!(f, "клиент остановлен"),
+            DeliveryError::Rejected { code, reason } => {
+                write!(f, "отклонено сервером ({code}): {reason}")
+            }
+        }
+    }
+}
+
+impl Error for DeliveryError {}
+
+/// The server asked the device to switch on or off. Requests with an `id`
+/// expect an answer through `ClientHandle::reply`.
+#[derive(Debug, Clone)]
+pub struct CommandRequest {
+    pub id: Option<u64>,
+    pub state: DeviceState,
+}
+
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    Status(ConnectionStatus),
+    Command(CommandRequest),
+    /// The server pushed the device's configuration.
+    Config(DeviceConfig),
+}
+
+/// Builder for a device connection: where the server is, how many unsent
+/// readings to keep and how to back off between attempts.
+///
+/// ```no_run
+/// # async fn run() {
+/// use otus_iced::client::DeviceClient;
+///
+/// let (client, events) = DeviceClient::new("127.0.0.1:8080").start();
+/// # }
+/// ```
+pub struct DeviceClient {
+    address: String,
+    buffer: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    ack_timeout: Duration,
+    hello: Option<Hello>,
+}
+
+impl Default for DeviceClient {
+    fn default() -> Self {
+        Self::new(DEFAULT_ADDRESS)
+    }
+}
+
+impl DeviceClient {
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            buffer: 64,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(10),
+            ack_timeout: Duration::from_secs(2),
+            hello: None,
+        }
+    }
+
+    /// Readings kept while the server is unreachable; the oldest are dropped.
+    /// Each keeps the time it was taken, so the server can file the ones
+    /// uploaded after a reconnect into history.
+    pub fn buffer(mut self, readings: usize) -> Self {
+        self.buffer = readings.max(1);
+        self
+    }
+
+    /// The delay after the first failed attempt, doubled after each next one
+    /// up to `max`.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Readings the server hasn't acknowledged within this time are written
+    /// again; the server drops the copies it has already accepted.
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = timeout.max(Duration::from_millis(1));
+        self
+    }
+
+    /// Introduce the device with this HELLO on every connection. The client
+    /// reports `Connected` only after the server welcomed it; a refusal is
+    /// retried like any other failure.
+    pub fn hello(mut self, hello: Hello) -> Self {
+        self.hello = Some(hello);
+        self
+    }
+
+    /// Starts connecting on the current tokio runtime. The client runs until
+    /// the handle and all its clones are dropped.
+    pub fn start(self) -> (ClientHandle, ClientEvents) {
+        let outbox = Arc::new(Outbox {
+            readings: Mutex::new(OutboxState {
+                next_seq: first_seq(),
+                ..OutboxState::default()
+            }),
+            changed: Notify::new(),
+            closed: AtomicBool::new(false),
+            capacity: self.buffer,
+        });
+        let (events, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(run(self, outbox.clone(), events));
+
+        let handle = ClientHandle {
+            shared: Arc::new(Shared { outbox }),
+        };
+
+        (handle, ClientEvents { receiver })
+    }
+}
+
+// Readings waiting to be written and those written but not acknowledged. When
+// the connection breaks the unacknowledged ones go back to the front of the
+// queue and are resent with the same sequence numbers.
+struct Outbox {
+    readings: Mutex<OutboxState>,
+    changed: Notify,
+    closed: AtomicBool,
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct OutboxState {
+    // Answers to commands, written before any reading.
+    replies: VecDeque<CommandReply>,
+    queue: VecDeque<Outgoing>,
+    in_flight: VecDeque<InFlight>,
+    next_seq: u64,
+    dropped: u64,
+}
+
+// A queued reading and, if someone waits for it, where to report the outcome.
+struct Outgoing {
+    seq: u64,
+    at: SystemTime,
+    reading: SensorData,
+    delivered: Option<oneshot::Sender<Result<(), DeliveryError>>>,
+}
+
+struct InFlight {
+    sent: Instant,
+    outgoing: Outgoing,
+}
+
+impl Outgoing {
+    fn line(&self) -> String {
+        format!(
+            "{}\n",
+            Envelope::new(self.reading.to_string())
+                .with_seq(self.seq)
+                .with_ts(unix_millis(self.at))
+        )
+    }
+
+    fn finish(self, result: Result<(), DeliveryError>) {
+        if let Some(delivered) = self.delivered {
+            let _ = delivered.send(result);
+        }
+    }
+}
+
+impl Outbox {
+    fn lock(&self) -> MutexGuard<'_, OutboxState> {
+        self.readings.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(
+        &self,
+        reading: SensorData,
+        delivered: Option<oneshot::Sender<Result<(), DeliveryError>>>,
+    ) {
+        let mut state = self.lock();
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+
+        if state.queue.len() == self.capacity {
+            if let Some(oldest) = state.queue.pop_front() {
+                oldest.finish(Err(DeliveryError::Dropped));
+            }
+            state.dropped += 1;
+        }
+
+        state.queue.push_back(Outgoing {
+            seq,
+            at: SystemTime::now(),
+            reading,
+            delivered,
+        });
+        drop(state);
+
+        self.changed.notify_one();
+    }
+
+    fn reply(&self, reply: CommandReply) {
+        self.lock().replies.push_back(reply);
+        self.changed.notify_one();
+    }
+
+    // Returns the next answer to a command, or moves the next queued reading
+    // in flight and returns its line unless too many are waiting for acks
+    // already.
+    fn next_line(&self) -> Option<String> {
+        let mut state = self.lock();
+
+        if let Some(reply) = state.replies.pop_front() {
+            return Some(format!("{reply}\n"));
+        }
+
+        if state.in_flight.len() >= WINDOW {
+            return None;
+        }
+
+        let outgoing = state.queue.pop_front()?;
+        let line = outgoing.line();
+
+        state.in_flight.push_back(InFlight {
+            sent: Instant::now(),
+            outgoing,
+        });
+
+        Some(line)
+    }
+
+    // Lines of the readings that waited for an ack longer than `timeout`.
+    fn expired(&self, timeout: Duration) -> Vec<String> {
+        let mut state = self.lock();
+        let now = Instant::now();
+
+        state
+            .in_flight
+            .iter_mut()
+            .filter(|in_flight| now - in_flight.sent >= timeout)
+            .map(|in_flight| {
+                in_flight.sent = now;
+                in_flight.outgoing.line()
+            })
+            .collect()
+    }
+
+    fn acknowledge(&self, seq: u64, result: Result<(), DeliveryError>) {
+        let mut state = self.lock();
+
+        if let Some(index) = state.in_flight.iter().position(|i| i.outgoing.seq == seq)
+            && let Some(in_flight) = state.in_flight.remove(index)
+        {
+            in_flight.outgoing.finish(result);
+        }
+    }
+
+    fn requeue_in_flight(&self) {
+        let mut state = self.lock();
+
+        while let Some(in_flight) = state.in_flight.pop_back() {
+            if state.queue.len() < self.capacity {
+                state.queue.push_front(in_flight.outgoing);
+            } else {
+                state.dropped += 1;
+                in_flight.outgoing.finish(Err(DeliveryError::Dropped));
+            }
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+struct Shared {
+    outbox: Arc<Outbox>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.outbox.closed.store(true, Ordering::SeqCst);
+        self.outbox.changed.notify_one();
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientHandle {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for ClientHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientHandle")
+            .field("buffered", &self.buffered())
+            .finish()
+    }
+}
+
+impl ClientHandle {
+    /// Queues the reading and returns at once; it goes out as soon as the
+    /// client is connected.
+    pub fn send(&self, reading: SensorData) {
+        self.shared.outbox.push(reading, None);
+    }
+
+    /// Like `send`, but the returned future resolves once the server
+    /// acknowledged the reading or it is known to be lost. The reading is queued
+    /// right away, whether or not the future is polled.
+    pub fn deliver(
+        &self,
+        reading: SensorData,
+    ) -> impl Future<Output = Result<(), DeliveryError>> + Send + 'static {
+        let (delivered, outcome) = oneshot::channel();
+
+        self.shared.outbox.push(reading, Some(delivered));
+
+        async move { outcome.await.unwrap_or(Err(DeliveryError::Closed)) }
+    }
+
+    /// Tells the server how a command ended: the state the device is in now,
+    /// or why it couldn't carry the command out. Requests without an id
+    /// need no answer.
+    pub fn reply(&self, request: &CommandRequest, result: Result<DeviceState, String>) {
+        let Some(id) = request.id else {
+            return;
+        };
+
+        self.shared.outbox.reply(CommandReply::new(id, result));
+    }
+
+    /// Readings the server hasn't acknowledged yet.
+    pub fn buffered(&self) -> usize {
+        let state = self.shared.outbox.lock();
+
+        state.queue.len() + state.in_flight.len()
+    }
+
+    /// Readings dropped because the buffer was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.outbox.lock().dropped
+    }
+}
+
+/// Connection status changes and commands from the server, in order.
+pub struct ClientEvents {
+    receiver: mpsc::UnboundedReceiver<ClientEvent>,
+}
+
+impl Stream for ClientEvents {
+    type Item = ClientEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+async fn run(
+    client: DeviceClient,
+    outbox: Arc<Outbox>,
+    events: mpsc::UnboundedSender<ClientEvent>,
+) {
+    let mut backoff = client.initial_backoff;
+
+    while !outbox.is_closed() {
+        let _ = events.send(ClientEvent::Status(ConnectionStatus::Connecting));
+
+        let reason = match TcpStream::connect(&client.address).await {
+            Ok(tcp) => match session(tcp, &client, &outbox, &events).await {
+                Some(Ok(reason)) => {
+                    backoff = client.initial_backoff;
+                    reason
+                }
+                Some(Err(reason)) => reason,
+                None => break,
+            },
+            Err(e) => e.to_string(),
+        };
+
+        let _ = events.send(ClientEvent::Status(ConnectionStatus::Unreachable {
+            reason,
+            retry_in: backoff,
+        }));
+
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = closed(&outbox) => break,
+        }
+
+        backoff = (backoff * 2).min(client.max_backoff);
+    }
+}
+
+async fn closed(outbox: &Outbox) {
+    while !outbox.is_closed() {
+        outbox.changed.notified().await;
+    }
+}
+
+// Introduces the device, then writes queued readings, resends the
+// unacknowledged ones and forwards commands until the connection breaks or the
+// client is dropped (returns `None`). The reason is `Err` if the session
+// never got past the handshake.
+async fn session(
+    tcp: TcpStream,
+    client: &DeviceClient,
+    outbox: &Outbox,
+    events: &mpsc::UnboundedSender<ClientEvent>,
+) -> Option<Result<String, String>> {
+    let (reader, mut writer) = tcp.into_split();
+    let mut lines = LineReader::new(reader, MAX_LINE_LENGTH);
+
+    if let Some(hello) = &client.hello
+        && let Err(reason) = handshake(&mut writer, &mut lines, hello).await
+    {
+        return Some(Err(reason));
+    }
+
+    let _ = events.send(ClientEvent::Status(ConnectionStatus::Connected));
+
+    let ack_timeout = client.ack_timeout;
+    let mut retry = tokio::time::interval(ack_timeout);
+
+    let reason = loop {
+        if outbox.is_closed() {
+            return None;
+        }
+
+        if let Some(line) = outbox.next_line() {
+            if let Err(e) = writer.write_all(line.as_bytes()).await {
+                break e.to_string();
+            }
+
+            continue;
+        }
+
+        tokio::select! {
+            _ = outbox.changed.notified() => {}
+            _ = retry.tick() => {
+                if let Err(e) = write_lines(&mut writer, outbox.expired(ack_timeout)).await {
+                    break e.to_string();
+                }
+            }
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => receive(&line, outbox, events),
+                Ok(None) => break "соединение закрыто сервером".into(),
+                Err(e) => break e.to_string(),
+            },
+        }
+    };
+
+    outbox.requeue_in_flight();
+
+    Some(Ok(reason))
+}
+
+async fn handshake(
+    writer: &mut OwnedWriteHalf,
+    lines: &mut LineReader<OwnedReadHalf>,
+    hello: &Hello,
+) -> Result<u32, String> {
+    writer
+        .write_all(format!("{hello}\n").as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let reply = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => {
+                    if let Ok(reply) = line.trim().parse::<HelloReply>() {
+                        return Ok(reply);
+                    }
+                }
+                Ok(None) => return Err("соединение закрыто сервером".to_string()),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    })
+    .await
+    .map_err(|_| "сервер не ответил на приветствие".to_string())??;
+
+    match reply {
+        HelloReply::Welcome { protocol } => Ok(protocol),
+        HelloReply::Refused(reason) => Err(format!("сервер отказал: {reason}")),
+    }
+}
+
+async fn write_lines(writer: &mut OwnedWriteHalf, lines: Vec<String>) -> io::Result<()> {
+    for line in lines {
+        writer.write_all(line.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+fn receive(line: &str, outbox: &Outbox, events: &mpsc::UnboundedSender<ClientEvent>) {
+    match line.trim().parse::<Reply>() {
+        Ok(Reply::Ack(seq)) => outbox.acknowledge(seq, Ok(())),
+        Ok(Reply::Nack { seq, code, reason }) => {
+            outbox.acknowledge(seq, Err(DeliveryError::Rejected { code, reason }))
+        }
+        Err(_) => {
+            if let Some(config) = line.trim().strip_prefix("CONFIG ") {
+                if let Ok(config) = config.parse() {
+                    let _ = events.send(ClientEvent::Config(config));
+                }
+            } else if let Some(request) = decode_command(line) {
+                let _ = events.send(ClientEvent::Command(request));
+            }
+        }
+    }
+}
+
+// Sequence numbers start at the current time in microseconds, so a restarted
+// device keeps counting up and the server doesn't take its readings for
+// retries of the old ones.
+fn first_seq() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_micros() as u64)
+        .unwrap_or(1)
+}
+
+fn unix_millis(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// "SET on" / "SET off", as written by `TextCodec`, optionally followed by
+// the command id.
+fn decode_command(line: &str) -> Option<CommandRequest> {
+    let envelope = line.trim().parse::<Envelope>().ok()?;
+
+    let state = match envelope.payload.strip_prefix("SET ")? {
+        "on" => DeviceState::new(true),
+        "off" => DeviceState::new(false),
+        _ => return None,
+    };
+
+    Some(CommandRequest {
+        id: envelope.id,
+        state,
+    })
+}
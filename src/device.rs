@@ -0,0 +1,270 @@
+use std::{error::Error, fmt::Display, str::FromStr};
+
+use crate::{
+    power::Power,
+    protocol::{Fields, join, split},
+    socket::Socket,
+    state::DeviceState,
+    termometer::Termometer,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DeviceKind {
+    Socket,
+    Termometer,
+}
+
+impl DeviceKind {
+    pub const ALL: [DeviceKind; 2] = [DeviceKind::Socket, DeviceKind::Termometer];
+}
+
+impl Display for DeviceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceKind::Socket => write!(f, "socket"),
+            DeviceKind::Termometer => write!(f, "termometer"),
+        }
+    }
+}
+
+impl FromStr for DeviceKind {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "socket" => Ok(DeviceKind::Socket),
+            "termometer" => Ok(DeviceKind::Termometer),
+            _ => Err("unknown device kind".into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SensorData {
+    SocketIndicator(Socket),
+    TermoIndicator(Termometer),
+}
+
+impl SensorData {
+    pub fn kind(&self) -> DeviceKind {
+        match self {
+            SensorData::SocketIndicator(_) => DeviceKind::Socket,
+            SensorData::TermoIndicator(_) => DeviceKind::Termometer,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        match self {
+            SensorData::SocketIndicator(s) => s.state().get(),
+            SensorData::TermoIndicator(t) => t.state().get(),
+        }
+    }
+}
+
+impl Display for SensorData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SensorData::SocketIndicator(s) => write!(f, "{s}"),
+            SensorData::TermoIndicator(t) => write!(f, "{t}"),
+        }
+    }
+}
+
+impl FromStr for SensorData {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(t) = s.parse::<Termometer>() {
+            return Ok(SensorData::TermoIndicator(t));
+        }
+
+        if let Ok(s) = s.parse::<Socket>() {
+            return Ok(SensorData::SocketIndicator(s));
+        }
+
+        Err("does not look like message from device".into())
+    }
+}
+
+/// An operator's request to switch a device on or off, e.g. "socket off".
+#[derive(Debug, Clone)]
+pub struct Command {
+    device: DeviceKind,
+    state: DeviceState,
+}
+
+impl Command {
+    pub fn new(device: DeviceKind, state: DeviceState) -> Self {
+        Self { device, state }
+    }
+
+    pub fn device(&self) -> DeviceKind {
+        self.device
+    }
+
+    pub fn state(&self) -> &DeviceState {
+        &self.state
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.device(), self.state())
+    }
+}
+
+impl FromStr for Command {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+
+        let device = parts.next().ok_or("missing device")?.parse()?;
+
+        let state = match parts.next() {
+            Some(state @ ("on" | "off")) => state.parse()?,
+            _ => return Err("command must be 'on' or 'off'".into()),
+        };
+
+        Ok(Self::new(device, state))
+    }
+}
+
+/// What a device announced it can do in its HELLO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Capability {
+    /// Accepts on/off commands.
+    Switch,
+    ReportsPower,
+    ReportsTemperature,
+    /// Reports the energy used, alongside its power.
+    ReportsEnergy,
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Capability::Switch => write!(f, "switch"),
+            Capability::ReportsPower => write!(f, "power"),
+            Capability::ReportsTemperature => write!(f, "temperature"),
+            Capability::ReportsEnergy => write!(f, "energy"),
+        }
+    }
+}
+
+impl FromStr for Capability {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "switch" => Ok(Capability::Switch),
+            "power" => Ok(Capability::ReportsPower),
+            "temperature" => Ok(Capability::ReportsTemperature),
+            "energy" => Ok(Capability::ReportsEnergy),
+            _ => Err("unknown capability".into()),
+        }
+    }
+}
+
+/// What the server knows about a connected device from its handshake, e.g.
+/// "kind=socket id=socket-1 firmware=0.1.0 protocol=1 capabilities=switch,power".
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub kind: DeviceKind,
+    pub id: String,
+    pub firmware: String,
+    pub protocol: u32,
+    pub capabilities: Vec<Capability>,
+}
+
+impl DeviceInfo {
+    pub fn can(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "kind={} id={} firmware={} protocol={} capabilities={}",
+            self.kind,
+            self.id,
+            self.firmware,
+            self.protocol,
+            join(&self.capabilities)
+        )
+    }
+}
+
+impl FromStr for DeviceInfo {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = Fields::parse(s)?;
+
+        Ok(Self {
+            kind: fields.get("kind")?.parse()?,
+            id: fields.get("id")?.into(),
+            firmware: fields.get("firmware")?.into(),
+            protocol: fields.get("protocol")?.parse()?,
+            capabilities: split(fields.get("capabilities").unwrap_or_default()),
+        })
+    }
+}
+
+/// Settings the server pushes to a device, e.g.
+/// "interval=10 deadband=0.5 max_power=2000".
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DeviceConfig {
+    /// Report at least this often, in seconds; zero reports on changes only.
+    pub interval: u32,
+    /// Changes smaller than this are not reported.
+    pub deadband: f32,
+    /// Sockets don't draw more than this, in watts.
+    pub max_power: Option<f32>,
+}
+
+impl Display for DeviceConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "interval={} deadband={}", self.interval, self.deadband)?;
+
+        if let Some(max_power) = self.max_power {
+            write!(f, " max_power={max_power}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for DeviceConfig {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = Fields::parse(s)?;
+
+        let config = Self {
+            interval: fields.get("interval")?.parse()?,
+            deadband: fields.get("deadband")?.parse()?,
+            max_power: fields.get("max_power").ok().map(str::parse).transpose()?,
+        };
+
+        if !config.deadband.is_finite() || config.deadband < 0.0 {
+            return Err("deadband must be a non-negative number".into());
+        }
+
+        if config
+            .max_power
+            .is_some_and(|power| !(Power::MIN_POWER..=Power::MAX_POWER).contains(&power))
+        {
+            return Err(format!(
+                "max_power must be between {} and {}",
+                Power::MIN_POWER,
+                Power::MAX_POWER
+            )
+            .into());
+        }
+
+        Ok(config)
+    }
+}
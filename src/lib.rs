@@ -1,5 +1,13 @@
+pub mod client;
+pub mod device;
+pub mod lines;
+mod listener;
+pub mod modbus;
 pub mod power;
+pub mod protocol;
+pub mod server;
 pub mod socket;
 pub mod state;
+pub mod status;
 pub mod temperature;
 pub mod termometer;
@@ -0,0 +1,58 @@
+use std::io;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+
+/// Reads '\n'-terminated lines of bounded length. Unlike `Lines` it keeps a
+/// partially received line between calls, so it is safe to use in `select!`.
+pub struct LineReader<R> {
+    reader: BufReader<R>,
+    buf: Vec<u8>,
+    limit: usize,
+}
+
+impl<R: AsyncRead + Unpin> LineReader<R> {
+    pub fn new(reader: R, limit: usize) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            buf: Vec::new(),
+            limit,
+        }
+    }
+
+    /// Returns `None` at the end of the stream; a last line without the
+    /// terminating '\n' is still returned. A line longer than the limit is an
+    /// `InvalidData` error.
+    pub async fn next_line(&mut self) -> io::Result<Option<String>> {
+        let allowed = (self.limit + 1).saturating_sub(self.buf.len()) as u64;
+
+        let n = (&mut self.reader)
+            .take(allowed)
+            .read_until(b'\n', &mut self.buf)
+            .await?;
+
+        if self.buf.len() > self.limit && self.buf.last() != Some(&b'\n') {
+            self.buf.clear();
+
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+        }
+
+        if n == 0 && self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        let line = String::from_utf8_lossy(&self.buf)
+            .trim_end_matches(['\r', '\n'])
+            .to_string();
+        self.buf.clear();
+
+        Ok(Some(line))
+    }
+
+    // Reads exactly `len` bytes following the last line, e.g. a request body.
+    pub(crate) async fn read_bytes(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![0; len];
+        self.reader.read_exact(&mut bytes).await?;
+
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,76 @@
+use std::{io, net::SocketAddr, time::Duration};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+
+// How long a listener waits after a failed accept. Errors such as running out
+// of file descriptors last until a connection closes, so retrying at once
+// would only spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// Accepts connections for as long as the listener lives: a failed accept is
+// tried again after `ACCEPT_BACKOFF` rather than closing the listener.
+pub(crate) struct Acceptor {
+    listener: TcpListener,
+    last_failure: Option<String>,
+}
+
+impl Acceptor {
+    pub(crate) fn new(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            last_failure: None,
+        }
+    }
+
+    // The next connection. `failed` hears of each failure once, not on every
+    // retry; a connection accepted in between lets it be reported again.
+    pub(crate) async fn accept(
+        &mut self,
+        mut failed: impl FnMut(&io::Error),
+    ) -> (TcpStream, SocketAddr) {
+        loop {
+            match self.listener.accept().await {
+                Ok(accepted) => {
+                    self.last_failure = None;
+                    return accepted;
+                }
+                Err(e) => {
+                    let failure = e.to_string();
+
+                    if self.last_failure.as_ref() != Some(&failure) {
+                        failed(&e);
+                    }
+                    self.last_failure = Some(failure);
+
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                }
+            }
+        }
+    }
+}
+
+// Runs `answer` for every connection accepted on `listener` until the future
+// is dropped, which also closes the connections still open. Nobody is told of
+// failed accepts; they are only retried.
+pub(crate) async fn serve<F>(
+    listener: TcpListener,
+    answer: impl Fn(TcpStream) -> F,
+) -> io::Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let mut acceptor = Acceptor::new(listener);
+    let mut connections = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            (tcp, _) = acceptor.accept(|_| {}) => {
+                connections.spawn(answer(tcp));
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+}
@@ -1,44 +1,338 @@
-use std::{hash::Hash, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use iced::{
     Font, Length, Subscription, Task,
-    advanced::subscription::{EventStream, Hasher, Recipe, from_recipe},
-    futures::{
-        SinkExt, StreamExt,
-        channel::mpsc::{self, Receiver},
-        lock::Mutex,
-        stream::BoxStream,
+    futures::{Stream, StreamExt, channel::mpsc, stream::FuturesUnordered},
+    widget::{self, Button, Column, Row, Text, TextInput},
+};
+use otus_iced::{
+    device::{Command, DeviceConfig, DeviceInfo, DeviceKind, SensorData},
+    lines::LineReader,
+    protocol::{CommandReply, Envelope},
+    server::{
+        ApiLine, DEFAULT_ADDRESS, DeviceServer, EventStream, FeedStats, Frame, InfluxConfig,
+        ModbusConfig, MqttConfig, PendingCommand, PollConfig, Registry, SequenceStats, ServerEvent,
+        ServerHandle, WebhookConfig, WebhookDelivery,
     },
-    widget::{self, Column, Row, Text},
+    socket::Socket,
+    state::DeviceState,
+    termometer::Termometer,
 };
-use otus_iced::{socket::Socket, termometer::Termometer};
 
-use tokio::{
-    io::AsyncReadExt,
-    net::{TcpListener, TcpStream},
-};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+const DEFAULT_API_ADDRESS: &str = "localhost:8081";
+const DEFAULT_CONFIG_FILE: &str = "devices.conf";
 
 const STATUS_OFFLINE: &str = "Статуc: Offline";
 const STATUS_ONLINE: &str = "Статуc: Online";
 const VALUE_NA: &str = "N/A";
 
-pub fn main() -> iced::Result {
+// The dashboard is redrawn at most this often because of network updates,
+// readings arriving in between are coalesced into the latest value.
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+
+const COMMAND_BUFFER: usize = 16;
+const SPINNER: [&str; 4] = ["◐", "◓", "◑", "◒"];
+const SPINNER_INTERVAL: Duration = Duration::from_millis(120);
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+// Longer lines from a remote server end the mirror session.
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+// Webhook deliveries listed under the cards, newest first.
+const DELIVERIES_SHOWN: usize = 5;
+
+const USAGE: &str = "Использование: server [--headless] [--bind <адрес>] [--api <адрес>] \
+                     [--http <адрес>] [--config <файл>] [--connect <адрес сервера>] \
+                     [--mqtt <брокер> [--mqtt-prefix <префикс>] [--mqtt-qos <0|1>] \
+                     [--mqtt-no-retain] [--mqtt-room <устройство>=<комната>]... \
+                     [--mqtt-discovery <префикс>]] \
+                     [--influx <приёмник> [--influx-token <токен>]] \
+                     [--webhook <url> [--webhook-on <событие>]... [--webhook-secret <ключ>] \
+                     [--webhook-template <тело>]]... \
+                     [--modbus <устройство>=<адрес> [--modbus-unit <номер>] \
+                     [--modbus-register <регистр>] [--modbus-coil <адрес>]]... \
+                     [--poll <адрес устройства> [--poll-interval <с>]]...";
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let options = Options::from_args(std::env::args().skip(1))?;
+
+    if options.headless {
+        return tokio::runtime::Runtime::new()?.block_on(run_headless(options));
+    }
+
+    let server = ServerConfig {
+        remote: options.connect.is_some(),
+        address: options.connect.unwrap_or(options.address),
+        api_address: options.api_address,
+        http_address: options.http_address,
+        mqtt: options.mqtt,
+        influx: options.influx,
+        webhooks: options.webhooks,
+        modbus: options.modbus,
+        polls: options.polls,
+        config_file: options.config_file,
+        generation: 0,
+    };
+
     iced::application("Устройства", SmartDeviceApp::update, SmartDeviceApp::view)
-        .window_size(iced::Size::new(900f32, 225f32))
+        .window_size(iced::Size::new(900f32, 600f32))
         .theme(|_| iced::Theme::GruvboxDark)
         .subscription(SmartDeviceApp::subscription)
-        .run_with(SmartDeviceApp::new)
+        .run_with(move || SmartDeviceApp::new(server))?;
+
+    Ok(())
+}
+
+struct Options {
+    headless: bool,
+    address: String,
+    api_address: String,
+    // The REST API is only served when asked for.
+    http_address: Option<String>,
+    mqtt: Option<MqttConfig>,
+    influx: Option<InfluxConfig>,
+    // The --webhook-* settings apply to the --webhook before them.
+    webhooks: Vec<WebhookConfig>,
+    // Likewise the --modbus-* settings and the --modbus before them.
+    modbus: Vec<ModbusConfig>,
+    // And the --poll-* settings.
+    polls: Vec<PollConfig>,
+    config_file: String,
+    connect: Option<String>,
 }
 
-#[derive(Debug)]
+impl Options {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Self {
+            headless: false,
+            address: DEFAULT_ADDRESS.into(),
+            api_address: DEFAULT_API_ADDRESS.into(),
+            http_address: None,
+            mqtt: None,
+            influx: None,
+            webhooks: Vec::new(),
+            modbus: Vec::new(),
+            polls: Vec::new(),
+            config_file: DEFAULT_CONFIG_FILE.into(),
+            connect: None,
+        };
+
+        // The --mqtt-* settings may come before --mqtt itself.
+        let mut mqtt_broker = None;
+        let mut mqtt = MqttConfig::new("");
+        let mut influx_token = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => options.headless = true,
+                "--bind" => options.address = args.next().ok_or(USAGE)?,
+                "--api" => options.api_address = args.next().ok_or(USAGE)?,
+                "--http" => options.http_address = Some(args.next().ok_or(USAGE)?),
+                "--config" => options.config_file = args.next().ok_or(USAGE)?,
+                "--connect" => options.connect = Some(args.next().ok_or(USAGE)?),
+                "--mqtt" => mqtt_broker = Some(args.next().ok_or(USAGE)?),
+                "--mqtt-prefix" => mqtt.prefix = args.next().ok_or(USAGE)?,
+                "--mqtt-qos" => {
+                    mqtt.qos = args
+                        .next()
+                        .ok_or(USAGE)?
+                        .parse()
+                        .map_err(|e| format!("--mqtt-qos: {e}\n{USAGE}"))?;
+                }
+                "--mqtt-no-retain" => mqtt.retain = false,
+                "--mqtt-discovery" => mqtt.discovery = Some(args.next().ok_or(USAGE)?),
+                "--mqtt-room" => {
+                    let room = args.next().ok_or(USAGE)?;
+                    let (device, room) = room.split_once('=').ok_or(format!(
+                        "--mqtt-room: ожидалось <устройство>=<комната>\n{USAGE}"
+                    ))?;
+                    let device = device
+                        .parse()
+                        .map_err(|e| format!("--mqtt-room: {e}\n{USAGE}"))?;
+
+                    mqtt.rooms.insert(device, room.into());
+                }
+                "--influx" => {
+                    let sink = args
+                        .next()
+                        .ok_or(USAGE)?
+                        .parse()
+                        .map_err(|e| format!("--influx: {e}\n{USAGE}"))?;
+
+                    options.influx = Some(InfluxConfig::new(sink));
+                }
+                "--influx-token" => influx_token = Some(args.next().ok_or(USAGE)?),
+                "--webhook" => {
+                    let webhook = WebhookConfig::new(args.next().ok_or(USAGE)?)
+                        .map_err(|e| format!("--webhook: {e}\n{USAGE}"))?;
+
+                    options.webhooks.push(webhook);
+                }
+                "--webhook-on" | "--webhook-secret" | "--webhook-template" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    let webhook = options
+                        .webhooks
+                        .last_mut()
+                        .ok_or(format!("{arg}: сначала укажите --webhook\n{USAGE}"))?;
+
+                    match arg.as_str() {
+                        "--webhook-on" => webhook.triggers.push(
+                            value
+                                .parse()
+                                .map_err(|e| format!("--webhook-on: {e}\n{USAGE}"))?,
+                        ),
+                        "--webhook-secret" => webhook.secret = Some(value),
+                        _ => webhook.template = Some(value),
+                    }
+                }
+                "--modbus" => {
+                    let device = args.next().ok_or(USAGE)?;
+                    let (device, address) = device
+                        .split_once('=')
+                        .ok_or(format!("--modbus: ожидалось <устройство>=<адрес>\n{USAGE}"))?;
+                    let device = device
+                        .parse()
+                        .map_err(|e| format!("--modbus: {e}\n{USAGE}"))?;
+
+                    options.modbus.push(ModbusConfig::new(device, address));
+                }
+                "--modbus-unit" | "--modbus-register" | "--modbus-coil" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    let modbus = options
+                        .modbus
+                        .last_mut()
+                        .ok_or(format!("{arg}: сначала укажите --modbus\n{USAGE}"))?;
+
+                    match arg.as_str() {
+                        "--modbus-unit" => {
+                            modbus.unit =
+                                value.parse().map_err(|e| format!("{arg}: {e}\n{USAGE}"))?
+                        }
+                        "--modbus-register" => {
+                            modbus.register =
+                                value.parse().map_err(|e| format!("{arg}: {e}\n{USAGE}"))?
+                        }
+                        _ => {
+                            modbus.coil =
+                                Some(value.parse().map_err(|e| format!("{arg}: {e}\n{USAGE}"))?)
+                        }
+                    }
+                }
+                "--poll" => options
+                    .polls
+                    .push(PollConfig::new(args.next().ok_or(USAGE)?)),
+                "--poll-interval" => {
+                    let seconds: f32 = args
+                        .next()
+                        .ok_or(USAGE)?
+                        .parse()
+                        .map_err(|e| format!("--poll-interval: {e}\n{USAGE}"))?;
+                    let poll = options
+                        .polls
+                        .last_mut()
+                        .ok_or(format!("{arg}: сначала укажите --poll\n{USAGE}"))?;
+
+                    poll.interval = Duration::try_from_secs_f32(seconds)
+                        .map_err(|e| format!("--poll-interval: {e}\n{USAGE}"))?;
+                }
+                _ => return Err(format!("Неизвестный аргумент: {arg}\n{USAGE}")),
+            }
+        }
+
+        if options.headless && options.connect.is_some() {
+            return Err(format!("--headless и --connect несовместимы\n{USAGE}"));
+        }
+
+        options.mqtt = mqtt_broker.map(|broker| MqttConfig { broker, ..mqtt });
+
+        if let Some(influx) = &mut options.influx {
+            influx.token = influx_token;
+        }
+
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Clone)]
 enum Message {
-    TermometerOnline(Termometer),
-    TermometerOffline,
+    Frame(Frame),
+
+    ServerStarted {
+        devices: SocketAddr,
+        api: Option<SocketAddr>,
+        http: Option<SocketAddr>,
+    },
+    Connected(String),
+    ServerFailed(String),
+    Ready(mpsc::Sender<Request>),
+
+    AddressChanged(String),
+    RestartServer,
+    ToggleSocket,
+    CommandFinished(CommandReply),
+    Spin,
+    ConfigChanged(DeviceKind, ConfigField, String),
+    ApplyConfig(DeviceKind),
+}
+
+// What the operator asks the server to do, in-process or over the API.
+#[derive(Debug, Clone)]
+enum Request {
+    Command(u64, Command),
+    Configure(DeviceKind, DeviceConfig),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ConfigField {
+    Interval,
+    Deadband,
+    MaxPower,
+}
+
+// The configuration editor of a device card, as typed by the operator.
+#[derive(Debug, Clone, Default)]
+struct ConfigInputs {
+    interval: String,
+    deadband: String,
+    max_power: String,
+}
+
+impl ConfigInputs {
+    fn new(config: DeviceConfig) -> Self {
+        Self {
+            interval: config.interval.to_string(),
+            deadband: config.deadband.to_string(),
+            max_power: config.max_power.map(|p| p.to_string()).unwrap_or_default(),
+        }
+    }
 
-    SocketOnline(Socket),
-    SocketOffline,
+    fn set(&mut self, field: ConfigField, value: String) {
+        match field {
+            ConfigField::Interval => self.interval = value,
+            ConfigField::Deadband => self.deadband = value,
+            ConfigField::MaxPower => self.max_power = value,
+        }
+    }
+
+    fn parse(&self) -> Result<DeviceConfig, Box<dyn Error>> {
+        let mut line = format!(
+            "interval={} deadband={}",
+            self.interval.trim(),
+            self.deadband.trim()
+        );
+
+        if !self.max_power.trim().is_empty() {
+            line.push_str(&format!(" max_power={}", self.max_power.trim()));
+        }
 
-    ServerStarted,
+        line.parse()
+    }
 }
 
 //#[derive(Default)]
@@ -46,7 +340,48 @@ struct SmartDeviceApp {
     termo_widget: TermoWidget,
     socket_widget: SocketWidget,
 
-    net_event_receiver: Arc<Mutex<mpsc::Receiver<SensorData>>>,
+    last_event: Option<String>,
+    stats: FeedStats,
+    sequences: BTreeMap<DeviceKind, SequenceStats>,
+    devices: BTreeMap<DeviceKind, DeviceInfo>,
+    configs: BTreeMap<DeviceKind, DeviceConfig>,
+    config_inputs: BTreeMap<DeviceKind, ConfigInputs>,
+    pending: BTreeMap<DeviceKind, Vec<PendingCommand>>,
+    deliveries: Vec<WebhookDelivery>,
+
+    address_input: String,
+    server: ServerConfig,
+    server_status: String,
+    requests: Option<mpsc::Sender<Request>>,
+    next_command: u64,
+    spinner: usize,
+}
+
+// Identity of the running server subscription: changing the address or
+// bumping the generation makes iced drop the old worker and start a new one.
+// A remote dashboard connects to `address` instead of hosting the server.
+#[derive(Debug, Clone, Hash)]
+struct ServerConfig {
+    remote: bool,
+    address: String,
+    api_address: String,
+    http_address: Option<String>,
+    mqtt: Option<MqttConfig>,
+    influx: Option<InfluxConfig>,
+    webhooks: Vec<WebhookConfig>,
+    modbus: Vec<ModbusConfig>,
+    polls: Vec<PollConfig>,
+    config_file: String,
+    generation: u64,
+}
+
+impl ServerConfig {
+    fn starting(&self) -> String {
+        match self.remote {
+            true => format!("Подключение к {}...", self.address),
+            false => format!("Запуск сервера на {}...", self.address),
+        }
+    }
 }
 
 #[derive(Default)]
@@ -75,6 +410,15 @@ impl TermoWidget {
 struct SocketWidget {
     state: bool,
     value: f32,
+    command: Option<SocketCommand>,
+}
+
+// The operator's last command to the socket. The card shows the state the
+// device confirmed, never the one it was asked for.
+enum SocketCommand {
+    Waiting { id: u64, on: bool },
+    Confirmed(bool),
+    Failed(String),
 }
 
 impl SocketWidget {
@@ -91,11 +435,20 @@ impl SocketWidget {
             _ => VALUE_NA.into(),
         }
     }
-}
 
-enum SensorData {
-    SocketIndicator(Socket),
-    TermoIndicator(Termometer),
+    fn waiting(&self) -> bool {
+        matches!(self.command, Some(SocketCommand::Waiting { .. }))
+    }
+
+    fn command_status(&self) -> String {
+        match &self.command {
+            Some(SocketCommand::Waiting { .. }) => "Ждём подтверждения устройства".into(),
+            Some(SocketCommand::Confirmed(true)) => "Устройство включилось".into(),
+            Some(SocketCommand::Confirmed(false)) => "Устройство выключилось".into(),
+            Some(SocketCommand::Failed(reason)) => format!("Не выполнено: {reason}"),
+            None => String::new(),
+        }
+    }
 }
 
 impl SmartDeviceApp {
@@ -119,34 +472,318 @@ impl SmartDeviceApp {
         self.socket_widget.value = 0.0;
     }
 
-    fn new() -> (Self, Task<Message>) {
-        let (net_event_sender, net_event_receiver) = mpsc::channel::<SensorData>(32);
+    fn toggle_socket(&mut self) {
+        if self.socket_widget.waiting() {
+            return;
+        }
+
+        let on = !self.socket_widget.state;
+        self.next_command += 1;
+        let id = self.next_command;
+
+        let command = Command::new(DeviceKind::Socket, DeviceState::new(on));
+
+        if self.send_request(Request::Command(id, command)) {
+            self.socket_widget.command = Some(SocketCommand::Waiting { id, on });
+        }
+    }
+
+    fn command_finished(&mut self, reply: CommandReply) {
+        let waiting = matches!(
+            self.socket_widget.command,
+            Some(SocketCommand::Waiting { id, .. }) if id == reply.id()
+        );
+
+        if !waiting {
+            return;
+        }
+
+        self.socket_widget.command = Some(match reply {
+            CommandReply::Done { state, .. } => {
+                self.socket_widget.state = state.get();
+                SocketCommand::Confirmed(state.get())
+            }
+            CommandReply::Failed { reason, .. } => SocketCommand::Failed(reason),
+        });
+    }
+
+    fn apply_frame(&mut self, frame: Frame) {
+        if frame.reset {
+            self.termometer_offline();
+            self.socket_offline();
+        }
+
+        for reading in frame.readings {
+            match reading {
+                SensorData::SocketIndicator(s) if s.state().get() => self.socket_online(s),
+                SensorData::SocketIndicator(_) => self.socket_offline(),
+                SensorData::TermoIndicator(t) if t.state().get() => self.termometer_online(t),
+                SensorData::TermoIndicator(_) => self.termometer_offline(),
+            }
+        }
+
+        if let Some(event) = frame.events.last() {
+            self.last_event = Some(event.to_string());
+        }
+
+        self.stats = frame.stats;
+        self.sequences = frame.sequences;
+        self.devices = frame.devices;
+
+        for (device, config) in &frame.configs {
+            self.config_inputs
+                .entry(*device)
+                .or_insert_with(|| ConfigInputs::new(*config));
+        }
+        self.configs = frame.configs;
+        self.pending = frame.pending;
+        self.deliveries = frame.deliveries;
+    }
+
+    fn pending_details(&self, device: DeviceKind) -> String {
+        let Some(pending) = self.pending.get(&device).filter(|p| !p.is_empty()) else {
+            return "Команд в очереди нет".into();
+        };
+
+        let now = SystemTime::now();
+        let commands = pending
+            .iter()
+            .map(|command| {
+                let left = command.expires_at.duration_since(now).unwrap_or_default();
+                format!("{} (ещё {} с)", command.state, left.as_secs())
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        format!("Ждут подключения: {commands}")
+    }
+
+    fn config_details(&self, device: DeviceKind) -> String {
+        match self.configs.get(&device) {
+            Some(config) => {
+                let mut details = match config.interval {
+                    0 => "Отчёт при изменении".to_string(),
+                    interval => format!("Отчёт каждые {interval} с"),
+                };
+                details.push_str(&format!("  порог: {}", config.deadband));
+
+                if let Some(max_power) = config.max_power {
+                    details.push_str(&format!("  предел: {max_power} Вт"));
+                }
+
+                details
+            }
+            None => "Настройки по умолчанию".into(),
+        }
+    }
+
+    fn config_editor(&self, device: DeviceKind, font: Font) -> Row<'_, Message> {
+        let inputs = self.config_inputs.get(&device);
+        let value = |field: fn(&ConfigInputs) -> &str| inputs.map(field).unwrap_or_default();
+
+        let input = |placeholder, value, field| {
+            TextInput::new(placeholder, value)
+                .on_input(move |value| Message::ConfigChanged(device, field, value))
+                .on_submit(Message::ApplyConfig(device))
+                .font(font)
+                .size(14)
+                .width(Length::Fixed(90.0))
+        };
+
+        let mut editor = Row::new()
+            .spacing(6)
+            .align_y(iced::Alignment::Center)
+            .push(input(
+                "интервал, с",
+                value(|i| &i.interval),
+                ConfigField::Interval,
+            ))
+            .push(input(
+                "порог",
+                value(|i| &i.deadband),
+                ConfigField::Deadband,
+            ));
+
+        if device == DeviceKind::Socket {
+            editor = editor.push(input(
+                "предел, Вт",
+                value(|i| &i.max_power),
+                ConfigField::MaxPower,
+            ));
+        }
+
+        editor.push(
+            Button::new(Text::new("Применить").font(font).size(14))
+                .on_press(Message::ApplyConfig(device)),
+        )
+    }
+
+    fn device_details(&self, device: DeviceKind) -> String {
+        match self.devices.get(&device) {
+            Some(info) => format!(
+                "{}  прошивка {}  протокол {}  возможности: {}",
+                info.id,
+                info.firmware,
+                info.protocol,
+                info.capabilities
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            None => "Устройство не представилось".into(),
+        }
+    }
+
+    fn sequence_details(&self, device: DeviceKind) -> String {
+        match self.sequences.get(&device) {
+            Some(s) => format!(
+                "Сообщение №{}  пропусков: {}  не по порядку: {}  повторов: {}",
+                s.last, s.gaps, s.out_of_order, s.duplicates
+            ),
+            None => "Сообщения без нумерации".into(),
+        }
+    }
+
+    fn delivery_details(&self) -> String {
+        if self.deliveries.is_empty() {
+            return "Вебхуки не срабатывали".into();
+        }
+
+        let deliveries = self
+            .deliveries
+            .iter()
+            .rev()
+            .take(DELIVERIES_SHOWN)
+            .map(|delivery| {
+                let outcome = match &delivery.error {
+                    _ if delivery.delivered => "доставлено".to_string(),
+                    Some(error) => format!("не доставлено: {error}"),
+                    None => "не доставлено".to_string(),
+                };
+
+                format!(
+                    "{} → {}: {outcome}, попыток: {}",
+                    delivery.event, delivery.url, delivery.attempts
+                )
+            })
+            .collect::<Vec<_>>();
+
+        format!("Вебхуки:\n{}", deliveries.join("\n"))
+    }
+
+    fn restart_server(&mut self) {
+        self.termo_widget = TermoWidget::default();
+        self.socket_widget = SocketWidget::default();
+        self.last_event = None;
+        self.stats = FeedStats::default();
+        self.sequences = BTreeMap::new();
+        self.devices = BTreeMap::new();
+        self.configs = BTreeMap::new();
+        self.config_inputs = BTreeMap::new();
+        self.pending = BTreeMap::new();
+        self.deliveries = Vec::new();
+        self.requests = None;
+
+        self.server = ServerConfig {
+            address: self.address_input.trim().to_string(),
+            generation: self.server.generation + 1,
+            ..self.server.clone()
+        };
+        self.server_status = self.server.starting();
+    }
+
+    fn send_request(&mut self, request: Request) -> bool {
+        let sent = self
+            .requests
+            .as_mut()
+            .is_some_and(|requests| requests.try_send(request).is_ok());
+
+        if !sent {
+            self.last_event = Some("Команда не отправлена: нет связи с сервером".into());
+        }
+
+        sent
+    }
 
+    fn apply_config(&mut self, device: DeviceKind) {
+        let parsed = self
+            .config_inputs
+            .get(&device)
+            .cloned()
+            .unwrap_or_default()
+            .parse();
+
+        match parsed {
+            Ok(config) => {
+                self.send_request(Request::Configure(device, config));
+            }
+            Err(e) => self.last_event = Some(format!("Неверные настройки {device}: {e}")),
+        }
+    }
+
+    fn new(server: ServerConfig) -> (Self, Task<Message>) {
         (
             Self {
                 termo_widget: TermoWidget::default(),
                 socket_widget: SocketWidget::default(),
-                net_event_receiver: Arc::new(Mutex::new(net_event_receiver)),
+                last_event: None,
+                stats: FeedStats::default(),
+                sequences: BTreeMap::new(),
+                devices: BTreeMap::new(),
+                configs: BTreeMap::new(),
+                config_inputs: BTreeMap::new(),
+                pending: BTreeMap::new(),
+                deliveries: Vec::new(),
+                address_input: server.address.clone(),
+                server_status: server.starting(),
+                server,
+                requests: None,
+                next_command: 0,
+                spinner: 0,
             },
-            Task::batch([
-                Task::perform(device_server(net_event_sender), |_| Message::ServerStarted),
-                widget::focus_next(),
-            ]),
+            widget::focus_next(),
         )
     }
 
     fn update(&mut self, message: Message) {
         match message {
-            Message::TermometerOnline(t) => self.termometer_online(t),
-            Message::TermometerOffline => self.termometer_offline(),
+            Message::Frame(frame) => self.apply_frame(frame),
 
-            Message::SocketOnline(s) => self.socket_online(s),
-            Message::SocketOffline => self.socket_offline(),
-            Message::ServerStarted => {}
+            Message::ServerStarted { devices, api, http } => {
+                self.server_status = listening(devices, api, http);
+            }
+            Message::Connected(address) => {
+                self.server_status = format!("Подключено к {address}");
+            }
+            Message::ServerFailed(error) => {
+                if self.socket_widget.waiting() {
+                    self.socket_widget.command =
+                        Some(SocketCommand::Failed("нет связи с сервером".into()));
+                }
+
+                self.server_status = match self.server.remote {
+                    true => format!("Нет связи с сервером: {error}"),
+                    false => format!("Сервер не запущен: {error}"),
+                };
+            }
+            Message::Ready(requests) => self.requests = Some(requests),
+
+            Message::AddressChanged(address) => self.address_input = address,
+            Message::RestartServer => self.restart_server(),
+            Message::ToggleSocket => self.toggle_socket(),
+            Message::CommandFinished(reply) => self.command_finished(reply),
+            Message::Spin => self.spinner = self.spinner.wrapping_add(1),
+            Message::ConfigChanged(device, field, value) => self
+                .config_inputs
+                .entry(device)
+                .or_default()
+                .set(field, value),
+            Message::ApplyConfig(device) => self.apply_config(device),
         }
     }
 
-    fn view(&self) -> Row<Message> {
+    fn view(&self) -> Column<'_, Message> {
         let roboto = Font::with_name("Roboto");
 
         let socket_label = Text::new("Розетка").font(roboto).size(32);
@@ -155,13 +792,69 @@ impl SmartDeviceApp {
 
         let socket_display = Text::new(self.socket_widget.value()).font(roboto).size(24);
 
+        let socket_toggle = match self.socket_widget.command {
+            Some(SocketCommand::Waiting { on, .. }) => Button::new(
+                Text::new(format!(
+                    "{} {}",
+                    SPINNER[self.spinner % SPINNER.len()],
+                    if on {
+                        "Включение..."
+                    } else {
+                        "Выключение..."
+                    }
+                ))
+                .font(roboto),
+            ),
+            _ => Button::new(
+                Text::new(if self.socket_widget.state {
+                    "Выключить"
+                } else {
+                    "Включить"
+                })
+                .font(roboto),
+            )
+            .on_press(Message::ToggleSocket),
+        };
+
+        let socket_command = Row::new()
+            .spacing(10)
+            .align_y(iced::Alignment::Center)
+            .push(socket_toggle)
+            .push(
+                Text::new(self.socket_widget.command_status())
+                    .font(roboto)
+                    .size(14),
+            );
+
+        let socket_info = Text::new(self.device_details(DeviceKind::Socket))
+            .font(roboto)
+            .size(14);
+
+        let socket_details = Text::new(self.sequence_details(DeviceKind::Socket))
+            .font(roboto)
+            .size(14);
+
+        let socket_config = Text::new(self.config_details(DeviceKind::Socket))
+            .font(roboto)
+            .size(14);
+
+        let socket_pending = Text::new(self.pending_details(DeviceKind::Socket))
+            .font(roboto)
+            .size(14);
+
         let socket_widget = Column::new()
             .spacing(12)
             .padding(20)
             .width(Length::Fill)
             .push(socket_label)
             .push(socket_state)
-            .push(socket_display);
+            .push(socket_display)
+            .push(socket_info)
+            .push(socket_details)
+            .push(socket_config)
+            .push(socket_pending)
+            .push(self.config_editor(DeviceKind::Socket, roboto))
+            .push(socket_command);
 
         let termo_label = Text::new("Термометр").font(roboto).size(32);
 
@@ -169,100 +862,412 @@ impl SmartDeviceApp {
 
         let termo_display = Text::new(self.termo_widget.value()).font(roboto).size(24);
 
+        let termo_info = Text::new(self.device_details(DeviceKind::Termometer))
+            .font(roboto)
+            .size(14);
+
+        let termo_details = Text::new(self.sequence_details(DeviceKind::Termometer))
+            .font(roboto)
+            .size(14);
+
+        let termo_config = Text::new(self.config_details(DeviceKind::Termometer))
+            .font(roboto)
+            .size(14);
+
+        let termo_pending = Text::new(self.pending_details(DeviceKind::Termometer))
+            .font(roboto)
+            .size(14);
+
         let termo_widget = Column::new()
             .spacing(10)
             .padding(20)
             .width(Length::Fill)
             .push(termo_label)
             .push(termo_state)
-            .push(termo_display);
+            .push(termo_display)
+            .push(termo_info)
+            .push(termo_details)
+            .push(termo_config)
+            .push(termo_pending)
+            .push(self.config_editor(DeviceKind::Termometer, roboto));
 
-        Row::new().push(socket_widget).push(termo_widget)
+        let feed_stats = Text::new(format!(
+            "Объединено обновлений: {}  Потеряно событий: {}  Повторов: {}  Дозагружено: {}  {}",
+            self.stats.coalesced,
+            self.stats.dropped,
+            self.stats.duplicates,
+            self.stats.backfilled,
+            self.last_event.as_deref().unwrap_or_default()
+        ))
+        .font(roboto)
+        .size(14);
+
+        let deliveries = Text::new(self.delivery_details()).font(roboto).size(14);
+
+        let address = TextInput::new(DEFAULT_ADDRESS, &self.address_input)
+            .on_input(Message::AddressChanged)
+            .on_submit(Message::RestartServer)
+            .font(roboto)
+            .width(Length::Fixed(220.0));
+
+        let restart = Button::new(
+            Text::new(if self.server.remote {
+                "Подключиться"
+            } else {
+                "Перезапустить"
+            })
+            .font(roboto),
+        )
+        .on_press(Message::RestartServer);
+
+        let server_status = Text::new(&self.server_status).font(roboto).size(16);
+
+        let server_controls = Row::new()
+            .spacing(10)
+            .padding([0, 20])
+            .align_y(iced::Alignment::Center)
+            .push(address)
+            .push(restart)
+            .push(server_status);
+
+        Column::new()
+            .spacing(10)
+            .push(Row::new().push(socket_widget).push(termo_widget))
+            .push(Row::new().padding([0, 20]).push(feed_stats))
+            .push(Row::new().padding([0, 20]).push(deliveries))
+            .push(server_controls)
     }
 
     fn subscription(&self) -> Subscription<Message> {
-        let netwrk = from_recipe(NetStream(self.net_event_receiver.clone()));
+        let server = self.server.clone();
+
+        let worker = match server.remote {
+            true => remote_worker(server.address.clone()).boxed(),
+            false => server_worker(server.clone()).boxed(),
+        };
 
-        Subscription::batch([netwrk])
+        let worker = Subscription::run_with_id(server, worker);
 
-        //Subscription::run(worker)
+        match self.socket_widget.waiting() {
+            true => Subscription::batch([
+                worker,
+                iced::time::every(SPINNER_INTERVAL).map(|_| Message::Spin),
+            ]),
+            false => worker,
+        }
     }
 }
 
-async fn device_server(net_event_sender: mpsc::Sender<SensorData>) {
-    let listener = TcpListener::bind("localhost:8080").await.unwrap();
+// Owns the whole server side of the dashboard: starts the device server and
+// turns its frames into messages. Dropping the stream (iced does it when the
+// subscription identity changes) stops the server.
+fn server_worker(server: ServerConfig) -> impl Stream<Item = Message> {
+    async_stream::stream! {
+        let mut builder = DeviceServer::new()
+            .bind(server.address)
+            .api(server.api_address)
+            .config_file(server.config_file)
+            .frame_interval(FRAME_INTERVAL);
 
-    tokio::spawn(async move {
-        loop {
-            let (tcp, _) = listener.accept().await.unwrap();
+        if let Some(http_address) = server.http_address {
+            builder = builder.http(http_address);
+        }
 
-            let mut tx_clone = net_event_sender.clone();
+        if let Some(mqtt) = server.mqtt {
+            builder = builder.mqtt(mqtt);
+        }
 
-            tokio::spawn(async move {
-                match handle_connection(tcp).await {
-                    Some(SensorData::SocketIndicator(s)) => {
-                        let _ = tx_clone.send(SensorData::SocketIndicator(s)).await;
-                    }
-                    Some(SensorData::TermoIndicator(t)) => {
-                        let _ = tx_clone.send(SensorData::TermoIndicator(t)).await;
-                    }
-                    None => {
-                        print!("Nothing is happend");
+        if let Some(influx) = server.influx {
+            builder = builder.influx(influx);
+        }
+
+        for webhook in server.webhooks {
+            builder = builder.webhook(webhook);
+        }
+
+        for modbus in server.modbus {
+            builder = builder.modbus(modbus);
+        }
+
+        for poll in server.polls {
+            builder = builder.poll(poll);
+        }
+
+        let started = builder.start().await;
+
+        let (server, mut events) = match started {
+            Ok(server) => server,
+            Err(e) => {
+                yield Message::ServerFailed(e.to_string());
+                return;
+            }
+        };
+
+        yield Message::ServerStarted {
+            devices: server.local_addr(),
+            api: server.api_addr(),
+            http: server.http_addr(),
+        };
+
+        let (request_sender, mut requests) = mpsc::channel(COMMAND_BUFFER);
+        yield Message::Ready(request_sender);
+
+        let mut outcomes = FuturesUnordered::new();
+
+        loop {
+            let message = tokio::select! {
+                frame = events.next() => match frame {
+                    Some(frame) => Message::Frame(frame),
+                    None => break,
+                },
+                Some(reply) = outcomes.next(), if !outcomes.is_empty() => {
+                    Message::CommandFinished(reply)
+                }
+                Some(request) = requests.next() => {
+                    match request {
+                        Request::Command(id, command) => {
+                            let outcome = server.submit(&command);
+
+                            outcomes.push(async move {
+                                CommandReply::new(id, outcome.await.map_err(|e| e.to_string()))
+                            });
+                        }
+                        Request::Configure(device, config) => {
+                            server.registry().configure(device, config).await;
+                        }
                     }
+                    continue;
                 }
-            });
+            };
+
+            yield message;
         }
-    });
+    }
 }
 
-async fn handle_connection(mut socket: TcpStream) -> Option<SensorData> {
-    let mut buf = [0; 128];
+// A remote dashboard mirrors the server's registry into a local one, so the
+// frames it produces are capped and coalesced exactly like in-process ones.
+fn remote_worker(address: String) -> impl Stream<Item = Message> {
+    async_stream::stream! {
+        let registry = Arc::new(Registry::default());
+        let mut events = EventStream::new(registry.clone(), FRAME_INTERVAL);
+
+        let (request_sender, requests) = mpsc::channel(COMMAND_BUFFER);
+        let (status_sender, mut statuses) = tokio::sync::mpsc::channel(4);
+        let _client = AbortOnDrop(tokio::spawn(mirror(
+            address,
+            registry,
+            requests,
+            status_sender,
+        )));
 
-    let n = socket.read(&mut buf).await.unwrap();
-    let recieved = String::from_utf8_lossy(&buf[..n]);
+        yield Message::Ready(request_sender);
 
-    if let Ok(t) = recieved.parse::<Termometer>() {
-        return Some(SensorData::TermoIndicator(t));
+        loop {
+            let message = tokio::select! {
+                Some(frame) = events.next() => Message::Frame(frame),
+                Some(status) = statuses.recv() => status,
+            };
+
+            yield message;
+        }
     }
+}
 
-    if let Ok(s) = recieved.parse::<Socket>() {
-        return Some(SensorData::SocketIndicator(s));
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// Same ingestion as the dashboard uses, but readings go to stdout and the
+// process stops cleanly on SIGINT/SIGTERM.
+async fn run_headless(options: Options) -> Result<(), Box<dyn Error>> {
+    let mut builder = DeviceServer::new()
+        .bind(options.address)
+        .api(options.api_address)
+        .config_file(options.config_file);
+
+    if let Some(http_address) = options.http_address {
+        builder = builder.http(http_address);
+    }
+
+    if let Some(mqtt) = options.mqtt {
+        builder = builder.mqtt(mqtt);
+    }
+
+    if let Some(influx) = options.influx {
+        builder = builder.influx(influx);
+    }
+
+    for webhook in options.webhooks {
+        builder = builder.webhook(webhook);
+    }
+
+    for modbus in options.modbus {
+        builder = builder.modbus(modbus);
+    }
+
+    for poll in options.polls {
+        builder = builder.poll(poll);
+    }
+
+    let (server, mut events) = builder.start().await?;
+
+    log_started(&server);
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    let mut stopping = false;
+
+    loop {
+        tokio::select! {
+            _ = &mut shutdown, if !stopping => {
+                println!("Остановка сервера...");
+                server.stop();
+                stopping = true;
+            }
+            frame = events.next() => match frame {
+                Some(frame) => log_frame(frame),
+                None => break,
+            },
+        }
     }
 
-    None
+    println!("Сервер остановлен");
+
+    Ok(())
+}
+
+fn log_started(server: &ServerHandle) {
+    println!(
+        "{}",
+        listening(server.local_addr(), server.api_addr(), server.http_addr())
+    );
+}
+
+fn listening(devices: SocketAddr, api: Option<SocketAddr>, http: Option<SocketAddr>) -> String {
+    let mut status = format!("Сервер слушает {devices}");
+
+    if let Some(api) = api {
+        status.push_str(&format!(", клиенты: {api}"));
+    }
+
+    if let Some(http) = http {
+        status.push_str(&format!(", HTTP: {http}"));
+    }
+
+    status
+}
+
+fn log_frame(frame: Frame) {
+    for reading in frame.readings {
+        println!("[{}] {}", reading.kind(), reading);
+    }
+
+    for event in frame.events {
+        println!("[event] {event}");
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
 
-struct NetStream(Arc<Mutex<Receiver<SensorData>>>);
+// Keeps a remote dashboard connected to the server: reconnects after
+// RECONNECT_DELAY and forwards the operator's requests while connected. The
+// outcome of each command comes back as a `CommandFinished` message.
+async fn mirror(
+    address: String,
+    registry: Arc<Registry>,
+    mut requests: mpsc::Receiver<Request>,
+    status: tokio::sync::mpsc::Sender<Message>,
+) {
+    loop {
+        let failure = match TcpStream::connect(&address).await {
+            Ok(tcp) => {
+                let _ = status.send(Message::Connected(address.clone())).await;
+
+                mirror_session(tcp, &registry, &mut requests, &status).await
+            }
+            Err(e) => e.to_string(),
+        };
 
-impl Recipe for NetStream {
-    type Output = Message;
+        let _ = status
+            .send(Message::ServerFailed(format!("{address}: {failure}")))
+            .await;
 
-    fn hash(&self, state: &mut Hasher) {
-        std::any::TypeId::of::<Self>().hash(state)
+        tokio::time::sleep(RECONNECT_DELAY).await;
     }
+}
 
-    fn stream(self: Box<Self>, _: EventStream) -> BoxStream<'static, Self::Output> {
-        Box::pin(async_stream::stream! {
-            let mut receiver = self.0.lock().await;
+async fn mirror_session(
+    tcp: TcpStream,
+    registry: &Registry,
+    requests: &mut mpsc::Receiver<Request>,
+    status: &tokio::sync::mpsc::Sender<Message>,
+) -> String {
+    let (reader, mut writer) = tcp.into_split();
+    let mut lines = LineReader::new(reader, MAX_LINE_LENGTH);
 
-            while let Some(event) = receiver.next().await {
-                match event {
-                    SensorData::SocketIndicator(s) => {
-                        if s.state().get() {
-                            yield Message::SocketOnline(s)
-                        } else {
-                            yield Message::SocketOffline
-                        };
+    loop {
+        tokio::select! {
+            line = lines.next_line() => match line {
+                // `ok()` drops the parse error, which can't be held across an await.
+                Ok(Some(line)) => match line.parse::<ApiLine>().ok() {
+                    Some(ApiLine::Snapshot) => registry.clear(),
+                    Some(ApiLine::State(data)) => registry.publish(data),
+                    Some(ApiLine::Event(event)) => registry.report(ServerEvent::Notice(event)),
+                    Some(ApiLine::Sequence(device, stats)) => registry.set_sequence(device, stats),
+                    Some(ApiLine::Device(info)) => registry.register(info),
+                    Some(ApiLine::Config(device, config)) => registry.set_config(device, config),
+                    Some(ApiLine::Pending(device, pending)) => registry.set_pending(device, pending),
+                    Some(ApiLine::Delivery(delivery)) => registry.add_delivery(delivery),
+                    Some(ApiLine::Result(reply)) => {
+                        let _ = status.send(Message::CommandFinished(reply)).await;
                     }
-                    SensorData::TermoIndicator(t) => {
-                        if t.state().get() {
-                            yield Message::TermometerOnline(t)
-                        } else {
-                            yield Message::TermometerOffline
-                        };
+                    None => {}
+                },
+                Ok(None) => return "соединение закрыто сервером".into(),
+                Err(e) => return e.to_string(),
+            },
+            Some(request) = requests.next() => {
+                let request = match request {
+                    Request::Command(id, command) => {
+                        format!("COMMAND {}\n", Envelope::new(command.to_string()).with_id(id))
+                    }
+                    Request::Configure(device, config) => {
+                        format!("{}\n", ApiLine::Config(device, config))
                     }
+                };
+
+                if let Err(e) = writer.write_all(request.as_bytes()).await {
+                    return e.to_string();
                 }
             }
-        })
+        }
     }
 }
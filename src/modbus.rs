@@ -0,0 +1,317 @@
+//! Just enough Modbus TCP to poll off-the-shelf meters and sensors: reading
+//! coils, holding and input registers and writing single coils, plus a
+//! simulator answering the same requests.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::listener;
+
+const READ_COILS: u8 = 0x01;
+const READ_HOLDING: u8 = 0x03;
+const READ_INPUT: u8 = 0x04;
+const WRITE_COIL: u8 = 0x05;
+
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_VALUE: u8 = 0x03;
+
+// Most registers and coils one read may ask for.
+const MAX_REGISTERS: u16 = 125;
+const MAX_COILS: u16 = 2000;
+
+const COIL_ON: u16 = 0xFF00;
+
+/// A connection to one Modbus TCP server, talking to unit `unit` behind it.
+pub struct ModbusClient {
+    tcp: TcpStream,
+    unit: u8,
+    transaction: u16,
+}
+
+impl ModbusClient {
+    pub async fn connect(address: &str, unit: u8) -> io::Result<Self> {
+        Ok(Self {
+            tcp: TcpStream::connect(address).await?,
+            unit,
+            transaction: 0,
+        })
+    }
+
+    pub async fn read_holding(&mut self, address: u16, count: u16) -> io::Result<Vec<u16>> {
+        self.read_registers(READ_HOLDING, address, count).await
+    }
+
+    pub async fn read_input(&mut self, address: u16, count: u16) -> io::Result<Vec<u16>> {
+        self.read_registers(READ_INPUT, address, count).await
+    }
+
+    pub async fn read_coils(&mut self, address: u16, count: u16) -> io::Result<Vec<bool>> {
+        let data = self
+            .request(
+                READ_COILS,
+                &[address.to_be_bytes(), count.to_be_bytes()].concat(),
+            )
+            .await?;
+
+        let bytes = data.get(1..).unwrap_or_default();
+
+        if bytes.len() * 8 < count as usize {
+            return Err(io::Error::other("короткий ответ"));
+        }
+
+        Ok((0..count as usize)
+            .map(|bit| bytes[bit / 8] & (1 << (bit % 8)) != 0)
+            .collect())
+    }
+
+    pub async fn write_coil(&mut self, address: u16, on: bool) -> io::Result<()> {
+        let value = if on { COIL_ON } else { 0 };
+
+        self.request(
+            WRITE_COIL,
+            &[address.to_be_bytes(), value.to_be_bytes()].concat(),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn read_registers(
+        &mut self,
+        function: u8,
+        address: u16,
+        count: u16,
+    ) -> io::Result<Vec<u16>> {
+        let data = self
+            .request(
+                function,
+                &[address.to_be_bytes(), count.to_be_bytes()].concat(),
+            )
+            .await?;
+
+        let bytes = data.get(1..).unwrap_or_default();
+
+        if bytes.len() < count as usize * 2 {
+            return Err(io::Error::other("короткий ответ"));
+        }
+
+        Ok(bytes
+            .chunks_exact(2)
+            .take(count as usize)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect())
+    }
+
+    // Sends one request and returns the data of the answer, after the
+    // function code.
+    async fn request(&mut self, function: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.transaction = self.transaction.wrapping_add(1);
+
+        let mut pdu = vec![function];
+        pdu.extend_from_slice(data);
+        self.tcp
+            .write_all(&frame(self.transaction, self.unit, &pdu))
+            .await?;
+
+        let (transaction, _, pdu) = read_frame(&mut self.tcp)
+            .await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+
+        if transaction != self.transaction {
+            return Err(io::Error::other("ответ на чужой запрос"));
+        }
+
+        match pdu.split_first() {
+            Some((answer, data)) if *answer == function => Ok(data.to_vec()),
+            Some((answer, data)) if *answer == function | 0x80 => Err(io::Error::other(format!(
+                "исключение {}",
+                data.first().copied().unwrap_or_default()
+            ))),
+            _ => Err(io::Error::other("некорректный ответ")),
+        }
+    }
+}
+
+// An MBAP header followed by the PDU.
+fn frame(transaction: u16, unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(7 + pdu.len());
+    frame.extend_from_slice(&transaction.to_be_bytes());
+    frame.extend_from_slice(&0u16.to_be_bytes());
+    frame.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+    frame.push(unit);
+    frame.extend_from_slice(pdu);
+    frame
+}
+
+// The transaction, unit and PDU of the next frame, or `None` once the peer
+// closed the connection.
+async fn read_frame(tcp: &mut TcpStream) -> io::Result<Option<(u16, u8, Vec<u8>)>> {
+    let mut header = [0; 7];
+
+    match tcp.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let transaction = u16::from_be_bytes([header[0], header[1]]);
+    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+
+    if len < 2 {
+        return Err(io::Error::other("некорректный кадр"));
+    }
+
+    let mut pdu = vec![0; len - 1];
+    tcp.read_exact(&mut pdu).await?;
+
+    Ok(Some((transaction, header[6], pdu)))
+}
+
+/// A Modbus TCP server backed by memory, for trying the adapter without a
+/// meter. Every address exists and starts at zero; clones share the same
+/// registers and coils.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use otus_iced::modbus::ModbusSimulator;
+/// use tokio::net::TcpListener;
+///
+/// let simulator = ModbusSimulator::new();
+/// simulator.set_holding(0, 1500);
+/// simulator.set_coil(0, true);
+///
+/// simulator.serve(TcpListener::bind("127.0.0.1:5020").await?).await
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ModbusSimulator {
+    bank: Arc<Mutex<Bank>>,
+}
+
+#[derive(Debug, Default)]
+struct Bank {
+    holding: BTreeMap<u16, u16>,
+    input: BTreeMap<u16, u16>,
+    coils: BTreeMap<u16, bool>,
+}
+
+impl ModbusSimulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn bank(&self) -> MutexGuard<'_, Bank> {
+        self.bank.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set_holding(&self, address: u16, value: u16) {
+        self.bank().holding.insert(address, value);
+    }
+
+    pub fn set_input(&self, address: u16, value: u16) {
+        self.bank().input.insert(address, value);
+    }
+
+    pub fn set_coil(&self, address: u16, on: bool) {
+        self.bank().coils.insert(address, on);
+    }
+
+    pub fn holding(&self, address: u16) -> u16 {
+        self.bank()
+            .holding
+            .get(&address)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn coil(&self, address: u16) -> bool {
+        self.bank().coils.get(&address).copied().unwrap_or_default()
+    }
+
+    /// Serves the registers and coils to clients on `listener` for as long as
+    /// the future is kept.
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        listener::serve(listener, |tcp| self.clone().answer(tcp)).await
+    }
+
+    async fn answer(self, mut tcp: TcpStream) {
+        while let Ok(Some((transaction, unit, pdu))) = read_frame(&mut tcp).await {
+            let answer = self.execute(&pdu);
+
+            if tcp
+                .write_all(&frame(transaction, unit, &answer))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+
+    fn execute(&self, pdu: &[u8]) -> Vec<u8> {
+        let Some((&function, data)) = pdu.split_first() else {
+            return vec![0x80, ILLEGAL_FUNCTION];
+        };
+
+        let exception = |code| vec![function | 0x80, code];
+
+        if data.len() < 4 {
+            return exception(ILLEGAL_VALUE);
+        }
+
+        let address = u16::from_be_bytes([data[0], data[1]]);
+        let value = u16::from_be_bytes([data[2], data[3]]);
+        let mut bank = self.bank();
+
+        match function {
+            READ_COILS if (1..=MAX_COILS).contains(&value) => {
+                let mut bytes = vec![0; value.div_ceil(8) as usize];
+
+                for bit in 0..value {
+                    let on = bank
+                        .coils
+                        .get(&address.wrapping_add(bit))
+                        .copied()
+                        .unwrap_or_default();
+
+                    if on {
+                        bytes[bit as usize / 8] |= 1 << (bit % 8);
+                    }
+                }
+
+                [vec![function, bytes.len() as u8], bytes].concat()
+            }
+            READ_HOLDING | READ_INPUT if (1..=MAX_REGISTERS).contains(&value) => {
+                let table = match function {
+                    READ_HOLDING => &bank.holding,
+                    _ => &bank.input,
+                };
+
+                let mut answer = vec![function, (value * 2) as u8];
+
+                for offset in 0..value {
+                    let register = table
+                        .get(&address.wrapping_add(offset))
+                        .copied()
+                        .unwrap_or_default();
+                    answer.extend_from_slice(&register.to_be_bytes());
+                }
+
+                answer
+            }
+            WRITE_COIL if value == COIL_ON || value == 0 => {
+                bank.coils.insert(address, value == COIL_ON);
+                pdu.to_vec()
+            }
+            READ_COILS | READ_HOLDING | READ_INPUT | WRITE_COIL => exception(ILLEGAL_VALUE),
+            _ => exception(ILLEGAL_FUNCTION),
+        }
+    }
+}
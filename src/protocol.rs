@@ -0,0 +1,377 @@
+use std::{error::Error, fmt::Display, str::FromStr};
+
+use crate::{
+    device::{Capability, DeviceInfo, DeviceKind},
+    state::DeviceState,
+};
+
+/// Protocol versions this crate speaks, oldest first. Since version 2 the
+/// server tags commands with an id and the device answers with
+/// `CommandReply`.
+pub const PROTOCOL_VERSIONS: &[u32] = &[1, 2];
+
+/// The first version with command ids.
+pub const COMMAND_IDS: u32 = 2;
+
+/// What a polling server sends a device that listens instead of connecting;
+/// the device answers with its latest reading.
+pub const STATUS_QUERY: &str = "status?";
+
+/// A line from a device: the payload the codecs understand, optionally
+/// followed by headers, e.g. "Socket 1500W State: on | seq=12 ts=1700000000000".
+/// `ts` is when the device took the reading, in milliseconds since the Unix
+/// epoch. Lines without headers are what older devices send and are never
+/// acknowledged. Commands from the server carry their `id` the same way,
+/// e.g. "SET off | id=7".
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub payload: String,
+    pub seq: Option<u64>,
+    pub ts: Option<u64>,
+    pub id: Option<u64>,
+}
+
+impl Envelope {
+    pub fn new(payload: impl Into<String>) -> Self {
+        Self {
+            payload: payload.into(),
+            seq: None,
+            ts: None,
+            id: None,
+        }
+    }
+
+    pub fn with_seq(mut self, seq: u64) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+
+    pub fn with_ts(mut self, ts: u64) -> Self {
+        self.ts = Some(ts);
+        self
+    }
+
+    pub fn with_id(mut self, id: u64) -> Self {
+        self.id = Some(id);
+        self
+    }
+}
+
+impl Display for Envelope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.payload)?;
+
+        let headers: Vec<String> = [
+            self.seq.map(|seq| format!("seq={seq}")),
+            self.ts.map(|ts| format!("ts={ts}")),
+            self.id.map(|id| format!("id={id}")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        if !headers.is_empty() {
+            write!(f, " | {}", headers.join(" "))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Envelope {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((payload, headers)) = s.rsplit_once(" | ") else {
+            return Ok(Envelope::new(s));
+        };
+
+        let mut envelope = Envelope::new(payload);
+
+        // Unknown headers are skipped so newer devices can talk to this server.
+        for header in headers.split_whitespace() {
+            match header.split_once('=') {
+                Some(("seq", value)) => envelope.seq = Some(value.parse()?),
+                Some(("ts", value)) => envelope.ts = Some(value.parse()?),
+                Some(("id", value)) => envelope.id = Some(value.parse()?),
+                _ => {}
+            }
+        }
+
+        Ok(envelope)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NackCode {
+    /// No codec understood the payload.
+    Malformed,
+    /// The device kind is disabled on this server.
+    Unsupported,
+    Other(u16),
+}
+
+impl NackCode {
+    pub fn code(&self) -> u16 {
+        match self {
+            NackCode::Malformed => 400,
+            NackCode::Unsupported => 403,
+            NackCode::Other(code) => *code,
+        }
+    }
+}
+
+impl From<u16> for NackCode {
+    fn from(code: u16) -> Self {
+        match code {
+            400 => NackCode::Malformed,
+            403 => NackCode::Unsupported,
+            code => NackCode::Other(code),
+        }
+    }
+}
+
+impl Display for NackCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+/// The server's answer to a device line that carried a sequence number:
+/// "ACK 12" or "NACK 12 400 <reason>". A retried line the server already
+/// accepted is acknowledged again but not applied twice.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Ack(u64),
+    Nack {
+        seq: u64,
+        code: NackCode,
+        reason: String,
+    },
+}
+
+impl Display for Reply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reply::Ack(seq) => write!(f, "ACK {seq}"),
+            Reply::Nack { seq, code, reason } => write!(f, "NACK {seq} {code} {reason}"),
+        }
+    }
+}
+
+impl FromStr for Reply {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(seq) = s.strip_prefix("ACK ") {
+            return Ok(Reply::Ack(seq.parse()?));
+        }
+
+        if let Some(rest) = s.strip_prefix("NACK ") {
+            let mut parts = rest.splitn(3, ' ');
+
+            let seq = parts.next().ok_or("missing sequence number")?.parse()?;
+            let code = parts.next().ok_or("missing error code")?.parse::<u16>()?;
+            let reason = parts.next().unwrap_or_default().to_string();
+
+            return Ok(Reply::Nack {
+                seq,
+                code: code.into(),
+                reason,
+            });
+        }
+
+        Err("unknown reply".into())
+    }
+}
+
+/// A device's answer to a command with an id: "DONE 7 on" with the state
+/// the device is in afterwards, or "FAILED 7 <reason>". The server answers
+/// remote dashboards the same way, with the id they chose.
+#[derive(Debug, Clone)]
+pub enum CommandReply {
+    Done { id: u64, state: DeviceState },
+    Failed { id: u64, reason: String },
+}
+
+impl CommandReply {
+    pub fn new(id: u64, result: Result<DeviceState, String>) -> Self {
+        match result {
+            Ok(state) => CommandReply::Done { id, state },
+            Err(reason) => CommandReply::Failed { id, reason },
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        match self {
+            CommandReply::Done { id, .. } | CommandReply::Failed { id, .. } => *id,
+        }
+    }
+}
+
+impl Display for CommandReply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandReply::Done { id, state } => write!(f, "DONE {id} {state}"),
+            CommandReply::Failed { id, reason } => write!(f, "FAILED {id} {reason}"),
+        }
+    }
+}
+
+impl FromStr for CommandReply {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(rest) = s.strip_prefix("DONE ") {
+            let (id, state) = rest.split_once(' ').ok_or("missing state")?;
+
+            return Ok(CommandReply::Done {
+                id: id.parse()?,
+                state: state.parse()?,
+            });
+        }
+
+        if let Some(rest) = s.strip_prefix("FAILED ") {
+            let (id, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+
+            return Ok(CommandReply::Failed {
+                id: id.parse()?,
+                reason: reason.into(),
+            });
+        }
+
+        Err("unknown command reply".into())
+    }
+}
+
+/// The first line of a session from a device that introduces itself, e.g.
+/// "HELLO kind=socket id=socket-1 firmware=0.1.0 protocols=1 capabilities=switch,power".
+/// Devices that skip it are still served, with the kind guessed from readings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub kind: DeviceKind,
+    pub id: String,
+    pub firmware: String,
+    pub protocols: Vec<u32>,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Hello {
+    /// The newest protocol version both sides speak.
+    pub fn negotiate(&self, supported: &[u32]) -> Option<u32> {
+        self.protocols
+            .iter()
+            .filter(|version| supported.contains(version))
+            .max()
+            .copied()
+    }
+
+    pub fn info(&self, protocol: u32) -> DeviceInfo {
+        DeviceInfo {
+            kind: self.kind,
+            id: self.id.clone(),
+            firmware: self.firmware.clone(),
+            protocol,
+            capabilities: self.capabilities.clone(),
+        }
+    }
+}
+
+impl Display for Hello {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "HELLO kind={} id={} firmware={} protocols={} capabilities={}",
+            self.kind,
+            self.id,
+            self.firmware,
+            join(&self.protocols),
+            join(&self.capabilities)
+        )
+    }
+}
+
+impl FromStr for Hello {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = Fields::parse(s.strip_prefix("HELLO ").ok_or("not a hello")?)?;
+
+        Ok(Self {
+            kind: fields.get("kind")?.parse()?,
+            id: fields.get("id")?.into(),
+            firmware: fields.get("firmware")?.into(),
+            protocols: split(fields.get("protocols")?),
+            capabilities: split(fields.get("capabilities").unwrap_or_default()),
+        })
+    }
+}
+
+/// The server's answer to HELLO: "WELCOME protocol=1", or "REFUSED <reason>"
+/// right before it closes the connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HelloReply {
+    Welcome { protocol: u32 },
+    Refused(String),
+}
+
+impl Display for HelloReply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HelloReply::Welcome { protocol } => write!(f, "WELCOME protocol={protocol}"),
+            HelloReply::Refused(reason) => write!(f, "REFUSED {reason}"),
+        }
+    }
+}
+
+impl FromStr for HelloReply {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(fields) = s.strip_prefix("WELCOME ") {
+            let protocol = Fields::parse(fields)?.get("protocol")?.parse()?;
+            return Ok(HelloReply::Welcome { protocol });
+        }
+
+        if let Some(reason) = s.strip_prefix("REFUSED ") {
+            return Ok(HelloReply::Refused(reason.into()));
+        }
+
+        Err("unknown hello reply".into())
+    }
+}
+
+// "name=value" pairs separated by spaces; values can't contain spaces.
+pub(crate) struct Fields<'a>(Vec<(&'a str, &'a str)>);
+
+impl<'a> Fields<'a> {
+    pub(crate) fn parse(s: &'a str) -> Result<Self, Box<dyn Error>> {
+        s.split_whitespace()
+            .map(|field| field.split_once('=').ok_or("expected name=value".into()))
+            .collect::<Result<_, _>>()
+            .map(Fields)
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Result<&'a str, Box<dyn Error>> {
+        self.0
+            .iter()
+            .find(|(field, _)| *field == name)
+            .map(|(_, value)| *value)
+            .ok_or_else(|| format!("missing {name}").into())
+    }
+}
+
+pub(crate) fn join<T: Display>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// Unknown items are skipped, so newer devices can talk to older servers.
+pub(crate) fn split<T: FromStr>(list: &str) -> Vec<T> {
+    list.split(',')
+        .filter_map(|item| item.parse().ok())
+        .collect()
+}
@@ -0,0 +1,661 @@
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    fmt::Display,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Stream;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinSet,
+};
+
+use crate::{
+    device::{Command, DeviceKind},
+    listener::Acceptor,
+    state::DeviceState,
+};
+
+mod api;
+mod codec;
+mod http;
+mod influx;
+mod metrics;
+mod modbus;
+mod mqtt;
+mod poll;
+mod registry;
+mod session;
+mod webhook;
+mod websocket;
+
+pub use api::ApiLine;
+pub use codec::{Codec, TextCodec};
+pub use http::{
+    CommandBody, CommandResource, ConfigResource, DeviceResource, ErrorResource, HealthResource,
+    InfoResource, PendingResource, ReadingResource,
+};
+pub use influx::{InfluxConfig, InfluxSink};
+pub use modbus::{ModbusConfig, ModbusRegister, RegisterTable};
+pub use mqtt::{MqttConfig, QoS};
+pub use poll::PollConfig;
+pub use registry::{
+    FeedStats, Frame, PendingCommand, Registry, Sample, SequenceStats, ServerCounters, ServerEvent,
+    Update,
+};
+pub use webhook::{WebhookConfig, WebhookDelivery, WebhookTrigger};
+pub use websocket::{FeedMessage, FeedRequest};
+
+pub const DEFAULT_ADDRESS: &str = "localhost:8080";
+
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Connections above this number are closed right after accept.
+    pub max_connections: usize,
+    /// Longer lines end the session, so a device can't make the server buffer
+    /// an endless message.
+    pub max_line_length: usize,
+    /// Discrete events kept for the event stream; the oldest are dropped.
+    pub event_queue: usize,
+    /// Updates a subscriber may fall behind before it is resynchronised.
+    pub update_buffer: usize,
+    /// Commands waiting to be written to one device.
+    pub command_buffer: usize,
+    /// How long a stopping server waits for open connections to finish.
+    pub shutdown_grace: Duration,
+    /// How long a command for a disconnected device waits for it to connect;
+    /// zero refuses such commands right away.
+    pub command_ttl: Duration,
+    /// Commands queued for one disconnected device.
+    pub command_queue: usize,
+    /// How long a device has to confirm a command it was sent.
+    pub command_timeout: Duration,
+    /// Readings kept in each device's history.
+    pub history: usize,
+    /// Readings the device took longer ago than this, e.g. uploaded after a
+    /// reconnect, go to history without replacing the live value.
+    pub backfill_after: Duration,
+    /// How long an HTTP client may take to send its request, head and body.
+    pub request_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            max_line_length: 1024,
+            event_queue: 64,
+            update_buffer: 256,
+            command_buffer: 16,
+            command_ttl: Duration::from_secs(60),
+            command_queue: 16,
+            command_timeout: Duration::from_secs(5),
+            shutdown_grace: Duration::from_secs(5),
+            history: 256,
+            backfill_after: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+pub(crate) struct Config {
+    pub(crate) limits: Limits,
+    pub(crate) codecs: Vec<Box<dyn Codec>>,
+    pub(crate) kinds: BTreeSet<DeviceKind>,
+}
+
+#[derive(Debug, Clone)]
+pub enum CommandError {
+    NotConnected(DeviceKind),
+    Busy(DeviceKind),
+    Unsupported(DeviceKind),
+    /// The device didn't connect before the queued command expired.
+    Expired(DeviceKind),
+    /// The device didn't confirm the command in time.
+    TimedOut(DeviceKind),
+    /// The device answered that it couldn't carry the command out.
+    Failed {
+        device: DeviceKind,
+        reason: String,
+    },
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::NotConnected(device) => write!(f, "Устройство {device} не подключено"),
+            CommandError::Busy(device) => {
+                write!(f, "Устройство {device} не успевает принимать команды")
+            }
+            CommandError::Unsupported(device) => {
+                write!(f, "Устройство {device} не принимает команды")
+            }
+            CommandError::Expired(device) => {
+                write!(f, "Устройство {device} не подключилось, команда просрочена")
+            }
+            CommandError::TimedOut(device) => {
+                write!(f, "Устройство {device} не подтвердило команду")
+            }
+            CommandError::Failed { device, reason } => write!(f, "{device}: {reason}"),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
+/// Where a command went right after it was issued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dispatch {
+    /// Handed to the connected device's session.
+    Sent,
+    /// Queued until the device connects (see `Limits::command_ttl`).
+    Queued,
+}
+
+/// Builder for the device server: where to listen for devices (and, if set,
+/// for remote dashboards and HTTP clients), which limits, codecs and device kinds to use.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use otus_iced::server::DeviceServer;
+///
+/// let (server, events) = DeviceServer::new().bind("127.0.0.1:0").start().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct DeviceServer {
+    address: String,
+    api_address: Option<String>,
+    http_address: Option<String>,
+    mqtt: Option<MqttConfig>,
+    influx: Option<InfluxConfig>,
+    webhooks: Vec<WebhookConfig>,
+    modbus: Vec<ModbusConfig>,
+    polls: Vec<PollConfig>,
+    frame_interval: Duration,
+    limits: Limits,
+    codecs: Vec<Box<dyn Codec>>,
+    kinds: BTreeSet<DeviceKind>,
+    config_file: Option<PathBuf>,
+}
+
+impl Default for DeviceServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceServer {
+    pub fn new() -> Self {
+        Self {
+            address: DEFAULT_ADDRESS.into(),
+            api_address: None,
+            http_address: None,
+            mqtt: None,
+            influx: None,
+            webhooks: Vec::new(),
+            modbus: Vec::new(),
+            polls: Vec::new(),
+            frame_interval: Duration::ZERO,
+            limits: Limits::default(),
+            codecs: Vec::new(),
+            kinds: DeviceKind::ALL.into_iter().collect(),
+            config_file: None,
+        }
+    }
+
+    pub fn bind(mut self, address: impl Into<String>) -> Self {
+        self.address = address.into();
+        self
+    }
+
+    /// Also accept remote dashboards on this address (see `ApiLine`).
+    pub fn api(mut self, address: impl Into<String>) -> Self {
+        self.api_address = Some(address.into());
+        self
+    }
+
+    /// Also serve the REST API, the WebSocket feed at `/ws` and a web
+    /// dashboard at `/` on this address (see `DeviceResource` and
+    /// `FeedMessage`).
+    pub fn http(mut self, address: impl Into<String>) -> Self {
+        self.http_address = Some(address.into());
+        self
+    }
+
+    /// Also mirror readings to an MQTT broker and take commands from it.
+    pub fn mqtt(mut self, config: MqttConfig) -> Self {
+        self.mqtt = Some(config);
+        self
+    }
+
+    /// Also export readings in InfluxDB line protocol.
+    pub fn influx(mut self, config: InfluxConfig) -> Self {
+        self.influx = Some(config);
+        self
+    }
+
+    /// Also POST to this webhook when its triggers fire; may be called for
+    /// several webhooks.
+    pub fn webhook(mut self, config: WebhookConfig) -> Self {
+        self.webhooks.push(config);
+        self
+    }
+
+    /// Also poll this Modbus TCP device; may be called for several devices,
+    /// one of each kind.
+    pub fn modbus(mut self, config: ModbusConfig) -> Self {
+        self.modbus.push(config);
+        self
+    }
+
+    /// Also poll this device, which listens for the server instead of
+    /// connecting to it; may be called for several devices.
+    pub fn poll(mut self, config: PollConfig) -> Self {
+        self.polls.push(config);
+        self
+    }
+
+    /// The event stream yields at most one frame per interval; zero means a
+    /// frame for every wake-up.
+    pub fn frame_interval(mut self, interval: Duration) -> Self {
+        self.frame_interval = interval;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Codecs are tried in the order they were added. Without any the server
+    /// speaks `TextCodec`.
+    pub fn codec(mut self, codec: impl Codec + 'static) -> Self {
+        self.codecs.push(Box::new(codec));
+        self
+    }
+
+    pub fn device_kinds(mut self, kinds: impl IntoIterator<Item = DeviceKind>) -> Self {
+        self.kinds = kinds.into_iter().collect();
+        self
+    }
+
+    /// Device configurations are loaded from this file at start and saved to
+    /// it whenever one changes.
+    pub fn config_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_file = Some(path.into());
+        self
+    }
+
+    /// Binds the listeners and starts serving on the current tokio runtime.
+    /// The server runs until `ServerHandle::stop` is called or both the
+    /// handle (with all its clones) and the event stream are dropped.
+    pub async fn start(mut self) -> io::Result<(ServerHandle, EventStream)> {
+        let listener = bind(&self.address).await?;
+        let api_listener = match &self.api_address {
+            Some(address) => Some(bind(address).await?),
+            None => None,
+        };
+
+        let http_listener = match &self.http_address {
+            Some(address) => Some(bind(address).await?),
+            None => None,
+        };
+
+        let local_addr = listener.local_addr()?;
+        let api_addr = match &api_listener {
+            Some(listener) => Some(listener.local_addr()?),
+            None => None,
+        };
+        let http_addr = match &http_listener {
+            Some(listener) => Some(listener.local_addr()?),
+            None => None,
+        };
+
+        if self.codecs.is_empty() {
+            self.codecs.push(Box::new(TextCodec));
+        }
+
+        let config = Arc::new(Config {
+            limits: self.limits,
+            codecs: self.codecs,
+            kinds: self.kinds,
+        });
+        let registry = Arc::new(Registry::new(&config.limits));
+
+        if let Some(path) = self.config_file {
+            registry
+                .load_configs(path.clone())
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        }
+
+        let (stop, stopped) = watch::channel(false);
+        let (done, finished) = watch::channel(false);
+
+        let devices = {
+            let (registry, config) = (registry.clone(), config.clone());
+
+            serve(
+                listener,
+                registry.clone(),
+                config.clone(),
+                stopped.clone(),
+                move |tcp, closing| {
+                    session::handle_connection(tcp, registry.clone(), config.clone(), closing)
+                },
+            )
+        };
+
+        let expiry = expire_commands(registry.clone(), config.clone(), stopped.clone());
+
+        let mqtt = {
+            let (registry, kinds, stopped) =
+                (registry.clone(), config.kinds.clone(), stopped.clone());
+
+            async move {
+                if let Some(mqtt) = self.mqtt {
+                    mqtt::bridge(registry, kinds, mqtt, stopped).await;
+                }
+            }
+        };
+
+        let influx = self.influx.map(|influx| {
+            let (updates, _) = registry.subscribe();
+
+            influx::export(
+                registry.clone(),
+                updates,
+                config.kinds.clone(),
+                influx,
+                stopped.clone(),
+            )
+        });
+        let influx = async move {
+            if let Some(export) = influx {
+                export.await;
+            }
+        };
+
+        let webhooks = (!self.webhooks.is_empty()).then(|| {
+            let (updates, _) = registry.subscribe();
+
+            webhook::dispatch(
+                registry.clone(),
+                updates,
+                config.kinds.clone(),
+                self.webhooks,
+                stopped.clone(),
+            )
+        });
+        let webhooks = async move {
+            if let Some(dispatch) = webhooks {
+                dispatch.await;
+            }
+        };
+
+        let modbus = futures::future::join_all(self.modbus.into_iter().map(|modbus| {
+            modbus::poll(
+                registry.clone(),
+                config.kinds.clone(),
+                modbus,
+                config.limits.command_buffer,
+                stopped.clone(),
+            )
+        }));
+
+        let polls = futures::future::join_all(
+            self.polls
+                .into_iter()
+                .map(|poll| poll::poll(registry.clone(), config.clone(), poll, stopped.clone())),
+        );
+
+        let api = {
+            let (registry, config, stopped) = (registry.clone(), config.clone(), stopped.clone());
+
+            async move {
+                let Some(listener) = api_listener else {
+                    return;
+                };
+
+                serve(
+                    listener,
+                    registry.clone(),
+                    config.clone(),
+                    stopped,
+                    move |tcp, closing| {
+                        api::handle_client(tcp, registry.clone(), config.clone(), closing)
+                    },
+                )
+                .await
+            }
+        };
+
+        let http = {
+            let (registry, config) = (registry.clone(), config.clone());
+
+            async move {
+                let Some(listener) = http_listener else {
+                    return;
+                };
+
+                serve(
+                    listener,
+                    registry.clone(),
+                    config.clone(),
+                    stopped,
+                    move |tcp, closing| {
+                        http::handle_connection(tcp, registry.clone(), config.clone(), closing)
+                    },
+                )
+                .await
+            }
+        };
+
+        let supervisor = registry.clone();
+        tokio::spawn(async move {
+            tokio::join!(
+                devices, api, http, mqtt, influx, webhooks, modbus, polls, expiry
+            );
+
+            supervisor.close();
+            let _ = done.send(true);
+        });
+
+        let shared = Arc::new(Shared {
+            local_addr,
+            api_addr,
+            http_addr,
+            stop,
+            finished,
+        });
+
+        let mut events = EventStream::new(registry.clone(), self.frame_interval);
+        events.server = Some(shared.clone());
+
+        Ok((ServerHandle { registry, shared }, events))
+    }
+}
+
+struct Shared {
+    local_addr: SocketAddr,
+    api_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    stop: watch::Sender<bool>,
+    finished: watch::Receiver<bool>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.stop.send_replace(true);
+    }
+}
+
+#[derive(Clone)]
+pub struct ServerHandle {
+    registry: Arc<Registry>,
+    shared: Arc<Shared>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shared.local_addr
+    }
+
+    pub fn api_addr(&self) -> Option<SocketAddr> {
+        self.shared.api_addr
+    }
+
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.shared.http_addr
+    }
+
+    pub fn registry(&self) -> &Arc<Registry> {
+        &self.registry
+    }
+
+    pub fn send(&self, command: &Command) -> Result<Dispatch, CommandError> {
+        self.registry.send(command)
+    }
+
+    /// See `Registry::submit`.
+    pub fn submit(
+        &self,
+        command: &Command,
+    ) -> impl Future<Output = Result<DeviceState, CommandError>> + use<> {
+        self.registry.submit(command)
+    }
+
+    /// Stops accepting connections and asks the open ones to close. The event
+    /// stream ends after the last frame once they are gone.
+    pub fn stop(&self) {
+        self.shared.stop.send_replace(true);
+    }
+
+    pub async fn shutdown(&self) {
+        self.stop();
+
+        let mut finished = self.shared.finished.clone();
+        let _ = finished.wait_for(|finished| *finished).await;
+    }
+}
+
+/// Frames of coalesced changes from a registry, at most one per interval.
+pub struct EventStream {
+    frames: Pin<Box<dyn Stream<Item = Frame> + Send>>,
+    server: Option<Arc<Shared>>,
+}
+
+impl EventStream {
+    pub fn new(registry: Arc<Registry>, interval: Duration) -> Self {
+        let frames = async_stream::stream! {
+            loop {
+                registry.changed().await;
+
+                let closed = registry.is_closed();
+
+                yield registry.take_frame();
+
+                if closed {
+                    break;
+                }
+
+                if !interval.is_zero() {
+                    tokio::time::sleep(interval).await;
+                }
+            }
+        };
+
+        Self {
+            frames: Box::pin(frames),
+            server: None,
+        }
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Frame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.frames.as_mut().poll_next(cx)
+    }
+}
+
+// Queued and unconfirmed commands run out of time even when nothing else
+// happens.
+async fn expire_commands(
+    registry: Arc<Registry>,
+    config: Arc<Config>,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let limits = &config.limits;
+    let shortest = [limits.command_ttl, limits.command_timeout]
+        .into_iter()
+        .filter(|limit| !limit.is_zero())
+        .min()
+        .unwrap_or(limits.command_timeout);
+
+    let period = (shortest / 10).clamp(Duration::from_millis(10), Duration::from_secs(1));
+    let mut tick = tokio::time::interval(period);
+
+    loop {
+        tokio::select! {
+            _ = stopped.wait_for(|stopped| *stopped) => break,
+            _ = tick.tick() => registry.expire_commands(),
+        }
+    }
+}
+
+async fn bind(address: &str) -> io::Result<TcpListener> {
+    TcpListener::bind(address)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("{address}: {e}")))
+}
+
+// Accepts connections until `stopped` turns true, then asks the open ones to
+// close and waits for them (bounded by `Limits::shutdown_grace`).
+async fn serve<F, C>(
+    listener: TcpListener,
+    registry: Arc<Registry>,
+    config: Arc<Config>,
+    mut stopped: watch::Receiver<bool>,
+    handle: F,
+) where
+    F: Fn(TcpStream, watch::Receiver<bool>) -> C,
+    C: Future<Output = ()> + Send + 'static,
+{
+    let (closing, _) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let mut acceptor = Acceptor::new(listener);
+
+    loop {
+        tokio::select! {
+            _ = async { stopped.wait_for(|stopped| *stopped).await.is_ok() } => break,
+            (tcp, peer) = acceptor.accept(|e| {
+                registry.report(ServerEvent::Notice(format!(
+                    "Не удалось принять подключение: {e}"
+                )));
+            }) => {
+                if connections.len() >= config.limits.max_connections {
+                    registry.report(ServerEvent::Rejected(format!("{peer}: слишком много подключений")));
+                    continue;
+                }
+
+                connections.spawn(handle(tcp, closing.subscribe()));
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+
+    drop(acceptor);
+    let _ = closing.send(true);
+
+    let _ = tokio::time::timeout(config.limits.shutdown_grace, connections.join_all()).await;
+}
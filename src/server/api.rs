@@ -0,0 +1,273 @@
+use std::{error::Error, fmt::Display, str::FromStr, sync::Arc};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, tcp::OwnedWriteHalf},
+    sync::{broadcast, mpsc, watch},
+};
+
+use crate::{
+    device::{Command, DeviceConfig, DeviceInfo, DeviceKind, SensorData},
+    lines::LineReader,
+    protocol::{CommandReply, Envelope},
+};
+
+use super::{
+    Config, ServerEvent, WebhookDelivery,
+    registry::{PendingCommand, Registry, SequenceStats, Update},
+};
+
+/// Lines the server sends to remote dashboards: a snapshot marker followed by
+/// the current state of every device, then live updates. Dashboards send
+/// commands back as "COMMAND socket on" and configurations as `Config`. A
+/// command tagged with an id, "COMMAND socket on | id=3", is answered with
+/// `Result` once the device confirmed it or it failed.
+#[derive(Debug, Clone)]
+pub enum ApiLine {
+    Snapshot,
+    State(SensorData),
+    Event(String),
+    /// "SEQUENCE socket last=12 gaps=0 out_of_order=1 duplicates=0"
+    Sequence(DeviceKind, SequenceStats),
+    /// "DEVICE kind=socket id=socket-1 firmware=0.1.0 protocol=1 capabilities=switch"
+    Device(DeviceInfo),
+    /// "CONFIG socket interval=10 deadband=0.5 max_power=2000", from the
+    /// server when a configuration changes and from dashboards to change it.
+    Config(DeviceKind, DeviceConfig),
+    /// "PENDING socket off@1700000000000 on@1700000005000", the commands
+    /// waiting for the device; an empty list means the queue was emptied.
+    Pending(DeviceKind, Vec<PendingCommand>),
+    /// "DELIVERY {"url": ..., "event": "socket:on", "delivered": true, ...}",
+    /// a webhook delivery as `GET /webhooks/deliveries` lists it.
+    Delivery(WebhookDelivery),
+    /// "DONE 3 on" or "FAILED 3 <reason>", only to the dashboard that sent
+    /// command 3.
+    Result(CommandReply),
+}
+
+impl Display for ApiLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiLine::Snapshot => write!(f, "SNAPSHOT"),
+            ApiLine::State(data) => write!(f, "STATE {data}"),
+            ApiLine::Event(event) => write!(f, "EVENT {event}"),
+            ApiLine::Sequence(device, stats) => write!(f, "SEQUENCE {device} {stats}"),
+            ApiLine::Device(info) => write!(f, "DEVICE {info}"),
+            ApiLine::Config(device, config) => write!(f, "CONFIG {device} {config}"),
+            ApiLine::Pending(device, pending) => {
+                write!(f, "PENDING {device}")?;
+
+                for command in pending {
+                    write!(f, " {command}")?;
+                }
+
+                Ok(())
+            }
+            ApiLine::Delivery(delivery) => write!(
+                f,
+                "DELIVERY {}",
+                serde_json::to_string(delivery).unwrap_or_default()
+            ),
+            ApiLine::Result(reply) => write!(f, "{reply}"),
+        }
+    }
+}
+
+impl FromStr for ApiLine {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "SNAPSHOT" {
+            return Ok(ApiLine::Snapshot);
+        }
+
+        if let Some(data) = s.strip_prefix("STATE ") {
+            return Ok(ApiLine::State(data.parse()?));
+        }
+
+        if let Some(event) = s.strip_prefix("EVENT ") {
+            return Ok(ApiLine::Event(event.into()));
+        }
+
+        if let Some(sequence) = s.strip_prefix("SEQUENCE ") {
+            let (device, stats) = sequence.split_once(' ').unwrap_or((sequence, ""));
+
+            return Ok(ApiLine::Sequence(device.parse()?, stats.parse()?));
+        }
+
+        if let Some(info) = s.strip_prefix("DEVICE ") {
+            return Ok(ApiLine::Device(info.parse()?));
+        }
+
+        if let Some(config) = s.strip_prefix("CONFIG ") {
+            let (device, config) = config.split_once(' ').ok_or("missing configuration")?;
+
+            return Ok(ApiLine::Config(device.parse()?, config.parse()?));
+        }
+
+        if let Some(pending) = s.strip_prefix("PENDING ") {
+            let mut parts = pending.split_whitespace();
+            let device = parts.next().ok_or("missing device")?.parse()?;
+
+            return Ok(ApiLine::Pending(
+                device,
+                parts.map(str::parse).collect::<Result<_, _>>()?,
+            ));
+        }
+
+        if let Some(delivery) = s.strip_prefix("DELIVERY ") {
+            return Ok(ApiLine::Delivery(serde_json::from_str(delivery)?));
+        }
+
+        if let Ok(reply) = s.parse::<CommandReply>() {
+            return Ok(ApiLine::Result(reply));
+        }
+
+        Err("unknown api line".into())
+    }
+}
+
+pub(crate) async fn handle_client(
+    socket: TcpStream,
+    registry: Arc<Registry>,
+    config: Arc<Config>,
+    mut closing: watch::Receiver<bool>,
+) {
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = LineReader::new(reader, config.limits.max_line_length);
+
+    let (mut updates, snapshot) = registry.subscribe();
+    let (results, mut finished) = mpsc::unbounded_channel();
+
+    if write_snapshot(&mut writer, snapshot, &registry)
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    loop {
+        let line = tokio::select! {
+            _ = closing.changed() => break,
+            update = updates.recv() => match update {
+                Ok(Update::Reading(data)) => ApiLine::State(data),
+                // Dashboards derive these from consecutive states themselves.
+                Ok(Update::Event(ServerEvent::Toggled { .. })) => continue,
+                Ok(Update::Event(event)) => ApiLine::Event(event.to_string()),
+                Ok(Update::Sequence(device, stats)) => ApiLine::Sequence(device, stats),
+                Ok(Update::Device(info)) => ApiLine::Device(info),
+                Ok(Update::Config(device, config)) => ApiLine::Config(device, config),
+                Ok(Update::Pending(device, pending)) => ApiLine::Pending(device, pending),
+                Ok(Update::Delivery(delivery)) => ApiLine::Delivery(delivery),
+                Ok(Update::Sample(_) | Update::Connection(..)) => continue,
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let (fresh, snapshot) = registry.subscribe();
+                    updates = fresh;
+
+                    if write_snapshot(&mut writer, snapshot, &registry).await.is_err() {
+                        break;
+                    }
+
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            Some(reply) = finished.recv() => ApiLine::Result(reply),
+            request = lines.next_line() => {
+                let Ok(Some(request)) = request else {
+                    break;
+                };
+
+                let request = request.trim();
+
+                if let Some(request) = request.strip_prefix("COMMAND ")
+                    && command(&registry, request, &results).is_ok()
+                {
+                    continue;
+                }
+
+                // The parse error isn't `Send`, so it is dropped before the
+                // configuration is saved.
+                match request.parse::<ApiLine>().ok() {
+                    Some(ApiLine::Config(device, config)) => {
+                        registry.configure(device, config).await;
+                        continue;
+                    }
+                    _ => ApiLine::Event(format!("Неизвестный запрос: {request}")),
+                }
+            }
+        };
+
+        if writer
+            .write_all(format!("{line}\n").as_bytes())
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+// Commands without an id are fire-and-forget; the outcome of the others is
+// sent back to this dashboard when it is known.
+fn command(
+    registry: &Registry,
+    request: &str,
+    results: &mpsc::UnboundedSender<CommandReply>,
+) -> Result<(), Box<dyn Error>> {
+    let envelope = request.parse::<Envelope>()?;
+    let command = envelope.payload.parse::<Command>()?;
+
+    let Some(id) = envelope.id else {
+        let _ = registry.send(&command);
+        return Ok(());
+    };
+
+    let outcome = registry.submit(&command);
+    let results = results.clone();
+
+    tokio::spawn(async move {
+        let reply = CommandReply::new(id, outcome.await.map_err(|e| e.to_string()));
+        let _ = results.send(reply);
+    });
+
+    Ok(())
+}
+
+async fn write_snapshot(
+    writer: &mut OwnedWriteHalf,
+    snapshot: Vec<SensorData>,
+    registry: &Registry,
+) -> std::io::Result<()> {
+    let mut lines = format!("{}\n", ApiLine::Snapshot);
+
+    for data in snapshot {
+        lines.push_str(&format!("{}\n", ApiLine::State(data)));
+    }
+
+    for info in registry.devices() {
+        lines.push_str(&format!("{}\n", ApiLine::Device(info)));
+    }
+
+    for (device, config) in registry.configs() {
+        lines.push_str(&format!("{}\n", ApiLine::Config(device, config)));
+    }
+
+    for (device, stats) in registry.sequences() {
+        lines.push_str(&format!("{}\n", ApiLine::Sequence(device, stats)));
+    }
+
+    for delivery in registry.deliveries() {
+        lines.push_str(&format!("{}\n", ApiLine::Delivery(delivery)));
+    }
+
+    for device in DeviceKind::ALL {
+        let pending = registry.pending(device);
+
+        if !pending.is_empty() {
+            lines.push_str(&format!("{}\n", ApiLine::Pending(device, pending)));
+        }
+    }
+
+    writer.write_all(lines.as_bytes()).await
+}
@@ -0,0 +1,27 @@
+use std::error::Error;
+
+use crate::device::{Command, SensorData};
+
+/// Turns lines received from a device into readings and commands into lines
+/// the device understands. The server tries its codecs in order until one of
+/// them accepts the line.
+pub trait Codec: Send + Sync {
+    fn decode(&self, line: &str) -> Result<SensorData, Box<dyn Error>>;
+
+    fn encode(&self, command: &Command) -> String;
+}
+
+/// The project's own text protocol: "Socket 1500W State: on" from devices and
+/// "SET off" to them.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TextCodec;
+
+impl Codec for TextCodec {
+    fn decode(&self, line: &str) -> Result<SensorData, Box<dyn Error>> {
+        line.parse()
+    }
+
+    fn encode(&self, command: &Command) -> String {
+        format!("SET {}", command.state())
+    }
+}
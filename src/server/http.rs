@@ -0,0 +1,570 @@
+use std::{
+    io,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpStream, tcp::OwnedReadHalf},
+    sync::watch,
+    time::timeout,
+};
+
+use crate::{
+    device::{Command, DeviceConfig, DeviceInfo, DeviceKind, SensorData},
+    lines::LineReader,
+    state::DeviceState,
+};
+
+use super::{CommandError, Config, Dispatch, PendingCommand, Registry, Sample, metrics, websocket};
+
+// Header lines a request may have before it is refused.
+const MAX_HEADERS: usize = 64;
+
+// The page served at `/`: the dashboard cards, kept live by the `/ws` feed.
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// A device as `GET /devices` and `GET /devices/{id}` return it. The id is
+/// the one the device introduced itself with, or its kind:
+///
+/// ```json
+/// {
+///   "kind": "socket",
+///   "id": "socket-1",
+///   "connected": true,
+///   "info": {"firmware": "0.1.0", "protocol": 2, "capabilities": ["switch", "power"]},
+///   "reading": {"on": true, "power": 1500.0, "at": 1700000000000},
+///   "config": {"interval": 10, "deadband": 0.5, "max_power": 2000.0},
+///   "pending": [{"state": "off", "expires_at": 1700000060000}]
+/// }
+/// ```
+///
+/// `info`, `reading` and `config` are `null` until the device introduced
+/// itself, reported or was configured.
+///
+/// JSON Schemas for the bodies of the HTTP API are in `schemas/`: this one is
+/// `device.json`, a list of them `devices.json`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceResource {
+    pub kind: String,
+    pub id: String,
+    pub connected: bool,
+    pub info: Option<InfoResource>,
+    pub reading: Option<ReadingResource>,
+    pub config: Option<ConfigResource>,
+    pub pending: Vec<PendingResource>,
+}
+
+/// What the device announced in its HELLO.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InfoResource {
+    pub firmware: String,
+    pub protocol: u32,
+    pub capabilities: Vec<String>,
+}
+
+/// The latest live reading; `at` is in milliseconds since the Unix epoch.
+/// Sockets report `power` in watts, termometers `temperature` in °C.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReadingResource {
+    pub on: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub power: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfigResource {
+    pub interval: u32,
+    pub deadband: f32,
+    pub max_power: Option<f32>,
+}
+
+/// A command waiting for the device to connect.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingResource {
+    pub state: String,
+    pub expires_at: u64,
+}
+
+/// The body of `POST /devices/{id}/commands`: `{"state": "on"}` or
+/// `{"state": "off"}`, as in `schemas/command.json`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandBody {
+    pub state: String,
+}
+
+/// How a command ended: `{"status": "done", "state": "off"}` with 200 once
+/// the device confirmed it, or `{"status": "queued", "state": "off"}` with
+/// 202 if it waits for the device to connect; see
+/// `schemas/command-result.json`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandResource {
+    pub status: String,
+    pub state: String,
+}
+
+/// `GET /health`: `{"status": "ok", "devices": 2, "connected": 1}`, as in
+/// `schemas/health.json`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HealthResource {
+    pub status: String,
+    pub devices: usize,
+    pub connected: usize,
+}
+
+/// Every error response: `{"error": "Устройство socket не подключено"}`, as in
+/// `schemas/error.json`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorResource {
+    pub error: String,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: &impl Serialize) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_string(body).unwrap_or_default(),
+        }
+    }
+
+    fn html(body: &str) -> Self {
+        Self {
+            status: 200,
+            content_type: "text/html; charset=utf-8",
+            body: body.to_string(),
+        }
+    }
+
+    // The Prometheus text exposition format.
+    fn metrics(body: String) -> Self {
+        Self {
+            status: 200,
+            content_type: "text/plain; version=0.0.4; charset=utf-8",
+            body,
+        }
+    }
+
+    fn error(status: u16, error: impl ToString) -> Self {
+        Self::json(
+            status,
+            &ErrorResource {
+                error: error.to_string(),
+            },
+        )
+    }
+}
+
+// One request per connection: the response is sent with "Connection: close".
+// `GET /ws` upgrades the connection to the WebSocket feed instead.
+pub(crate) async fn handle_connection(
+    socket: TcpStream,
+    registry: Arc<Registry>,
+    config: Arc<Config>,
+    mut closing: watch::Receiver<bool>,
+) {
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = LineReader::new(reader, config.limits.max_line_length);
+
+    let reading = read_request(&mut lines, config.limits.max_line_length);
+
+    let request = tokio::select! {
+        _ = closing.changed() => return,
+        request = timeout(config.limits.request_timeout, reading) => request
+            .unwrap_or_else(|_| Err(Response::error(408, "запрос не получен вовремя"))),
+    };
+
+    let response = match request {
+        Ok(Some(request)) if request.path == "/ws" => match upgrade(&request) {
+            Ok(key) => {
+                return websocket::feed(lines, writer, &key, registry, config, closing).await;
+            }
+            Err(response) => response,
+        },
+        Ok(Some(request)) => tokio::select! {
+            _ = closing.changed() => return,
+            response = route(&registry, &config, request) => response,
+        },
+        Ok(None) => return,
+        Err(response) => response,
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+
+    let _ = writer
+        .write_all(format!("{head}{}", response.body).as_bytes())
+        .await;
+    let _ = writer.shutdown().await;
+}
+
+// `None` if the client went away before sending a whole request. Bodies are
+// bounded by `Limits::max_line_length` like device lines.
+async fn read_request(
+    lines: &mut LineReader<OwnedReadHalf>,
+    max_body: usize,
+) -> Result<Option<Request>, Response> {
+    let Ok(Some(request_line)) = lines.next_line().await else {
+        return Ok(None);
+    };
+
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(Response::error(400, "некорректный запрос"));
+    };
+
+    let mut headers = Vec::new();
+    let mut content_length = 0;
+
+    for _ in 0..MAX_HEADERS {
+        let header = match lines.next_line().await {
+            Ok(Some(header)) => header,
+            Ok(None) => return Ok(None),
+            Err(_) => return Err(Response::error(431, "слишком длинный заголовок")),
+        };
+
+        if header.is_empty() {
+            let body = match content_length {
+                0 => Vec::new(),
+                len if len > max_body => {
+                    return Err(Response::error(413, "слишком большое тело запроса"));
+                }
+                len => match lines.read_bytes(len).await {
+                    Ok(body) => body,
+                    Err(_) => return Ok(None),
+                },
+            };
+
+            return Ok(Some(Request {
+                method: method.to_string(),
+                path: target.split('?').next().unwrap_or_default().to_string(),
+                headers,
+                body,
+            }));
+        }
+
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(Response::error(400, "некорректный заголовок"));
+        };
+
+        let (name, value) = (name.trim(), value.trim());
+
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .parse()
+                .map_err(|_| Response::error(400, "некорректный Content-Length"))?;
+        }
+
+        headers.push((name.to_string(), value.to_string()));
+    }
+
+    Err(Response::error(431, "слишком много заголовков"))
+}
+
+// Returns the client's Sec-WebSocket-Key if it asked for a WebSocket.
+fn upgrade(request: &Request) -> Result<String, Response> {
+    if request.method != "GET" {
+        return Err(Response::error(405, "метод не поддерживается"));
+    }
+
+    let websocket = request
+        .header("upgrade")
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+
+    match (websocket, request.header("sec-websocket-key")) {
+        (true, Some(key)) => Ok(key.to_string()),
+        _ => Err(Response::error(426, "нужно подключение WebSocket")),
+    }
+}
+
+async fn route(registry: &Registry, config: &Config, request: Request) -> Response {
+    let segments: Vec<&str> = request
+        .path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    match (request.method.as_str(), &segments[..]) {
+        ("GET", []) => Response::html(DASHBOARD),
+        ("GET", ["health"]) => health(registry, config),
+        ("GET", ["metrics"]) => Response::metrics(metrics::render(registry, config)),
+        ("GET", ["webhooks", "deliveries"]) => Response::json(200, &registry.deliveries()),
+        ("GET", ["devices"]) => {
+            let devices: Vec<_> = config
+                .kinds
+                .iter()
+                .map(|kind| device(registry, *kind))
+                .collect();
+
+            Response::json(200, &devices)
+        }
+        ("GET", ["devices", id]) => match find(registry, config, id) {
+            Some(kind) => Response::json(200, &device(registry, kind)),
+            None => not_found(id),
+        },
+        ("POST", ["devices", id, "commands"]) => match find(registry, config, id) {
+            Some(kind) => command(registry, kind, &request.body).await,
+            None => not_found(id),
+        },
+        (
+            _,
+            []
+            | ["health"]
+            | ["metrics"]
+            | ["webhooks", "deliveries"]
+            | ["devices"]
+            | ["devices", _]
+            | ["devices", _, "commands"],
+        ) => Response::error(405, "метод не поддерживается"),
+        _ => Response::error(404, format!("нет ресурса {}", request.path)),
+    }
+}
+
+fn not_found(id: &str) -> Response {
+    Response::error(404, format!("устройство {id} не найдено"))
+}
+
+// Devices are found by the id they introduced themselves with or by kind.
+pub(super) fn find(registry: &Registry, config: &Config, id: &str) -> Option<DeviceKind> {
+    let kind = registry
+        .devices()
+        .into_iter()
+        .find(|info| info.id == id)
+        .map(|info| info.kind)
+        .or_else(|| id.parse().ok())?;
+
+    config.kinds.contains(&kind).then_some(kind)
+}
+
+fn health(registry: &Registry, config: &Config) -> Response {
+    let connected = config
+        .kinds
+        .iter()
+        .filter(|kind| registry.is_connected(**kind))
+        .count();
+
+    Response::json(
+        200,
+        &HealthResource {
+            status: "ok".into(),
+            devices: config.kinds.len(),
+            connected,
+        },
+    )
+}
+
+pub(super) fn device(registry: &Registry, kind: DeviceKind) -> DeviceResource {
+    let info = registry
+        .devices()
+        .into_iter()
+        .find(|info| info.kind == kind);
+
+    DeviceResource {
+        kind: kind.to_string(),
+        id: info
+            .as_ref()
+            .map_or_else(|| kind.to_string(), |info| info.id.clone()),
+        connected: registry.is_connected(kind),
+        info: info.map(InfoResource::from),
+        reading: registry.latest(kind).map(ReadingResource::from),
+        config: registry.config(kind).map(ConfigResource::from),
+        pending: registry
+            .pending(kind)
+            .into_iter()
+            .map(PendingResource::from)
+            .collect(),
+    }
+}
+
+// A connected device is given `Limits::command_timeout` to confirm the
+// command before the response is sent; for a disconnected one the command is
+// queued and the response doesn't wait.
+async fn command(registry: &Registry, kind: DeviceKind, body: &[u8]) -> Response {
+    let state = match serde_json::from_slice::<CommandBody>(body) {
+        Ok(body) if body.state == "on" || body.state == "off" => body.state,
+        Ok(_) => return Response::error(422, "state должно быть \"on\" или \"off\""),
+        Err(e) => return Response::error(400, format!("некорректное тело запроса: {e}")),
+    };
+
+    let command = Command::new(kind, DeviceState::new(state == "on"));
+
+    if !registry.is_connected(kind) {
+        return match registry.send(&command) {
+            Ok(Dispatch::Queued) => Response::json(
+                202,
+                &CommandResource {
+                    status: "queued".into(),
+                    state,
+                },
+            ),
+            Ok(Dispatch::Sent) => Response::json(
+                202,
+                &CommandResource {
+                    status: "sent".into(),
+                    state,
+                },
+            ),
+            Err(e) => command_error(e),
+        };
+    }
+
+    match registry.submit(&command).await {
+        Ok(state) => Response::json(
+            200,
+            &CommandResource {
+                status: "done".into(),
+                state: state.to_string(),
+            },
+        ),
+        Err(e) => command_error(e),
+    }
+}
+
+fn command_error(error: CommandError) -> Response {
+    let status = match error {
+        CommandError::NotConnected(_) => 409,
+        CommandError::Busy(_) => 503,
+        CommandError::Unsupported(_) => 422,
+        CommandError::Expired(_) | CommandError::TimedOut(_) => 504,
+        CommandError::Failed { .. } => 502,
+    };
+
+    Response::error(status, error)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        422 => "Unprocessable Content",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+// A client for the exporters and webhooks: one POST on a fresh connection,
+// answered with the status code.
+pub(super) async fn post(
+    address: &str,
+    path: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> io::Result<u16> {
+    let mut tcp = TcpStream::connect(address).await?;
+
+    let mut request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {address}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        body.len()
+    );
+
+    for (name, value) in headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    request.push_str("\r\n");
+
+    tcp.write_all(request.as_bytes()).await?;
+    tcp.write_all(body).await?;
+
+    let mut status = String::new();
+    BufReader::new(tcp).read_line(&mut status).await?;
+
+    status
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| io::Error::other("некорректный ответ"))
+}
+
+pub(super) fn millis(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl From<DeviceInfo> for InfoResource {
+    fn from(info: DeviceInfo) -> Self {
+        Self {
+            firmware: info.firmware,
+            protocol: info.protocol,
+            capabilities: info.capabilities.iter().map(ToString::to_string).collect(),
+        }
+    }
+}
+
+impl From<Sample> for ReadingResource {
+    fn from(sample: Sample) -> Self {
+        let (power, temperature) = match &sample.reading {
+            SensorData::SocketIndicator(s) => (Some(s.power().get()), None),
+            SensorData::TermoIndicator(t) => (None, Some(t.temperature().get())),
+        };
+
+        Self {
+            on: sample.reading.is_on(),
+            power,
+            temperature,
+            at: millis(sample.at),
+        }
+    }
+}
+
+impl From<DeviceConfig> for ConfigResource {
+    fn from(config: DeviceConfig) -> Self {
+        Self {
+            interval: config.interval,
+            deadband: config.deadband,
+            max_power: config.max_power,
+        }
+    }
+}
+
+impl From<PendingCommand> for PendingResource {
+    fn from(pending: PendingCommand) -> Self {
+        Self {
+            state: pending.state.to_string(),
+            expires_at: millis(pending.expires_at),
+        }
+    }
+}
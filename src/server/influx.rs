@@ -0,0 +1,376 @@
+use std::{
+    collections::{BTreeSet, VecDeque},
+    error::Error,
+    fmt::Display,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket},
+    sync::{broadcast, watch},
+    time::Instant,
+};
+
+use crate::device::{DeviceKind, SensorData};
+
+use super::{Registry, ServerEvent, Update, http};
+
+/// Where the InfluxDB exporter writes, written as "file:<path>",
+/// "udp://<host:port>", "tcp://<host:port>" or an InfluxDB write endpoint
+/// such as "http://localhost:8086/api/v2/write?org=home&bucket=house".
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum InfluxSink {
+    /// Lines are appended to the file.
+    File(PathBuf),
+    /// One datagram per batch.
+    Udp(String),
+    /// One connection kept open, batches written one after another.
+    Tcp(String),
+    /// Each batch is a POST to `path` on `address`.
+    Http { address: String, path: String },
+}
+
+impl Display for InfluxSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InfluxSink::File(path) => write!(f, "file:{}", path.display()),
+            InfluxSink::Udp(address) => write!(f, "udp://{address}"),
+            InfluxSink::Tcp(address) => write!(f, "tcp://{address}"),
+            InfluxSink::Http { address, path } => write!(f, "http://{address}{path}"),
+        }
+    }
+}
+
+impl FromStr for InfluxSink {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("file:") {
+            return match path.is_empty() {
+                true => Err("file sink needs a path".into()),
+                false => Ok(InfluxSink::File(path.into())),
+            };
+        }
+
+        let (scheme, rest) = s.split_once("://").ok_or("unknown sink")?;
+
+        if rest.is_empty() {
+            return Err("sink needs an address".into());
+        }
+
+        match scheme {
+            "udp" => Ok(InfluxSink::Udp(rest.into())),
+            "tcp" => Ok(InfluxSink::Tcp(rest.into())),
+            "http" => {
+                let (address, path) = match rest.find('/') {
+                    Some(slash) => (&rest[..slash], &rest[slash..]),
+                    None => (rest, "/api/v2/write"),
+                };
+
+                Ok(InfluxSink::Http {
+                    address: address.into(),
+                    path: path.into(),
+                })
+            }
+            _ => Err("unknown sink".into()),
+        }
+    }
+}
+
+/// How the server exports readings for long-term storage. Every reading,
+/// backfilled ones too, becomes one line of InfluxDB line protocol stamped
+/// with the time the device took it, or the time it arrived if the device
+/// didn't say, in nanoseconds:
+///
+/// ```text
+/// devices,device=socket on=true,power=1500 1700000000000000000
+/// devices,device=termometer on=true,temperature=21.5 1700000000000000000
+/// ```
+///
+/// Lines are sent in batches of `batch_size`, or whatever is waiting every
+/// `flush_interval`. While the sink fails the lines wait in a buffer of
+/// `buffer` lines, the oldest dropped first, and writing is retried after
+/// `retry_delay`, doubling up to `max_retry_delay`.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// use otus_iced::server::{DeviceServer, InfluxConfig};
+///
+/// let sink = "http://localhost:8086/api/v2/write?org=home&bucket=house".parse()?;
+/// let influx = InfluxConfig::new(sink);
+///
+/// let (server, events) = DeviceServer::new().influx(influx).start().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InfluxConfig {
+    pub sink: InfluxSink,
+    pub measurement: String,
+    /// Sent as "Authorization: Token ..." to an HTTP sink.
+    pub token: Option<String>,
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+    pub buffer: usize,
+    pub retry_delay: Duration,
+    pub max_retry_delay: Duration,
+    /// How long one write may take before it counts as failed.
+    pub timeout: Duration,
+}
+
+impl InfluxConfig {
+    pub fn new(sink: InfluxSink) -> Self {
+        Self {
+            sink,
+            measurement: "devices".into(),
+            token: None,
+            batch_size: 100,
+            flush_interval: Duration::from_secs(1),
+            buffer: 10_000,
+            retry_delay: Duration::from_secs(1),
+            max_retry_delay: Duration::from_secs(30),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// The reading as one line of line protocol, without a trailing newline.
+    pub fn line(&self, data: &SensorData, at: SystemTime) -> String {
+        let value = match data {
+            SensorData::SocketIndicator(s) => format!("power={}", s.power().get()),
+            SensorData::TermoIndicator(t) => format!("temperature={}", t.temperature().get()),
+        };
+        let at = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+
+        format!(
+            "{},device={} on={},{value} {at}",
+            escape(&self.measurement),
+            data.kind(),
+            data.is_on()
+        )
+    }
+}
+
+// Measurement names must escape commas and spaces.
+fn escape(name: &str) -> String {
+    name.replace(',', "\\,").replace(' ', "\\ ")
+}
+
+// Exports readings of `kinds` until `stopped` turns true, then makes one
+// last attempt to write what is still buffered or on its way. `updates` is
+// subscribed to when the server starts, so no reading published after that
+// is missed. A failing sink is reported once, not on every retry, and so is
+// a full buffer.
+pub(crate) async fn export(
+    registry: Arc<Registry>,
+    mut updates: broadcast::Receiver<Update>,
+    kinds: BTreeSet<DeviceKind>,
+    influx: InfluxConfig,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let mut exporter = Exporter::new(&registry, &influx);
+
+    let mut flush = tokio::time::interval(influx.flush_interval.max(Duration::from_millis(1)));
+    flush.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = async { stopped.wait_for(|stopped| *stopped).await.is_ok() } => break,
+            update = updates.recv() => match update {
+                Ok(Update::Sample(sample)) if kinds.contains(&sample.reading.kind()) => {
+                    exporter.push(influx.line(&sample.reading, sample.at));
+
+                    if exporter.buffer.len() >= influx.batch_size {
+                        exporter.flush().await;
+                    }
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    registry.report(ServerEvent::Notice(format!(
+                        "InfluxDB: пропущено обновлений: {missed}"
+                    )));
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = flush.tick() => exporter.flush().await,
+        }
+    }
+
+    while let Ok(update) = updates.try_recv() {
+        if let Update::Sample(sample) = update
+            && kinds.contains(&sample.reading.kind())
+        {
+            exporter.push(influx.line(&sample.reading, sample.at));
+        }
+    }
+
+    exporter.retry_at = None;
+    exporter.flush().await;
+}
+
+struct Exporter<'a> {
+    registry: &'a Registry,
+    influx: &'a InfluxConfig,
+    buffer: VecDeque<String>,
+    // Kept open between batches for UDP and TCP sinks.
+    udp: Option<UdpSocket>,
+    tcp: Option<TcpStream>,
+    retry_at: Option<Instant>,
+    retry_delay: Duration,
+    last_failure: Option<String>,
+    overflowed: bool,
+}
+
+impl<'a> Exporter<'a> {
+    fn new(registry: &'a Registry, influx: &'a InfluxConfig) -> Self {
+        Self {
+            registry,
+            influx,
+            buffer: VecDeque::new(),
+            udp: None,
+            tcp: None,
+            retry_at: None,
+            retry_delay: influx.retry_delay,
+            last_failure: None,
+            overflowed: false,
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        if self.buffer.len() >= self.influx.buffer.max(1) {
+            self.buffer.pop_front();
+
+            if !self.overflowed {
+                self.registry.report(ServerEvent::Notice(
+                    "InfluxDB: буфер заполнен, старые показания отбрасываются".into(),
+                ));
+            }
+            self.overflowed = true;
+        }
+
+        self.buffer.push_back(line);
+    }
+
+    // Writes batches until the buffer is empty or the sink fails; waits
+    // for the retry delay to pass before trying a failed sink again.
+    async fn flush(&mut self) {
+        if self.retry_at.is_some_and(|at| Instant::now() < at) {
+            return;
+        }
+
+        while !self.buffer.is_empty() {
+            let count = self.buffer.len().min(self.influx.batch_size.max(1));
+            let mut batch = String::new();
+
+            for line in self.buffer.iter().take(count) {
+                batch.push_str(line);
+                batch.push('\n');
+            }
+
+            let written = tokio::time::timeout(self.influx.timeout, self.write(batch.as_bytes()))
+                .await
+                .unwrap_or_else(|_| Err(io::Error::other("нет ответа")));
+
+            if let Err(e) = written {
+                self.fail(e);
+                return;
+            }
+
+            self.buffer.drain(..count);
+        }
+
+        self.retry_at = None;
+        self.retry_delay = self.influx.retry_delay;
+        self.overflowed = false;
+
+        if self.last_failure.take().is_some() {
+            self.registry.report(ServerEvent::Notice(format!(
+                "InfluxDB: запись в {} восстановлена",
+                self.influx.sink
+            )));
+        }
+    }
+
+    async fn write(&mut self, batch: &[u8]) -> io::Result<()> {
+        match &self.influx.sink {
+            InfluxSink::File(path) => {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+
+                file.write_all(batch).await?;
+                file.flush().await
+            }
+            InfluxSink::Udp(address) => {
+                let udp =
+                    match self.udp.take() {
+                        Some(udp) => udp,
+                        None => {
+                            let target =
+                                tokio::net::lookup_host(address).await?.next().ok_or_else(
+                                    || io::Error::other(format!("{address}: адрес не найден")),
+                                )?;
+
+                            // The local end must be of the same family as the sink.
+                            let local: SocketAddr = match target {
+                                SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                                SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                            };
+
+                            let udp = UdpSocket::bind(local).await?;
+                            udp.connect(target).await?;
+                            udp
+                        }
+                    };
+
+                self.udp.insert(udp).send(batch).await.map(|_| ())
+            }
+            InfluxSink::Tcp(address) => {
+                let tcp = match self.tcp.take() {
+                    Some(tcp) => tcp,
+                    None => TcpStream::connect(address).await?,
+                };
+
+                self.tcp.insert(tcp).write_all(batch).await
+            }
+            // InfluxDB answers 204 on success.
+            InfluxSink::Http { address, path } => {
+                let mut headers = vec![("Content-Type", "text/plain; charset=utf-8".to_string())];
+
+                if let Some(token) = &self.influx.token {
+                    headers.push(("Authorization", format!("Token {token}")));
+                }
+
+                match http::post(address, path, &headers, batch).await? {
+                    200..=299 => Ok(()),
+                    code => Err(io::Error::other(format!("ответ {code}"))),
+                }
+            }
+        }
+    }
+
+    fn fail(&mut self, e: io::Error) {
+        let failure = e.to_string();
+
+        if self.last_failure.as_ref() != Some(&failure) {
+            self.registry.report(ServerEvent::Notice(format!(
+                "InfluxDB: не удалось записать в {}: {failure}",
+                self.influx.sink
+            )));
+        }
+        self.last_failure = Some(failure);
+
+        self.udp = None;
+        self.tcp = None;
+        self.retry_at = Some(Instant::now() + self.retry_delay);
+        self.retry_delay = (self.retry_delay * 2).min(self.influx.max_retry_delay);
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    fmt::{Display, Write},
+    time::SystemTime,
+};
+
+use crate::device::{DeviceKind, SensorData};
+
+use super::{Config, Registry, Sample};
+
+// The devices and the server's counters in the Prometheus text format, as
+// `GET /metrics` serves them. Devices are labeled by kind so the series stay
+// the same when a device introduces itself under a new id.
+pub(super) fn render(registry: &Registry, config: &Config) -> String {
+    let now = SystemTime::now();
+    let samples: Vec<(DeviceKind, Sample)> = config
+        .kinds
+        .iter()
+        .filter_map(|kind| registry.latest(*kind).map(|sample| (*kind, sample)))
+        .collect();
+
+    let mut metrics = Metrics::default();
+
+    metrics.family(
+        "otus_device_connected",
+        "gauge",
+        "1 while the device is connected",
+        "device",
+        config
+            .kinds
+            .iter()
+            .map(|kind| (kind, u8::from(registry.is_connected(*kind)))),
+    );
+    metrics.family(
+        "otus_device_on",
+        "gauge",
+        "1 if the latest reading says the device is on",
+        "device",
+        samples
+            .iter()
+            .map(|(kind, sample)| (kind, u8::from(sample.reading.is_on()))),
+    );
+    metrics.family(
+        "otus_device_power_watts",
+        "gauge",
+        "Power the socket reported last",
+        "device",
+        samples
+            .iter()
+            .filter_map(|(kind, sample)| match &sample.reading {
+                SensorData::SocketIndicator(s) => Some((kind, s.power().get())),
+                SensorData::TermoIndicator(_) => None,
+            }),
+    );
+    metrics.family(
+        "otus_device_temperature_celsius",
+        "gauge",
+        "Temperature the termometer reported last",
+        "device",
+        samples
+            .iter()
+            .filter_map(|(kind, sample)| match &sample.reading {
+                SensorData::TermoIndicator(t) => Some((kind, t.temperature().get())),
+                SensorData::SocketIndicator(_) => None,
+            }),
+    );
+    metrics.family(
+        "otus_device_last_seen_seconds",
+        "gauge",
+        "Seconds since the server last received a reading from the device",
+        "device",
+        config.kinds.iter().filter_map(|kind| {
+            let age = now.duration_since(registry.last_seen(*kind)?);
+            Some((kind, age.unwrap_or_default().as_secs_f64()))
+        }),
+    );
+
+    let counters = registry.counters();
+
+    metrics.single(
+        "otus_connections_accepted_total",
+        "counter",
+        "Device connections accepted",
+        counters.connections,
+    );
+    metrics.family(
+        "otus_messages_parsed_total",
+        "counter",
+        "Lines from devices parsed, by message type",
+        "type",
+        [
+            ("reading", counters.readings),
+            ("hello", counters.hellos),
+            ("reply", counters.replies),
+        ],
+    );
+    metrics.single(
+        "otus_parse_failures_total",
+        "counter",
+        "Lines from devices that couldn't be parsed",
+        counters.parse_failures,
+    );
+    metrics.single(
+        "otus_events_dropped_total",
+        "counter",
+        "Events dropped because the event queue was full",
+        registry.stats().dropped,
+    );
+
+    metrics.0
+}
+
+#[derive(Default)]
+struct Metrics(String);
+
+impl Metrics {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {name} {help}");
+        let _ = writeln!(self.0, "# TYPE {name} {kind}");
+    }
+
+    fn single(&mut self, name: &str, kind: &str, help: &str, value: impl Display) {
+        self.header(name, kind, help);
+        let _ = writeln!(self.0, "{name} {value}");
+    }
+
+    fn family<L: Display, V: Display>(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        label: &str,
+        values: impl IntoIterator<Item = (L, V)>,
+    ) {
+        self.header(name, kind, help);
+
+        for (key, value) in values {
+            let _ = writeln!(self.0, "{name}{{{label}=\"{key}\"}} {value}");
+        }
+    }
+}
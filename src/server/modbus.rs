@@ -0,0 +1,333 @@
+use std::{
+    collections::BTreeSet, error::Error, fmt::Display, io, str::FromStr, sync::Arc, time::Duration,
+};
+
+use tokio::sync::{mpsc, watch};
+
+use crate::{
+    device::{Capability, DeviceInfo, DeviceKind, SensorData},
+    modbus::ModbusClient,
+    power::Power,
+    protocol::CommandReply,
+    socket::Socket,
+    state::DeviceState,
+    temperature::Temperature,
+    termometer::Termometer,
+};
+
+use super::{
+    ServerEvent,
+    poll::{Reconnect, taken, within},
+    registry::{Outbound, Registry},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegisterTable {
+    Holding,
+    Input,
+}
+
+/// The register holding a device's power or temperature, written as
+/// "holding:0" or "input:3*0.1". The register is read as a signed 16-bit
+/// number and multiplied by the scale, 1 unless given.
+#[derive(Debug, Clone, Copy)]
+pub struct ModbusRegister {
+    pub table: RegisterTable,
+    pub address: u16,
+    pub scale: f32,
+}
+
+impl ModbusRegister {
+    pub fn holding(address: u16) -> Self {
+        Self {
+            table: RegisterTable::Holding,
+            address,
+            scale: 1.0,
+        }
+    }
+
+    async fn read(&self, client: &mut ModbusClient) -> io::Result<f32> {
+        let registers = match self.table {
+            RegisterTable::Holding => client.read_holding(self.address, 1).await?,
+            RegisterTable::Input => client.read_input(self.address, 1).await?,
+        };
+
+        Ok(registers[0] as i16 as f32 * self.scale)
+    }
+}
+
+// The scale is compared by its bits, so the register can be hashed along
+// with the rest of the config.
+impl PartialEq for ModbusRegister {
+    fn eq(&self, other: &Self) -> bool {
+        (self.table, self.address, self.scale.to_bits())
+            == (other.table, other.address, other.scale.to_bits())
+    }
+}
+
+impl Eq for ModbusRegister {}
+
+impl std::hash::Hash for ModbusRegister {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        (self.table, self.address, self.scale.to_bits()).hash(state);
+    }
+}
+
+impl Display for ModbusRegister {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let table = match self.table {
+            RegisterTable::Holding => "holding",
+            RegisterTable::Input => "input",
+        };
+
+        write!(f, "{table}:{}", self.address)?;
+
+        if self.scale != 1.0 {
+            write!(f, "*{}", self.scale)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ModbusRegister {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (table, rest) = s.split_once(':').ok_or("unknown register")?;
+
+        let table = match table {
+            "holding" => RegisterTable::Holding,
+            "input" => RegisterTable::Input,
+            _ => return Err("unknown register table".into()),
+        };
+
+        let (address, scale) = match rest.split_once('*') {
+            Some((address, scale)) => (address, scale.parse()?),
+            None => (rest, 1.0),
+        };
+
+        Ok(Self {
+            table,
+            address: address.parse()?,
+            scale,
+        })
+    }
+}
+
+/// A Modbus TCP device the server polls in place of one that connects by
+/// itself. Every `interval` the `register` becomes the power of a socket or
+/// the temperature of a termometer, and the `coil`, if there is one, its
+/// on/off state; commands for the device write the coil. A device without a
+/// coil is always on and takes no commands.
+///
+/// While the device answers it counts as connected. When it stops the
+/// server tries again every `reconnect_delay`.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// use otus_iced::{
+///     device::DeviceKind,
+///     server::{DeviceServer, ModbusConfig},
+/// };
+///
+/// let mut meter = ModbusConfig::new(DeviceKind::Socket, "192.168.1.20:502");
+/// meter.register = "input:12*0.1".parse()?;
+/// meter.coil = Some(0);
+///
+/// let (server, events) = DeviceServer::new().modbus(meter).start().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ModbusConfig {
+    pub device: DeviceKind,
+    pub address: String,
+    pub unit: u8,
+    pub register: ModbusRegister,
+    pub coil: Option<u16>,
+    pub interval: Duration,
+    /// How long one request may take before the device counts as lost.
+    pub timeout: Duration,
+    pub reconnect_delay: Duration,
+}
+
+impl ModbusConfig {
+    pub fn new(device: DeviceKind, address: impl Into<String>) -> Self {
+        Self {
+            device,
+            address: address.into(),
+            unit: 1,
+            register: ModbusRegister::holding(0),
+            coil: None,
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            reconnect_delay: Duration::from_secs(5),
+        }
+    }
+
+    fn info(&self) -> DeviceInfo {
+        let reports = match self.device {
+            DeviceKind::Socket => Capability::ReportsPower,
+            DeviceKind::Termometer => Capability::ReportsTemperature,
+        };
+
+        let mut capabilities = vec![reports];
+
+        if self.coil.is_some() {
+            capabilities.insert(0, Capability::Switch);
+        }
+
+        DeviceInfo {
+            kind: self.device,
+            id: format!("modbus-{}/{}", self.address, self.unit),
+            firmware: "modbus".into(),
+            protocol: 1,
+            capabilities,
+        }
+    }
+
+    fn reading(&self, value: f32, on: bool) -> SensorData {
+        let state = DeviceState::new(on);
+
+        match self.device {
+            DeviceKind::Socket => {
+                SensorData::SocketIndicator(Socket::new(Power::new(value), state))
+            }
+            DeviceKind::Termometer => {
+                SensorData::TermoIndicator(Termometer::new(Temperature::new(value), state))
+            }
+        }
+    }
+}
+
+// Polls a Modbus device until `stopped` turns true.
+pub(crate) async fn poll(
+    registry: Arc<Registry>,
+    kinds: BTreeSet<DeviceKind>,
+    modbus: ModbusConfig,
+    command_buffer: usize,
+    mut stopped: watch::Receiver<bool>,
+) {
+    if !kinds.contains(&modbus.device) {
+        registry.report(ServerEvent::Notice(format!(
+            "Modbus {}: устройства {} не поддерживаются",
+            modbus.address, modbus.device
+        )));
+        return;
+    }
+
+    let source = format!("Modbus {}", modbus.address);
+    let mut reconnect = Reconnect::new(&registry, source, modbus.reconnect_delay);
+
+    while let Err(e) = connected(
+        &registry,
+        &modbus,
+        command_buffer,
+        &mut stopped,
+        &mut reconnect.last_failure,
+    )
+    .await
+    {
+        if !reconnect.retry(e, &mut stopped).await {
+            return;
+        }
+    }
+}
+
+// One connection: the device is attached like a session of its own while
+// it answers.
+async fn connected(
+    registry: &Registry,
+    modbus: &ModbusConfig,
+    command_buffer: usize,
+    stopped: &mut watch::Receiver<bool>,
+    last_failure: &mut Option<String>,
+) -> io::Result<()> {
+    let mut client = within(
+        modbus.timeout,
+        ModbusClient::connect(&modbus.address, modbus.unit),
+    )
+    .await?;
+    let data = read(&mut client, modbus).await?;
+
+    let (session, mut outbound) = mpsc::channel::<Outbound>(command_buffer.max(1));
+
+    if !registry.attach_exclusive(modbus.device, session.clone()) {
+        return Err(taken(modbus.device));
+    }
+
+    let info = modbus.info();
+    registry.report(ServerEvent::Notice(format!(
+        "Подключено устройство {} ({}) по Modbus",
+        info.id, info.kind
+    )));
+    registry.register(info);
+    *last_failure = None;
+
+    registry.publish(data);
+
+    let mut ticks = tokio::time::interval(modbus.interval.max(Duration::from_millis(1)));
+    ticks.tick().await;
+
+    let result = loop {
+        tokio::select! {
+            _ = async { stopped.wait_for(|stopped| *stopped).await.is_ok() } => break Ok(()),
+            _ = ticks.tick() => match read(&mut client, modbus).await {
+                Ok(data) => registry.publish(data),
+                Err(e) => break Err(e),
+            },
+            Some(outbound) = outbound.recv() => {
+                let Outbound::Command { id, command } = outbound else {
+                    continue;
+                };
+
+                let Some(coil) = modbus.coil else {
+                    let reason = "устройство не переключается".to_string();
+                    registry.complete(modbus.device, CommandReply::new(id, Err(reason)));
+                    continue;
+                };
+
+                let switch = client.write_coil(coil, command.state().get());
+                let switched = within(modbus.timeout, switch).await;
+
+                match switched {
+                    Ok(()) => {}
+                    Err(e) => {
+                        registry.complete(modbus.device, CommandReply::new(id, Err(e.to_string())));
+                        break Err(e);
+                    }
+                }
+
+                // The coil is read back, so the reply tells what the device
+                // actually did.
+                match read(&mut client, modbus).await {
+                    Ok(data) => {
+                        let state = DeviceState::new(data.is_on());
+                        registry.publish(data);
+                        registry.complete(modbus.device, CommandReply::new(id, Ok(state)));
+                    }
+                    Err(e) => {
+                        registry.complete(modbus.device, CommandReply::new(id, Err(e.to_string())));
+                        break Err(e);
+                    }
+                }
+            }
+        }
+    };
+
+    registry.detach(modbus.device, &session);
+    result
+}
+
+async fn read(client: &mut ModbusClient, modbus: &ModbusConfig) -> io::Result<SensorData> {
+    within(modbus.timeout, async {
+        let value = modbus.register.read(client).await?;
+        let on = match modbus.coil {
+            Some(coil) => client.read_coils(coil, 1).await?[0],
+            None => true,
+        };
+
+        Ok(modbus.reading(value, on))
+    })
+    .await
+}
@@ -0,0 +1,711 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    error::Error,
+    fmt::Display,
+    io,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::{broadcast, watch},
+};
+
+use serde_json::json;
+
+use crate::{
+    device::{Command, DeviceInfo, DeviceKind, SensorData},
+    state::DeviceState,
+};
+
+use super::{Registry, ServerEvent, Update};
+
+// Devices without a room of their own are published under this one.
+const DEFAULT_ROOM: &str = "default";
+
+// Larger packets from the broker end the session.
+const MAX_PACKET: usize = 64 * 1024;
+
+// QoS 1 publishes the broker hasn't acknowledged yet, kept to send again
+// after a reconnect. When more are waiting the oldest is given up; a newer
+// reading replaces it anyway.
+const MAX_IN_FLIGHT: usize = 64;
+
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const DUP: u8 = 0x08;
+const PINGREQ: [u8; 2] = [0xC0, 0];
+const DISCONNECT: [u8; 2] = [0xE0, 0];
+
+/// MQTT delivery guarantee for what the bridge publishes and subscribes to.
+/// Exactly-once delivery is not supported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum QoS {
+    #[default]
+    AtMostOnce,
+    AtLeastOnce,
+}
+
+impl QoS {
+    fn level(self) -> u8 {
+        match self {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce => 1,
+        }
+    }
+}
+
+impl Display for QoS {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.level())
+    }
+}
+
+impl FromStr for QoS {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(QoS::AtMostOnce),
+            "1" => Ok(QoS::AtLeastOnce),
+            _ => Err("QoS must be 0 or 1".into()),
+        }
+    }
+}
+
+/// How the server mirrors its devices to an MQTT broker. Every reading is
+/// published to "<prefix>/<room>/<device>/state" ("on" or "off") and to
+/// ".../power" for sockets or ".../temperature" for termometers; "on" or
+/// "off" published to ".../set" switches the device. The bridge itself is
+/// "online" or "offline" at "<prefix>/server/status".
+///
+/// With `discovery` set, Home Assistant discovery configs are published too:
+/// a temperature sensor in °C for the termometer, a switch and a power
+/// sensor in W for the socket.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use otus_iced::{
+///     device::DeviceKind,
+///     server::{DeviceServer, MqttConfig},
+/// };
+///
+/// let mut mqtt = MqttConfig::new("localhost:1883");
+/// mqtt.rooms.insert(DeviceKind::Socket, "kitchen".into());
+///
+/// let (server, events) = DeviceServer::new().mqtt(mqtt).start().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MqttConfig {
+    /// "host:port" of the broker.
+    pub broker: String,
+    pub client_id: String,
+    pub prefix: String,
+    pub rooms: BTreeMap<DeviceKind, String>,
+    /// With `QoS::AtLeastOnce` a publish is sent again after a reconnect
+    /// until the broker acknowledges it.
+    pub qos: QoS,
+    /// Publish readings retained, so new subscribers get the last one at once.
+    pub retain: bool,
+    /// How often the broker is pinged; zero turns keep-alive off.
+    pub keep_alive: Duration,
+    /// How long the broker may take to accept the connection.
+    pub connect_timeout: Duration,
+    /// How long to wait before connecting again after the broker was lost.
+    pub reconnect_delay: Duration,
+    /// Home Assistant's discovery prefix, usually "homeassistant"; `None`
+    /// publishes no discovery configs.
+    pub discovery: Option<String>,
+}
+
+impl MqttConfig {
+    pub fn new(broker: impl Into<String>) -> Self {
+        Self {
+            broker: broker.into(),
+            client_id: "otus-iced".into(),
+            prefix: "home".into(),
+            rooms: BTreeMap::new(),
+            qos: QoS::default(),
+            retain: true,
+            keep_alive: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(10),
+            reconnect_delay: Duration::from_secs(2),
+            discovery: None,
+        }
+    }
+
+    /// The topic `leaf` of a device, e.g. "home/kitchen/socket/power".
+    pub fn topic(&self, device: DeviceKind, leaf: &str) -> String {
+        let room = self.rooms.get(&device).map_or(DEFAULT_ROOM, String::as_str);
+
+        format!("{}/{room}/{device}/{leaf}", self.prefix)
+    }
+
+    fn status_topic(&self) -> String {
+        format!("{}/server/status", self.prefix)
+    }
+
+    // Home Assistant entities of a device as discovery topics and configs.
+    // The firmware is filled in once the device introduced itself.
+    fn discovery(&self, device: DeviceKind, info: Option<&DeviceInfo>) -> Vec<(String, String)> {
+        let Some(discovery) = &self.discovery else {
+            return Vec::new();
+        };
+
+        let node = &self.client_id;
+        let (name, model) = match device {
+            DeviceKind::Socket => ("Розетка", "socket"),
+            DeviceKind::Termometer => ("Термометр", "termometer"),
+        };
+
+        let mut ha_device = json!({
+            "identifiers": [format!("{node}_{device}")],
+            "name": name,
+            "model": model,
+            "manufacturer": "otus-iced",
+        });
+
+        if let Some(info) = info {
+            ha_device["sw_version"] = json!(info.firmware);
+        }
+
+        let entity = |object: &str, name: &str| {
+            json!({
+                "name": name,
+                "unique_id": format!("{node}_{object}"),
+                "availability_topic": self.status_topic(),
+                "device": ha_device,
+            })
+        };
+
+        let mut configs = Vec::new();
+
+        match device {
+            DeviceKind::Socket => {
+                let mut switch = entity("socket", "Питание");
+                switch["state_topic"] = json!(self.topic(device, "state"));
+                switch["command_topic"] = json!(self.topic(device, "set"));
+                switch["payload_on"] = json!("on");
+                switch["payload_off"] = json!("off");
+                switch["state_on"] = json!("on");
+                switch["state_off"] = json!("off");
+                switch["device_class"] = json!("outlet");
+                configs.push((format!("{discovery}/switch/{node}/socket/config"), switch));
+
+                let mut power = entity("socket_power", "Мощность");
+                power["state_topic"] = json!(self.topic(device, "power"));
+                power["device_class"] = json!("power");
+                power["unit_of_measurement"] = json!("W");
+                power["state_class"] = json!("measurement");
+                configs.push((
+                    format!("{discovery}/sensor/{node}/socket_power/config"),
+                    power,
+                ));
+            }
+            DeviceKind::Termometer => {
+                let mut temperature = entity("termometer_temperature", "Температура");
+                temperature["state_topic"] = json!(self.topic(device, "temperature"));
+                temperature["device_class"] = json!("temperature");
+                temperature["unit_of_measurement"] = json!("°C");
+                temperature["state_class"] = json!("measurement");
+                configs.push((
+                    format!("{discovery}/sensor/{node}/termometer_temperature/config"),
+                    temperature,
+                ));
+            }
+        }
+
+        configs
+            .into_iter()
+            .map(|(topic, config)| (topic, config.to_string()))
+            .collect()
+    }
+}
+
+// Keeps the server connected to the broker until `stopped` turns true,
+// reconnecting after `MqttConfig::reconnect_delay`. A lost connection is
+// reported once, not on every failed attempt.
+pub(crate) async fn bridge(
+    registry: Arc<Registry>,
+    kinds: BTreeSet<DeviceKind>,
+    mqtt: MqttConfig,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let mut last_failure = None;
+    let mut publisher = Publisher::new(&mqtt);
+
+    loop {
+        let connection = session(&registry, &kinds, &mqtt, &mut publisher, &mut stopped);
+
+        let failure = match connection.await {
+            Ok(()) => return,
+            Err(e) => e.to_string(),
+        };
+
+        if last_failure.as_ref() != Some(&failure) {
+            registry.report(ServerEvent::Notice(format!(
+                "MQTT: нет связи с {}: {failure}",
+                mqtt.broker
+            )));
+        }
+        last_failure = Some(failure);
+
+        tokio::select! {
+            _ = stopped.wait_for(|stopped| *stopped) => return,
+            _ = tokio::time::sleep(mqtt.reconnect_delay) => {}
+        }
+    }
+}
+
+// One connection to the broker. Returns `Ok` once the server stops.
+// Publishes the previous connection left unacknowledged are sent again.
+async fn session(
+    registry: &Registry,
+    kinds: &BTreeSet<DeviceKind>,
+    mqtt: &MqttConfig,
+    publisher: &mut Publisher<'_>,
+    stopped: &mut watch::Receiver<bool>,
+) -> io::Result<()> {
+    let connected = tokio::time::timeout(mqtt.connect_timeout, async {
+        let tcp = TcpStream::connect(&mqtt.broker).await?;
+        let (reader, mut writer) = tcp.into_split();
+        let mut packets = PacketReader::new(reader);
+
+        writer.write_all(&connect(mqtt)).await?;
+        let connack = packets.next().await?;
+
+        io::Result::Ok((packets, writer, connack))
+    });
+
+    let (mut packets, mut writer, connack) = connected
+        .await
+        .map_err(|_| io::Error::other("брокер не ответил на подключение"))??;
+
+    match connack {
+        Some(Packet::ConnAck { code: 0 }) => {}
+        Some(Packet::ConnAck { code }) => {
+            return Err(io::Error::other(format!("брокер отказал, код {code}")));
+        }
+        _ => return Err(io::Error::other("брокер не подтвердил подключение")),
+    }
+
+    registry.report(ServerEvent::Notice(format!(
+        "MQTT: подключено к {}",
+        mqtt.broker
+    )));
+
+    let set_topics: Vec<String> = kinds.iter().map(|kind| mqtt.topic(*kind, "set")).collect();
+
+    writer
+        .write_all(&subscribe(publisher.next_id(), &set_topics, mqtt.qos))
+        .await?;
+    publisher.resend(&mut writer).await?;
+    publisher
+        .send(&mut writer, &mqtt.status_topic(), "online", true)
+        .await?;
+
+    let (mut updates, snapshot) = registry.subscribe();
+    let devices = registry.devices();
+
+    for kind in kinds {
+        let info = devices.iter().find(|info| info.kind == *kind);
+
+        for (topic, config) in mqtt.discovery(*kind, info) {
+            publisher.send(&mut writer, &topic, &config, true).await?;
+        }
+    }
+
+    for data in snapshot {
+        publisher.reading(&mut writer, &data).await?;
+    }
+
+    let keep_alive = !mqtt.keep_alive.is_zero();
+    let mut ping = tokio::time::interval(mqtt.keep_alive.max(Duration::from_millis(1)));
+    ping.tick().await;
+    let mut answered = true;
+
+    loop {
+        tokio::select! {
+            // The guard `wait_for` returns must not be held across the awaits
+            // in the other branches.
+            _ = async { stopped.wait_for(|stopped| *stopped).await.is_ok() } => break,
+            update = updates.recv() => match update {
+                Ok(Update::Reading(data)) => publisher.reading(&mut writer, &data).await?,
+                Ok(Update::Device(info)) if kinds.contains(&info.kind) => {
+                    for (topic, config) in mqtt.discovery(info.kind, Some(&info)) {
+                        publisher.send(&mut writer, &topic, &config, true).await?;
+                    }
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let (fresh, snapshot) = registry.subscribe();
+                    updates = fresh;
+
+                    for data in snapshot {
+                        publisher.reading(&mut writer, &data).await?;
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            packet = packets.next() => {
+                answered = true;
+
+                match packet? {
+                    Some(Packet::Publish { topic, payload, id }) => {
+                        if let Some(id) = id {
+                            writer.write_all(&puback(id)).await?;
+                        }
+
+                        command(registry, kinds, mqtt, &topic, &payload);
+                    }
+                    Some(Packet::PubAck { id }) => publisher.acknowledged(id),
+                    Some(_) => {}
+                    None => return Err(io::Error::other("брокер закрыл соединение")),
+                }
+            }
+            _ = ping.tick(), if keep_alive => {
+                if !answered {
+                    return Err(io::Error::other("брокер не отвечает"));
+                }
+
+                answered = false;
+                writer.write_all(&PINGREQ).await?;
+            }
+        }
+    }
+
+    publisher
+        .send(&mut writer, &mqtt.status_topic(), "offline", true)
+        .await?;
+    writer.write_all(&DISCONNECT).await
+}
+
+// "on" or "off" published to a device's set topic.
+fn command(
+    registry: &Registry,
+    kinds: &BTreeSet<DeviceKind>,
+    mqtt: &MqttConfig,
+    topic: &str,
+    payload: &[u8],
+) {
+    let Some(kind) = kinds.iter().find(|kind| mqtt.topic(**kind, "set") == topic) else {
+        return;
+    };
+
+    let payload = String::from_utf8_lossy(payload);
+
+    match payload.trim().to_lowercase().parse::<DeviceState>() {
+        Ok(state) => {
+            let _ = registry.send(&Command::new(*kind, state));
+        }
+        Err(_) => registry.report(ServerEvent::Rejected(format!("MQTT {topic}: {payload}"))),
+    }
+}
+
+// Publishes with the configured QoS and numbers packets that need it. Lives
+// as long as the bridge, so QoS 1 publishes outlast a lost connection.
+struct Publisher<'a> {
+    mqtt: &'a MqttConfig,
+    last_id: u16,
+    in_flight: VecDeque<(u16, Vec<u8>)>,
+}
+
+impl<'a> Publisher<'a> {
+    fn new(mqtt: &'a MqttConfig) -> Self {
+        Self {
+            mqtt,
+            last_id: 0,
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    // Skips ids still waiting for their PUBACK.
+    fn next_id(&mut self) -> u16 {
+        loop {
+            self.last_id = self.last_id.checked_add(1).unwrap_or(1);
+
+            if !self.in_flight.iter().any(|(id, _)| *id == self.last_id) {
+                return self.last_id;
+            }
+        }
+    }
+
+    fn acknowledged(&mut self, id: u16) {
+        self.in_flight.retain(|(waiting, _)| *waiting != id);
+    }
+
+    async fn resend(&mut self, writer: &mut OwnedWriteHalf) -> io::Result<()> {
+        for (_, packet) in &self.in_flight {
+            let mut packet = packet.clone();
+            packet[0] |= DUP;
+
+            writer.write_all(&packet).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send(
+        &mut self,
+        writer: &mut OwnedWriteHalf,
+        topic: &str,
+        payload: &str,
+        retain: bool,
+    ) -> io::Result<()> {
+        let id = match self.mqtt.qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce => Some(self.next_id()),
+        };
+
+        let packet = publish(topic, payload.as_bytes(), self.mqtt.qos, retain, id);
+
+        if let Some(id) = id {
+            if self.in_flight.len() == MAX_IN_FLIGHT {
+                self.in_flight.pop_front();
+            }
+            self.in_flight.push_back((id, packet.clone()));
+        }
+
+        writer.write_all(&packet).await
+    }
+
+    async fn reading(&mut self, writer: &mut OwnedWriteHalf, data: &SensorData) -> io::Result<()> {
+        let device = data.kind();
+        let retain = self.mqtt.retain;
+        let state = DeviceState::new(data.is_on()).to_string();
+
+        let (leaf, value) = match data {
+            SensorData::SocketIndicator(s) => ("power", s.power().get()),
+            SensorData::TermoIndicator(t) => ("temperature", t.temperature().get()),
+        };
+
+        let state_topic = self.mqtt.topic(device, "state");
+        let value_topic = self.mqtt.topic(device, leaf);
+
+        self.send(writer, &state_topic, &state, retain).await?;
+        self.send(writer, &value_topic, &value.to_string(), retain)
+            .await
+    }
+}
+
+// What the bridge needs to understand from the broker.
+enum Packet {
+    ConnAck {
+        code: u8,
+    },
+    PubAck {
+        id: u16,
+    },
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+        id: Option<u16>,
+    },
+    Other,
+}
+
+// Reads whole packets. Bytes of a packet still on its way are kept between
+// calls, so it is safe to use in `select!`.
+struct PacketReader {
+    reader: OwnedReadHalf,
+    buf: Vec<u8>,
+}
+
+impl PacketReader {
+    fn new(reader: OwnedReadHalf) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+        }
+    }
+
+    // `None` once the broker closed the connection.
+    async fn next(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            if let Some(packet) = self.parse()? {
+                return Ok(Some(packet));
+            }
+
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn parse(&mut self) -> io::Result<Option<Packet>> {
+        let Some((remaining, header_len)) = remaining_length(&self.buf[1.min(self.buf.len())..])?
+        else {
+            return Ok(None);
+        };
+
+        if remaining > MAX_PACKET {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "packet too long",
+            ));
+        }
+
+        let total = 1 + header_len + remaining;
+
+        if self.buf.len() < total {
+            return Ok(None);
+        }
+
+        let packet: Vec<u8> = self.buf.drain(..total).collect();
+        let (header, body) = (packet[0], &packet[1 + header_len..]);
+
+        Ok(Some(match header & 0xF0 {
+            CONNACK if body.len() >= 2 => Packet::ConnAck { code: body[1] },
+            PUBLISH => decode_publish(header, body)?,
+            PUBACK if body.len() >= 2 => Packet::PubAck {
+                id: u16::from_be_bytes([body[0], body[1]]),
+            },
+            _ => Packet::Other,
+        }))
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// The remaining length and how many bytes encode it, or `None` if more bytes
+// are needed.
+fn remaining_length(bytes: &[u8]) -> io::Result<Option<(usize, usize)>> {
+    let mut value = 0;
+
+    for (i, byte) in bytes.iter().enumerate().take(4) {
+        value |= ((byte & 0x7F) as usize) << (7 * i);
+
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, i + 1)));
+        }
+    }
+
+    match bytes.len() >= 4 {
+        true => Err(invalid("malformed remaining length")),
+        false => Ok(None),
+    }
+}
+
+fn decode_publish(header: u8, body: &[u8]) -> io::Result<Packet> {
+    let qos = (header >> 1) & 0x03;
+
+    let topic_len = u16::from_be_bytes(
+        body.get(..2)
+            .ok_or_else(|| invalid("short publish"))?
+            .try_into()
+            .unwrap_or_default(),
+    ) as usize;
+    let topic = body
+        .get(2..2 + topic_len)
+        .ok_or_else(|| invalid("short publish"))?;
+    let mut rest = &body[2 + topic_len..];
+
+    let id = match qos {
+        0 => None,
+        _ => {
+            let id = rest.get(..2).ok_or_else(|| invalid("short publish"))?;
+            let id = u16::from_be_bytes([id[0], id[1]]);
+            rest = &rest[2..];
+
+            Some(id)
+        }
+    };
+
+    Ok(Packet::Publish {
+        topic: String::from_utf8_lossy(topic).into_owned(),
+        payload: rest.to_vec(),
+        id,
+    })
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut remaining = body.len();
+
+    loop {
+        let byte = (remaining % 128) as u8;
+        remaining /= 128;
+
+        match remaining {
+            0 => {
+                packet.push(byte);
+                break;
+            }
+            _ => packet.push(byte | 0x80),
+        }
+    }
+
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+// A retained "offline" will on the status topic. With QoS 1 the broker keeps
+// the session, so both sides can finish what a lost connection left open.
+fn connect(mqtt: &MqttConfig) -> Vec<u8> {
+    const CLEAN_SESSION: u8 = 0x02;
+    const WILL: u8 = 0x04;
+    const WILL_RETAIN: u8 = 0x20;
+
+    let session = match mqtt.qos {
+        QoS::AtMostOnce => CLEAN_SESSION,
+        QoS::AtLeastOnce => 0,
+    };
+
+    let mut body = Vec::new();
+    string(&mut body, "MQTT");
+    body.push(4);
+    body.push(session | WILL | WILL_RETAIN | (mqtt.qos.level() << 3));
+
+    let keep_alive = mqtt.keep_alive.as_secs().min(u16::MAX.into()) as u16;
+    body.extend_from_slice(&keep_alive.to_be_bytes());
+
+    string(&mut body, &mqtt.client_id);
+    string(&mut body, &mqtt.status_topic());
+    string(&mut body, "offline");
+
+    packet(0x10, &body)
+}
+
+fn publish(topic: &str, payload: &[u8], qos: QoS, retain: bool, id: Option<u16>) -> Vec<u8> {
+    let mut body = Vec::new();
+    string(&mut body, topic);
+
+    if let Some(id) = id {
+        body.extend_from_slice(&id.to_be_bytes());
+    }
+
+    body.extend_from_slice(payload);
+
+    packet(PUBLISH | (qos.level() << 1) | retain as u8, &body)
+}
+
+fn subscribe(id: u16, topics: &[String], qos: QoS) -> Vec<u8> {
+    let mut body = id.to_be_bytes().to_vec();
+
+    for topic in topics {
+        string(&mut body, topic);
+        body.push(qos.level());
+    }
+
+    packet(0x82, &body)
+}
+
+fn puback(id: u16) -> Vec<u8> {
+    packet(PUBACK, &id.to_be_bytes())
+}
@@ -0,0 +1,247 @@
+use std::{io, sync::Arc, time::Duration};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    sync::{mpsc, watch},
+    time::timeout,
+};
+
+use crate::{
+    device::{Capability, DeviceInfo, DeviceKind},
+    lines::LineReader,
+    protocol::{CommandReply, STATUS_QUERY},
+};
+
+use super::{
+    Config, ServerEvent,
+    registry::{Outbound, Registry},
+    session,
+};
+
+/// A device that listens instead of connecting: the server connects to
+/// `address`, sends "status?" every `interval` and reads the answer the way
+/// it reads lines from connected devices, e.g. "Socket 1500W State: on". The
+/// first answer tells which device it is. Polled devices only report, they
+/// don't take commands.
+///
+/// While the device answers it counts as connected. When it stops the
+/// server tries again every `reconnect_delay`.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use otus_iced::server::{DeviceServer, PollConfig};
+///
+/// let (server, events) = DeviceServer::new()
+///     .poll(PollConfig::new("192.168.1.30:9000"))
+///     .start()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PollConfig {
+    pub address: String,
+    pub interval: Duration,
+    /// How long the device may take to answer before it counts as lost.
+    pub timeout: Duration,
+    pub reconnect_delay: Duration,
+}
+
+impl PollConfig {
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            reconnect_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+// Polls a device that answers "status?" until `stopped` turns true.
+pub(crate) async fn poll(
+    registry: Arc<Registry>,
+    config: Arc<Config>,
+    poll: PollConfig,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let source = format!("Опрос {}", poll.address);
+    let mut reconnect = Reconnect::new(&registry, source, poll.reconnect_delay);
+
+    while let Err(e) = connected(
+        &registry,
+        &config,
+        &poll,
+        &mut stopped,
+        &mut reconnect.last_failure,
+    )
+    .await
+    {
+        if !reconnect.retry(e, &mut stopped).await {
+            return;
+        }
+    }
+}
+
+// Tries again to reach a device the server connects to itself once its
+// connection returned the error that lost it. A failure is reported once,
+// not on every attempt.
+pub(super) struct Reconnect<'a> {
+    registry: &'a Registry,
+    source: String,
+    delay: Duration,
+    // Reset by the connection once the device answers, so its next failure
+    // is reported again.
+    pub(super) last_failure: Option<String>,
+}
+
+impl<'a> Reconnect<'a> {
+    pub(super) fn new(registry: &'a Registry, source: String, delay: Duration) -> Self {
+        Self {
+            registry,
+            source,
+            delay,
+            last_failure: None,
+        }
+    }
+
+    // Reports the failure that lost the device and waits for the next
+    // attempt; false if the server stopped meanwhile.
+    pub(super) async fn retry(
+        &mut self,
+        failure: io::Error,
+        stopped: &mut watch::Receiver<bool>,
+    ) -> bool {
+        let failure = failure.to_string();
+
+        if self.last_failure.as_ref() != Some(&failure) {
+            self.registry.report(ServerEvent::Notice(format!(
+                "{}: нет связи с устройством: {failure}",
+                self.source
+            )));
+        }
+        self.last_failure = Some(failure);
+
+        tokio::select! {
+            _ = async { stopped.wait_for(|stopped| *stopped).await.is_ok() } => false,
+            _ = tokio::time::sleep(self.delay) => true,
+        }
+    }
+}
+
+// One connection: once the device answered it is attached like a session of
+// its own. Answers that can't be parsed are rejected like lines from
+// connected devices.
+async fn connected(
+    registry: &Registry,
+    config: &Config,
+    poll: &PollConfig,
+    stopped: &mut watch::Receiver<bool>,
+    last_failure: &mut Option<String>,
+) -> io::Result<()> {
+    let tcp = within(poll.timeout, TcpStream::connect(&poll.address)).await?;
+    let (reader, mut writer) = tcp.into_split();
+    let mut lines = LineReader::new(reader, config.limits.max_line_length);
+
+    registry.count(|counters| counters.connections += 1);
+
+    let (session, mut outbound) = mpsc::channel::<Outbound>(config.limits.command_buffer.max(1));
+    let mut device: Option<DeviceKind> = None;
+
+    let mut ticks = tokio::time::interval(poll.interval.max(Duration::from_millis(1)));
+
+    let result = loop {
+        tokio::select! {
+            _ = async { stopped.wait_for(|stopped| *stopped).await.is_ok() } => break Ok(()),
+            _ = ticks.tick() => {
+                let answer = within(poll.timeout, async {
+                    writer.write_all(format!("{STATUS_QUERY}\n").as_bytes()).await?;
+                    lines.next_line().await
+                })
+                .await;
+
+                let answer = match answer {
+                    Ok(Some(answer)) => answer,
+                    Ok(None) => break Err(io::ErrorKind::UnexpectedEof.into()),
+                    Err(e) => break Err(e),
+                };
+
+                let data = match session::decode(config, answer.trim(), device) {
+                    Ok((data, _)) => data,
+                    Err(_) => {
+                        registry.count(|counters| counters.parse_failures += 1);
+                        registry.report(ServerEvent::Rejected(answer));
+                        continue;
+                    }
+                };
+
+                registry.count(|counters| counters.readings += 1);
+
+                if device.is_none() {
+                    if !registry.attach_exclusive(data.kind(), session.clone()) {
+                        break Err(taken(data.kind()));
+                    }
+
+                    let info = info(poll, data.kind());
+
+                    registry.report(ServerEvent::Notice(format!(
+                        "Подключено устройство {} ({}) по опросу",
+                        info.id, info.kind
+                    )));
+                    registry.register(info);
+
+                    device = Some(data.kind());
+                    *last_failure = None;
+                }
+
+                registry.publish(data);
+            }
+            // Commands queued while the device was away still reach the
+            // session once it answers; it has no way to carry them out.
+            Some(Outbound::Command { id, .. }) = outbound.recv() => {
+                if let Some(kind) = device {
+                    let reason = "устройство только отвечает на опрос".to_string();
+                    registry.complete(kind, CommandReply::new(id, Err(reason)));
+                }
+            }
+        }
+    };
+
+    if let Some(kind) = device {
+        registry.detach(kind, &session);
+    }
+
+    result
+}
+
+// Why a polled device is left alone while another source reports for its
+// kind; the server tries again once that source is gone.
+pub(super) fn taken(kind: DeviceKind) -> io::Error {
+    io::Error::other(format!("{kind} уже подключено из другого источника"))
+}
+
+fn info(poll: &PollConfig, kind: DeviceKind) -> DeviceInfo {
+    let reports = match kind {
+        DeviceKind::Socket => Capability::ReportsPower,
+        DeviceKind::Termometer => Capability::ReportsTemperature,
+    };
+
+    DeviceInfo {
+        kind,
+        id: format!("poll-{}", poll.address),
+        firmware: "unknown".into(),
+        protocol: 1,
+        capabilities: vec![reports],
+    }
+}
+
+// Gives up on a request to a polled device after `limit`.
+pub(super) async fn within<T>(
+    limit: Duration,
+    request: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    timeout(limit, request)
+        .await
+        .unwrap_or_else(|_| Err(io::Error::other("нет ответа")))
+}
@@ -0,0 +1,1134 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    error::Error,
+    fmt::Display,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        Mutex, MutexGuard, OnceLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::{Notify, broadcast, mpsc, oneshot};
+
+use crate::{
+    device::{Capability, Command, DeviceConfig, DeviceInfo, DeviceKind, SensorData},
+    protocol::{COMMAND_IDS, CommandReply},
+    state::DeviceState,
+};
+
+use super::{CommandError, Dispatch, Limits, WebhookDelivery};
+
+/// Discrete events are never coalesced: a device switched off and on again
+/// between two frames must still be visible to the dashboard.
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    Toggled { device: DeviceKind, on: bool },
+    Rejected(String),
+    Notice(String),
+}
+
+impl Display for ServerEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerEvent::Toggled { device, on } => {
+                let state = if *on { "on" } else { "off" };
+                write!(f, "{device}: {state}")
+            }
+            ServerEvent::Rejected(message) => write!(f, "Отклонено: {message}"),
+            ServerEvent::Notice(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FeedStats {
+    pub coalesced: u64,
+    pub dropped: u64,
+    /// Retried readings that had already been accepted.
+    pub duplicates: u64,
+    /// Readings that went to history only because they were too old to be
+    /// shown live.
+    pub backfilled: u64,
+}
+
+/// Running totals since the server started, for monitoring.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServerCounters {
+    /// Device connections accepted.
+    pub connections: u64,
+    pub readings: u64,
+    pub hellos: u64,
+    /// Answers to commands ("DONE 7 on", "FAILED 7 ...").
+    pub replies: u64,
+    /// Lines from devices that couldn't be parsed or decoded.
+    pub parse_failures: u64,
+}
+
+/// A reading with the time the device took it, or the time it arrived if
+/// the device didn't say.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub at: SystemTime,
+    pub reading: SensorData,
+}
+
+/// A command waiting for its device to connect, written as
+/// "off@1700000000000" with the expiry in milliseconds since the Unix epoch.
+#[derive(Debug, Clone)]
+pub struct PendingCommand {
+    pub state: DeviceState,
+    pub expires_at: SystemTime,
+}
+
+impl Display for PendingCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let expires_at = self
+            .expires_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+
+        write!(f, "{}@{expires_at}", self.state)
+    }
+}
+
+impl FromStr for PendingCommand {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (state, expires_at) = s.split_once('@').ok_or("expected state@expiry")?;
+
+        Ok(Self {
+            state: state.parse()?,
+            expires_at: UNIX_EPOCH + Duration::from_millis(expires_at.parse()?),
+        })
+    }
+}
+
+/// How a device's sequence numbers arrived: `last` is the highest accepted
+/// one, `gaps` counts jumps over missing numbers, `out_of_order` readings
+/// older than `last` (dropped as stale) and `duplicates` retries of readings
+/// already accepted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SequenceStats {
+    pub last: u64,
+    pub gaps: u64,
+    pub out_of_order: u64,
+    pub duplicates: u64,
+}
+
+impl Display for SequenceStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "last={} gaps={} out_of_order={} duplicates={}",
+            self.last, self.gaps, self.out_of_order, self.duplicates
+        )
+    }
+}
+
+impl FromStr for SequenceStats {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut stats = SequenceStats::default();
+
+        for field in s.split_whitespace() {
+            let (name, value) = field.split_once('=').ok_or("expected name=value")?;
+            let value = value.parse()?;
+
+            match name {
+                "last" => stats.last = value,
+                "gaps" => stats.gaps = value,
+                "out_of_order" => stats.out_of_order = value,
+                "duplicates" => stats.duplicates = value,
+                _ => {}
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+/// Everything that changed since the previous frame: the latest reading of
+/// each updated device and the discrete events in arrival order. `reset`
+/// means the registry was cleared and devices missing here are unknown.
+#[derive(Debug, Clone, Default)]
+pub struct Frame {
+    pub reset: bool,
+    pub readings: Vec<SensorData>,
+    pub events: Vec<ServerEvent>,
+    pub stats: FeedStats,
+    pub sequences: BTreeMap<DeviceKind, SequenceStats>,
+    pub devices: BTreeMap<DeviceKind, DeviceInfo>,
+    pub configs: BTreeMap<DeviceKind, DeviceConfig>,
+    /// Commands queued for disconnected devices, oldest first.
+    pub pending: BTreeMap<DeviceKind, Vec<PendingCommand>>,
+    /// Webhook deliveries, newest last.
+    pub deliveries: Vec<WebhookDelivery>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Update {
+    Reading(SensorData),
+    /// Every reading as it goes into history, backfilled ones included, with
+    /// the time it was taken.
+    Sample(Sample),
+    Event(ServerEvent),
+    Sequence(DeviceKind, SequenceStats),
+    Device(DeviceInfo),
+    Config(DeviceKind, DeviceConfig),
+    /// The device's whole command queue after it changed.
+    Pending(DeviceKind, Vec<PendingCommand>),
+    /// The device connected (`true`) or its last session ended.
+    Connection(DeviceKind, bool),
+    Delivery(WebhookDelivery),
+}
+
+// What a device session writes to its device besides replies.
+#[derive(Debug, Clone)]
+pub(crate) enum Outbound {
+    Command { id: u64, command: Command },
+    Config(DeviceConfig),
+}
+
+/// Latest reading per device plus a bounded queue of discrete events. Network
+/// tasks never wait for the consumer: they overwrite the latest value and
+/// wake the reader up. Other subscribers follow the same changes through the
+/// `updates` broadcast, and commands reach devices through their sessions.
+pub struct Registry {
+    state: Mutex<RegistryState>,
+    changed: Notify,
+    closed: AtomicBool,
+    updates: broadcast::Sender<Update>,
+    sessions: Mutex<BTreeMap<DeviceKind, mpsc::Sender<Outbound>>>,
+    config_file: OnceLock<PathBuf>,
+    // Saves run one at a time, each writing what is configured by then, so an
+    // older save can't overwrite a newer one.
+    saving: tokio::sync::Mutex<()>,
+    event_queue: usize,
+    history: usize,
+    backfill_after: Duration,
+    command_ttl: Duration,
+    command_queue: usize,
+    command_timeout: Duration,
+    next_command: AtomicU64,
+}
+
+// Whoever waits to learn how a command ended.
+type Issuer = oneshot::Sender<Result<DeviceState, CommandError>>;
+
+// A command queued for a disconnected device.
+struct Queued {
+    id: u64,
+    command: Command,
+    expires_at: SystemTime,
+    issuer: Option<Issuer>,
+}
+
+// A command sent to a device that hasn't confirmed it yet.
+struct Awaiting {
+    command: Command,
+    sent: SystemTime,
+    deadline: SystemTime,
+    issuer: Option<Issuer>,
+}
+
+impl Queued {
+    fn pending(&self) -> PendingCommand {
+        PendingCommand {
+            state: self.command.state().clone(),
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+#[derive(Default)]
+struct RegistryState {
+    latest: BTreeMap<DeviceKind, SensorData>,
+    latest_at: BTreeMap<DeviceKind, SystemTime>,
+    // When the server last received a reading, whatever its timestamp.
+    seen_at: BTreeMap<DeviceKind, SystemTime>,
+    history: BTreeMap<DeviceKind, VecDeque<Sample>>,
+    devices: BTreeMap<DeviceKind, DeviceInfo>,
+    configs: BTreeMap<DeviceKind, DeviceConfig>,
+    sequences: BTreeMap<DeviceKind, DeviceSequence>,
+    pending: BTreeMap<DeviceKind, VecDeque<Queued>>,
+    awaiting: BTreeMap<u64, Awaiting>,
+    dirty: BTreeSet<DeviceKind>,
+    events: VecDeque<ServerEvent>,
+    stats: FeedStats,
+    counters: ServerCounters,
+    deliveries: VecDeque<WebhookDelivery>,
+    reset: bool,
+}
+
+// Webhook deliveries kept for `Registry::deliveries`.
+const DELIVERY_LOG: usize = 50;
+
+// Sequence numbers accepted recently, to tell a retry from a stale reading.
+const RECENT_SEQUENCES: usize = 64;
+
+#[derive(PartialEq)]
+enum Arrival {
+    Fresh,
+    Duplicate,
+    Stale,
+}
+
+#[derive(Default)]
+struct DeviceSequence {
+    stats: SequenceStats,
+    recent: VecDeque<u64>,
+}
+
+impl DeviceSequence {
+    fn accept(&mut self, seq: u64) -> Arrival {
+        // A number far behind the window can't be a late retry: the device
+        // restarted and counts from the beginning again.
+        if seq.saturating_add(RECENT_SEQUENCES as u64) < self.stats.last {
+            self.restart();
+        }
+
+        if self.recent.contains(&seq) {
+            self.stats.duplicates += 1;
+            return Arrival::Duplicate;
+        }
+
+        if self.recent.len() == RECENT_SEQUENCES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(seq);
+
+        if seq < self.stats.last {
+            self.stats.out_of_order += 1;
+            return Arrival::Stale;
+        }
+
+        if self.stats.last != 0 && seq > self.stats.last.saturating_add(1) {
+            self.stats.gaps += 1;
+        }
+
+        self.stats.last = seq;
+        Arrival::Fresh
+    }
+
+    // Forgets where the device was, keeping the counters.
+    fn restart(&mut self) {
+        self.stats.last = 0;
+        self.recent.clear();
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new(&Limits::default())
+    }
+}
+
+impl Registry {
+    pub fn new(limits: &Limits) -> Self {
+        Self {
+            state: Mutex::default(),
+            changed: Notify::new(),
+            closed: AtomicBool::new(false),
+            updates: broadcast::channel(limits.update_buffer.max(1)).0,
+            sessions: Mutex::default(),
+            config_file: OnceLock::new(),
+            saving: tokio::sync::Mutex::new(()),
+            event_queue: limits.event_queue.max(1),
+            history: limits.history.max(1),
+            backfill_after: limits.backfill_after,
+            command_ttl: limits.command_ttl,
+            command_queue: limits.command_queue.max(1),
+            command_timeout: limits.command_timeout,
+            next_command: AtomicU64::new(1),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RegistryState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push_event(&self, state: &mut RegistryState, event: ServerEvent) {
+        if state.events.len() == self.event_queue {
+            state.events.pop_front();
+            state.stats.dropped += 1;
+        }
+
+        state.events.push_back(event);
+    }
+
+    // Keeps the device's history ordered by time, so backfilled readings
+    // land between the ones received live.
+    fn push_history(&self, state: &mut RegistryState, sample: Sample) {
+        let _ = self.updates.send(Update::Sample(sample.clone()));
+
+        let history = state.history.entry(sample.reading.kind()).or_default();
+
+        let position = history.partition_point(|s| s.at <= sample.at);
+        history.insert(position, sample);
+
+        if history.len() > self.history {
+            history.pop_front();
+        }
+    }
+
+    // Like `report`, for callers that hold the lock already.
+    fn push_notice(&self, state: &mut RegistryState, message: String) {
+        let event = ServerEvent::Notice(message);
+
+        let _ = self.updates.send(Update::Event(event.clone()));
+        self.push_event(state, event);
+    }
+
+    fn await_reply(
+        &self,
+        state: &mut RegistryState,
+        id: u64,
+        command: Command,
+        issuer: Option<Issuer>,
+    ) {
+        let sent = SystemTime::now();
+
+        state.awaiting.insert(
+            id,
+            Awaiting {
+                command,
+                sent,
+                deadline: sent + self.command_timeout,
+                issuer,
+            },
+        );
+    }
+
+    fn settle(
+        &self,
+        state: &mut RegistryState,
+        awaiting: Awaiting,
+        result: Result<DeviceState, CommandError>,
+    ) {
+        let notice = match &result {
+            Ok(_) => format!("Команда выполнена: {}", awaiting.command),
+            Err(e) => format!("Команда {} не выполнена: {e}", awaiting.command),
+        };
+        self.push_notice(state, notice);
+
+        if let Some(issuer) = awaiting.issuer {
+            let _ = issuer.send(result);
+        }
+    }
+
+    fn pending_changed(&self, state: &RegistryState, device: DeviceKind) {
+        let pending = state
+            .pending
+            .get(&device)
+            .map(|queue| queue.iter().map(Queued::pending).collect())
+            .unwrap_or_default();
+
+        let _ = self.updates.send(Update::Pending(device, pending));
+    }
+
+    fn apply(&self, state: &mut RegistryState, data: SensorData, at: SystemTime) {
+        let device = data.kind();
+
+        let toggled = state
+            .latest
+            .get(&device)
+            .is_some_and(|previous| previous.is_on() != data.is_on());
+
+        if toggled {
+            let event = ServerEvent::Toggled {
+                device,
+                on: data.is_on(),
+            };
+            self.push_event(state, event);
+        }
+
+        if !state.dirty.insert(device) {
+            state.stats.coalesced += 1;
+        }
+
+        // Devices that don't answer commands confirm them by reporting the
+        // requested state after they got them. Devices that do answer are
+        // only believed by id, in `complete`.
+        let answers = state
+            .devices
+            .get(&device)
+            .is_some_and(|info| info.protocol >= COMMAND_IDS);
+
+        let confirmed: Vec<u64> = state
+            .awaiting
+            .iter()
+            .filter(|(_, awaiting)| {
+                !answers
+                    && awaiting.command.device() == device
+                    && awaiting.command.state().get() == data.is_on()
+                    && at >= awaiting.sent
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in confirmed {
+            if let Some(awaiting) = state.awaiting.remove(&id) {
+                self.settle(state, awaiting, Ok(DeviceState::new(data.is_on())));
+            }
+        }
+
+        let _ = self.updates.send(Update::Reading(data.clone()));
+        state.latest.insert(device, data);
+        state.latest_at.insert(device, at);
+    }
+
+    pub fn publish(&self, data: SensorData) {
+        let mut state = self.lock();
+        let at = SystemTime::now();
+
+        state.seen_at.insert(data.kind(), at);
+
+        self.push_history(
+            &mut state,
+            Sample {
+                at,
+                reading: data.clone(),
+            },
+        );
+        self.apply(&mut state, data, at);
+        drop(state);
+
+        self.changed.notify_one();
+    }
+
+    /// Publishes a reading from a device session with the device's sequence
+    /// number and timestamp, if it sent them. Retries of accepted readings
+    /// are counted and dropped. Readings older than the live one, or taken
+    /// longer than `Limits::backfill_after` ago, only go to history. Returns
+    /// whether the reading became the device's live value.
+    pub fn publish_stamped(
+        &self,
+        data: SensorData,
+        seq: Option<u64>,
+        at: Option<SystemTime>,
+    ) -> bool {
+        let mut state = self.lock();
+        let device = data.kind();
+        let now = SystemTime::now();
+
+        state.seen_at.insert(device, now);
+
+        let arrival = match seq {
+            Some(seq) => {
+                let sequence = state.sequences.entry(device).or_default();
+                let arrival = sequence.accept(seq);
+
+                let _ = self.updates.send(Update::Sequence(device, sequence.stats));
+                arrival
+            }
+            None => Arrival::Fresh,
+        };
+
+        if arrival == Arrival::Duplicate {
+            state.stats.duplicates += 1;
+            drop(state);
+
+            self.changed.notify_one();
+            return false;
+        }
+
+        let at = at.unwrap_or(now);
+        let recent = now
+            .duration_since(at)
+            .map_or(true, |age| age <= self.backfill_after);
+        let newest = state
+            .latest_at
+            .get(&device)
+            .is_none_or(|latest| *latest <= at);
+
+        self.push_history(
+            &mut state,
+            Sample {
+                at,
+                reading: data.clone(),
+            },
+        );
+
+        let live = arrival == Arrival::Fresh && recent && newest;
+
+        if live {
+            self.apply(&mut state, data, at);
+        } else if !recent {
+            state.stats.backfilled += 1;
+        }
+        drop(state);
+
+        self.changed.notify_one();
+        live
+    }
+
+    /// Remembers what a device announced in its handshake; it stays known
+    /// after the device disconnects. The device's sequence numbers start
+    /// over.
+    pub fn register(&self, info: DeviceInfo) {
+        let mut state = self.lock();
+
+        if let Some(sequence) = state.sequences.get_mut(&info.kind) {
+            sequence.restart();
+        }
+        let _ = self.updates.send(Update::Device(info.clone()));
+        state.devices.insert(info.kind, info);
+        drop(state);
+
+        self.changed.notify_one();
+    }
+
+    /// The device's latest live reading and when it was taken.
+    pub fn latest(&self, device: DeviceKind) -> Option<Sample> {
+        let state = self.lock();
+        let reading = state.latest.get(&device)?.clone();
+        let at = state.latest_at.get(&device).copied()?;
+
+        Some(Sample { at, reading })
+    }
+
+    /// When the server last received a reading from the device, retries and
+    /// backfilled readings included.
+    pub fn last_seen(&self, device: DeviceKind) -> Option<SystemTime> {
+        self.lock().seen_at.get(&device).copied()
+    }
+
+    pub fn stats(&self) -> FeedStats {
+        self.lock().stats
+    }
+
+    pub fn counters(&self) -> ServerCounters {
+        self.lock().counters
+    }
+
+    pub(crate) fn count(&self, update: impl FnOnce(&mut ServerCounters)) {
+        update(&mut self.lock().counters);
+    }
+
+    /// Webhook deliveries, newest last.
+    pub fn deliveries(&self) -> Vec<WebhookDelivery> {
+        self.lock().deliveries.iter().cloned().collect()
+    }
+
+    // Logs the outcome of a webhook delivery and tells every subscriber.
+    pub(crate) fn record_delivery(&self, delivery: WebhookDelivery) {
+        let event = match delivery.delivered {
+            true => format!("Вебхук {}: {} доставлено", delivery.url, delivery.event),
+            false => format!(
+                "Вебхук {}: {} не доставлено: {}",
+                delivery.url,
+                delivery.event,
+                delivery.error.as_deref().unwrap_or_default()
+            ),
+        };
+
+        self.add_delivery(delivery);
+        self.report(ServerEvent::Notice(event));
+    }
+
+    /// Mirrors a webhook delivery received from another server.
+    pub fn add_delivery(&self, delivery: WebhookDelivery) {
+        let mut state = self.lock();
+
+        if state.deliveries.len() == DELIVERY_LOG {
+            state.deliveries.pop_front();
+        }
+        state.deliveries.push_back(delivery.clone());
+
+        let _ = self.updates.send(Update::Delivery(delivery));
+        drop(state);
+
+        self.changed.notify_one();
+    }
+
+    pub fn is_connected(&self, device: DeviceKind) -> bool {
+        self.sessions().contains_key(&device)
+    }
+
+    pub fn devices(&self) -> Vec<DeviceInfo> {
+        self.lock().devices.values().cloned().collect()
+    }
+
+    pub fn configs(&self) -> BTreeMap<DeviceKind, DeviceConfig> {
+        self.lock().configs.clone()
+    }
+
+    pub fn config(&self, device: DeviceKind) -> Option<DeviceConfig> {
+        self.lock().configs.get(&device).copied()
+    }
+
+    /// Stores the configuration, pushes it to the device if it is connected
+    /// (otherwise it gets it when it connects) and saves every device's
+    /// configuration to the server's config file, if there is one.
+    pub async fn configure(&self, device: DeviceKind, config: DeviceConfig) {
+        self.set_config(device, config);
+
+        if let Some(session) = self.sessions().get(&device) {
+            let _ = session.try_send(Outbound::Config(config));
+        }
+
+        let notice = match self.save_configs().await {
+            Ok(()) => format!("Настройки {device}: {config}"),
+            Err(e) => format!("Настройки {device} не сохранены: {e}"),
+        };
+        self.report(ServerEvent::Notice(notice));
+    }
+
+    /// Mirrors a configuration received from another server.
+    pub fn set_config(&self, device: DeviceKind, config: DeviceConfig) {
+        let mut state = self.lock();
+
+        let _ = self.updates.send(Update::Config(device, config));
+        state.configs.insert(device, config);
+        drop(state);
+
+        self.changed.notify_one();
+    }
+
+    // One "socket interval=10 deadband=0.5" line per device. Unreadable lines
+    // are skipped, a missing file means nothing was configured yet.
+    pub(crate) fn load_configs(&self, path: PathBuf) -> std::io::Result<()> {
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut state = self.lock();
+
+        for line in contents.lines() {
+            let Some((device, config)) = line.split_once(' ') else {
+                continue;
+            };
+
+            if let (Ok(device), Ok(config)) = (device.parse(), config.parse()) {
+                state.configs.insert(device, config);
+            }
+        }
+        drop(state);
+
+        let _ = self.config_file.set(path);
+        Ok(())
+    }
+
+    async fn save_configs(&self) -> std::io::Result<()> {
+        let Some(path) = self.config_file.get() else {
+            return Ok(());
+        };
+
+        let _saving = self.saving.lock().await;
+
+        let contents: String = self
+            .lock()
+            .configs
+            .iter()
+            .map(|(device, config)| format!("{device} {config}\n"))
+            .collect();
+
+        tokio::fs::write(path, contents).await
+    }
+
+    /// The device's readings ordered by time, oldest first; at most
+    /// `Limits::history` of them are kept.
+    pub fn history(&self, device: DeviceKind) -> Vec<Sample> {
+        self.lock()
+            .history
+            .get(&device)
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn sequences(&self) -> BTreeMap<DeviceKind, SequenceStats> {
+        self.lock()
+            .sequences
+            .iter()
+            .map(|(device, sequence)| (*device, sequence.stats))
+            .collect()
+    }
+
+    /// Mirrors sequence counters received from another server.
+    pub fn set_sequence(&self, device: DeviceKind, stats: SequenceStats) {
+        self.lock().sequences.entry(device).or_default().stats = stats;
+        self.changed.notify_one();
+    }
+
+    pub fn pending(&self, device: DeviceKind) -> Vec<PendingCommand> {
+        self.lock()
+            .pending
+            .get(&device)
+            .map(|queue| queue.iter().map(Queued::pending).collect())
+            .unwrap_or_default()
+    }
+
+    /// Mirrors a command queue received from another server.
+    pub fn set_pending(&self, device: DeviceKind, pending: Vec<PendingCommand>) {
+        let mut state = self.lock();
+
+        let queue = pending
+            .into_iter()
+            .map(|pending| Queued {
+                id: 0,
+                command: Command::new(device, pending.state),
+                expires_at: pending.expires_at,
+                issuer: None,
+            })
+            .collect::<VecDeque<_>>();
+
+        match queue.is_empty() {
+            true => state.pending.remove(&device),
+            false => state.pending.insert(device, queue),
+        };
+        drop(state);
+
+        self.changed.notify_one();
+    }
+
+    /// Drops queued commands that waited longer than `Limits::command_ttl`
+    /// and gives up on sent ones the device didn't confirm within
+    /// `Limits::command_timeout`, telling whoever issued them.
+    pub(crate) fn expire_commands(&self) {
+        let now = SystemTime::now();
+        let mut state = self.lock();
+        let mut expired = Vec::new();
+
+        for (device, queue) in state.pending.iter_mut() {
+            let (waiting, gone) = std::mem::take(queue)
+                .into_iter()
+                .partition::<VecDeque<_>, _>(|queued| queued.expires_at > now);
+
+            *queue = waiting;
+            expired.extend(gone.into_iter().map(|queued| (*device, queued)));
+        }
+
+        state.pending.retain(|_, queue| !queue.is_empty());
+
+        let devices: BTreeSet<_> = expired.iter().map(|(device, _)| *device).collect();
+        for device in devices {
+            self.pending_changed(&state, device);
+        }
+
+        for (device, queued) in expired {
+            if let Some(issuer) = queued.issuer {
+                let _ = issuer.send(Err(CommandError::Expired(device)));
+            }
+
+            let notice = format!(
+                "Команда {} не доставлена: устройство не подключилось",
+                queued.command
+            );
+            self.push_notice(&mut state, notice);
+        }
+
+        let timed_out: Vec<u64> = state
+            .awaiting
+            .iter()
+            .filter(|(_, awaiting)| awaiting.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in timed_out {
+            if let Some(awaiting) = state.awaiting.remove(&id) {
+                let device = awaiting.command.device();
+                self.settle(&mut state, awaiting, Err(CommandError::TimedOut(device)));
+            }
+        }
+        drop(state);
+
+        self.changed.notify_one();
+    }
+
+    /// Settles a command the device answered. Answers to unknown ids, e.g.
+    /// to commands that timed out already, are ignored.
+    pub(crate) fn complete(&self, device: DeviceKind, reply: CommandReply) {
+        let mut state = self.lock();
+
+        let known = state
+            .awaiting
+            .get(&reply.id())
+            .is_some_and(|awaiting| awaiting.command.device() == device);
+
+        if !known {
+            return;
+        }
+
+        if let Some(awaiting) = state.awaiting.remove(&reply.id()) {
+            let result = match reply {
+                CommandReply::Done { state, .. } => Ok(state),
+                CommandReply::Failed { reason, .. } => Err(CommandError::Failed { device, reason }),
+            };
+
+            self.settle(&mut state, awaiting, result);
+        }
+        drop(state);
+
+        self.changed.notify_one();
+    }
+
+    pub fn report(&self, event: ServerEvent) {
+        let mut state = self.lock();
+
+        let _ = self.updates.send(Update::Event(event.clone()));
+        self.push_event(&mut state, event);
+        drop(state);
+
+        self.changed.notify_one();
+    }
+
+    /// Forgets every device, e.g. before a remote dashboard applies a new
+    /// snapshot from the server.
+    pub fn clear(&self) {
+        let mut state = self.lock();
+
+        state.latest.clear();
+        state.pending.clear();
+        state.deliveries.clear();
+        state.dirty.clear();
+        state.reset = true;
+        drop(state);
+
+        self.changed.notify_one();
+    }
+
+    pub fn take_frame(&self) -> Frame {
+        let mut state = self.lock();
+
+        let dirty = std::mem::take(&mut state.dirty);
+        let readings = dirty
+            .into_iter()
+            .filter_map(|device| state.latest.get(&device).cloned())
+            .collect();
+
+        Frame {
+            reset: std::mem::take(&mut state.reset),
+            readings,
+            events: state.events.drain(..).collect(),
+            stats: state.stats,
+            sequences: state
+                .sequences
+                .iter()
+                .map(|(device, sequence)| (*device, sequence.stats))
+                .collect(),
+            devices: state.devices.clone(),
+            configs: state.configs.clone(),
+            pending: state
+                .pending
+                .iter()
+                .map(|(device, queue)| (*device, queue.iter().map(Queued::pending).collect()))
+                .collect(),
+            deliveries: state.deliveries.iter().cloned().collect(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<SensorData> {
+        self.lock().latest.values().cloned().collect()
+    }
+
+    /// The snapshot and the subscription are taken under the same lock, so a
+    /// subscriber sees every update after its snapshot exactly once.
+    pub fn subscribe(&self) -> (broadcast::Receiver<Update>, Vec<SensorData>) {
+        let state = self.lock();
+
+        (
+            self.updates.subscribe(),
+            state.latest.values().cloned().collect(),
+        )
+    }
+
+    /// Resolves once something was published since the previous frame was
+    /// taken, or the registry was closed.
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.changed.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn sessions(&self) -> MutexGuard<'_, BTreeMap<DeviceKind, mpsc::Sender<Outbound>>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Like `attach`, for devices the server polls: a device kind another
+    // source is attached for is refused rather than taken over.
+    pub(crate) fn attach_exclusive(
+        &self,
+        device: DeviceKind,
+        session: mpsc::Sender<Outbound>,
+    ) -> bool {
+        let taken = self
+            .sessions()
+            .get(&device)
+            .is_some_and(|current| !current.is_closed() && !current.same_channel(&session));
+
+        if !taken {
+            self.attach(device, session);
+        }
+        !taken
+    }
+
+    // A device that connects gets its stored configuration right away, then
+    // the commands queued while it was away.
+    pub(crate) fn attach(&self, device: DeviceKind, session: mpsc::Sender<Outbound>) {
+        self.expire_commands();
+
+        let mut state = self.lock();
+
+        if let Some(config) = state.configs.get(&device) {
+            let _ = session.try_send(Outbound::Config(*config));
+        }
+
+        if self.sessions().insert(device, session.clone()).is_none() {
+            let _ = self.updates.send(Update::Connection(device, true));
+        }
+
+        let Some(queue) = state.pending.remove(&device) else {
+            return;
+        };
+
+        let mut delivered = Vec::new();
+        let mut waiting = VecDeque::new();
+
+        for queued in queue {
+            let sent = waiting.is_empty()
+                && session
+                    .try_send(Outbound::Command {
+                        id: queued.id,
+                        command: queued.command.clone(),
+                    })
+                    .is_ok();
+
+            match sent {
+                true => delivered.push(queued),
+                false => waiting.push_back(queued),
+            }
+        }
+
+        if !waiting.is_empty() {
+            state.pending.insert(device, waiting);
+        }
+        self.pending_changed(&state, device);
+
+        for queued in delivered {
+            let notice = format!("Команда отправлена после подключения: {}", queued.command);
+            self.push_notice(&mut state, notice);
+
+            self.await_reply(&mut state, queued.id, queued.command, queued.issuer);
+        }
+        drop(state);
+
+        self.changed.notify_one();
+    }
+
+    pub(crate) fn detach(&self, device: DeviceKind, session: &mpsc::Sender<Outbound>) {
+        let mut sessions = self.sessions();
+
+        if sessions
+            .get(&device)
+            .is_some_and(|current| current.same_channel(session))
+        {
+            sessions.remove(&device);
+            let _ = self.updates.send(Update::Connection(device, false));
+        }
+    }
+
+    /// Hands the command to the device's session, or queues it for up to
+    /// `Limits::command_ttl` if the device is not connected, and tells every
+    /// subscriber what happened. Devices that announced no
+    /// `Capability::Switch` are not sent commands.
+    pub fn send(&self, command: &Command) -> Result<Dispatch, CommandError> {
+        self.dispatch(command, None)
+    }
+
+    /// Like `send`, but resolves with the state the device reports once it
+    /// carried the command out. Fails if the device refused it, didn't
+    /// answer within `Limits::command_timeout` or never connected.
+    pub fn submit(
+        &self,
+        command: &Command,
+    ) -> impl Future<Output = Result<DeviceState, CommandError>> + use<> {
+        let device = command.device();
+        let (issuer, outcome) = oneshot::channel();
+        let dispatched = self.dispatch(command, Some(issuer));
+
+        async move {
+            dispatched?;
+            outcome.await.unwrap_or(Err(CommandError::Expired(device)))
+        }
+    }
+
+    // The queue is checked under the same lock `attach` takes, so a command
+    // can't be queued for a device that has just connected.
+    fn dispatch(
+        &self,
+        command: &Command,
+        issuer: Option<Issuer>,
+    ) -> Result<Dispatch, CommandError> {
+        let device = command.device();
+        let id = self.next_command.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.lock();
+
+        let switchable = state
+            .devices
+            .get(&device)
+            .is_none_or(|info| info.can(Capability::Switch));
+        let session = self.sessions().get(&device).cloned();
+
+        let result = match session {
+            _ if !switchable => Err(CommandError::Unsupported(device)),
+            Some(session) => {
+                let outbound = Outbound::Command {
+                    id,
+                    command: command.clone(),
+                };
+
+                match session.try_send(outbound) {
+                    Ok(()) => {
+                        self.await_reply(&mut state, id, command.clone(), issuer);
+                        Ok(Dispatch::Sent)
+                    }
+                    Err(_) => Err(CommandError::Busy(device)),
+                }
+            }
+            None if self.command_ttl.is_zero() => Err(CommandError::NotConnected(device)),
+            None => {
+                let queue = state.pending.entry(device).or_default();
+
+                match queue.len() < self.command_queue {
+                    true => {
+                        queue.push_back(Queued {
+                            id,
+                            command: command.clone(),
+                            expires_at: SystemTime::now() + self.command_ttl,
+                            issuer,
+                        });
+                        self.pending_changed(&state, device);
+
+                        Ok(Dispatch::Queued)
+                    }
+                    false => Err(CommandError::Busy(device)),
+                }
+            }
+        };
+        drop(state);
+
+        let notice = match &result {
+            Ok(Dispatch::Sent) => format!("Команда отправлена: {command}"),
+            Ok(Dispatch::Queued) => format!("Команда ждёт подключения устройства: {command}"),
+            Err(e) => e.to_string(),
+        };
+        self.report(ServerEvent::Notice(notice));
+
+        result
+    }
+}
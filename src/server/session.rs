@@ -0,0 +1,242 @@
+use std::{
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    sync::{mpsc, watch},
+};
+
+use crate::{
+    device::{DeviceInfo, DeviceKind, SensorData},
+    lines::LineReader,
+    protocol::{
+        COMMAND_IDS, CommandReply, Envelope, Hello, HelloReply, NackCode, PROTOCOL_VERSIONS, Reply,
+    },
+};
+
+use super::{
+    Config, ServerEvent,
+    registry::{Outbound, Registry},
+};
+
+// A device session: an optional HELLO, then every line is a reading, and
+// while the connection stays open the device can be sent commands such as
+// "SET on" or its configuration ("CONFIG interval=10 ..."). Readings with a
+// sequence number are answered with ACK or NACK. Devices that negotiated
+// command ids get "SET on | id=7" and answer with "DONE 7 on" or "FAILED 7 ...".
+pub(crate) async fn handle_connection(
+    socket: TcpStream,
+    registry: Arc<Registry>,
+    config: Arc<Config>,
+    mut closing: watch::Receiver<bool>,
+) {
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = LineReader::new(reader, config.limits.max_line_length);
+
+    let (session, mut outbound) = mpsc::channel::<Outbound>(config.limits.command_buffer.max(1));
+    let mut device: Option<(DeviceKind, usize)> = None;
+    let mut announced: Option<DeviceKind> = None;
+    let mut protocol = 1;
+
+    registry.count(|counters| counters.connections += 1);
+
+    loop {
+        tokio::select! {
+            _ = closing.changed() => break,
+            line = lines.next_line() => {
+                let line = match line {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break,
+                    Err(e) => {
+                        registry.count(|counters| counters.parse_failures += 1);
+                        registry.report(ServerEvent::Rejected(e.to_string()));
+                        break;
+                    }
+                };
+
+                let recieved = line.trim();
+
+                if recieved.is_empty() {
+                    continue;
+                }
+
+                if recieved.starts_with("HELLO ") {
+                    let reply = match greet(&registry, &config, recieved) {
+                        Ok(info) => {
+                            reattach(&registry, &session, device, info.kind);
+
+                            let codec = device.map(|(_, codec)| codec).unwrap_or_default();
+                            device = Some((info.kind, codec));
+                            announced = Some(info.kind);
+                            protocol = info.protocol;
+
+                            HelloReply::Welcome { protocol: info.protocol }
+                        }
+                        Err(reason) => HelloReply::Refused(reason),
+                    };
+
+                    let refused = matches!(reply, HelloReply::Refused(_));
+
+                    if writer.write_all(format!("{reply}\n").as_bytes()).await.is_err() || refused {
+                        break;
+                    }
+
+                    continue;
+                }
+
+                if let Ok(reply) = recieved.parse::<CommandReply>() {
+                    registry.count(|counters| counters.replies += 1);
+
+                    if let Some((kind, _)) = device {
+                        registry.complete(kind, reply);
+                    }
+
+                    continue;
+                }
+
+                let envelope = match recieved.parse::<Envelope>() {
+                    Ok(envelope) => envelope,
+                    Err(e) => {
+                        registry.count(|counters| counters.parse_failures += 1);
+                        registry.report(ServerEvent::Rejected(format!("{recieved}: {e}")));
+                        continue;
+                    }
+                };
+
+                let reply = match decode(&config, &envelope.payload, announced) {
+                    Ok((data, codec)) => {
+                        registry.count(|counters| counters.readings += 1);
+
+                        reattach(&registry, &session, device, data.kind());
+
+                        device = Some((data.kind(), codec));
+
+                        let at = envelope.ts.map(|ts| UNIX_EPOCH + Duration::from_millis(ts));
+                        registry.publish_stamped(data, envelope.seq, at);
+
+                        envelope.seq.map(Reply::Ack)
+                    }
+                    Err((code, reason)) => {
+                        registry.count(|counters| counters.parse_failures += 1);
+                        registry.report(ServerEvent::Rejected(envelope.payload.clone()));
+
+                        envelope.seq.map(|seq| Reply::Nack { seq, code, reason })
+                    }
+                };
+
+                if let Some(reply) = reply
+                    && writer.write_all(format!("{reply}\n").as_bytes()).await.is_err()
+                {
+                    break;
+                }
+            }
+            Some(outbound) = outbound.recv() => {
+                let line = match outbound {
+                    Outbound::Command { id, command } => {
+                        let codec = device.map(|(_, codec)| codec).unwrap_or_default();
+                        let line = config.codecs[codec].encode(&command);
+
+                        match protocol >= COMMAND_IDS {
+                            true => format!("{}\n", Envelope::new(line).with_id(id)),
+                            false => format!("{line}\n"),
+                        }
+                    }
+                    Outbound::Config(device_config) => format!("CONFIG {device_config}\n"),
+                };
+
+                if writer.write_all(line.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some((kind, _)) = device {
+        registry.detach(kind, &session);
+    }
+}
+
+// Attaches the session for `kind` when the connection starts reporting as
+// it. A connection that reported as another kind before is detached from
+// that one, so its commands are no longer sent here.
+fn reattach(
+    registry: &Registry,
+    session: &mpsc::Sender<Outbound>,
+    device: Option<(DeviceKind, usize)>,
+    kind: DeviceKind,
+) {
+    match device {
+        Some((current, _)) if current == kind => return,
+        Some((current, _)) => registry.detach(current, session),
+        None => {}
+    }
+
+    registry.attach(kind, session.clone());
+}
+
+// Negotiates the protocol version and registers the device, or explains why
+// the session can't go on.
+fn greet(registry: &Registry, config: &Config, line: &str) -> Result<DeviceInfo, String> {
+    let hello = match line.parse::<Hello>() {
+        Ok(hello) => hello,
+        Err(e) => {
+            registry.count(|counters| counters.parse_failures += 1);
+            return Err(format!("некорректное приветствие: {e}"));
+        }
+    };
+
+    registry.count(|counters| counters.hellos += 1);
+
+    if !config.kinds.contains(&hello.kind) {
+        return Err(format!("устройства {} не поддерживаются", hello.kind));
+    }
+
+    let protocol = hello
+        .negotiate(PROTOCOL_VERSIONS)
+        .ok_or("нет общей версии протокола")?;
+
+    let info = hello.info(protocol);
+
+    registry.report(ServerEvent::Notice(format!(
+        "Подключено устройство {} ({}), прошивка {}",
+        info.id, info.kind, info.firmware
+    )));
+    registry.register(info.clone());
+
+    Ok(info)
+}
+
+// Returns the reading and the index of the codec that understood it, or why
+// the line can't be accepted. A device that introduced itself may only send
+// readings of its own kind.
+pub(super) fn decode(
+    config: &Config,
+    line: &str,
+    announced: Option<DeviceKind>,
+) -> Result<(SensorData, usize), (NackCode, String)> {
+    let (data, codec) = config
+        .codecs
+        .iter()
+        .enumerate()
+        .find_map(|(index, codec)| codec.decode(line).ok().map(|data| (data, index)))
+        .ok_or((
+            NackCode::Malformed,
+            "не удалось разобрать сообщение".to_string(),
+        ))?;
+
+    if let Some(kind) = announced
+        && kind != data.kind()
+    {
+        return Err((NackCode::Malformed, format!("ожидались показания {kind}")));
+    }
+
+    if !config.kinds.contains(&data.kind()) {
+        let reason = format!("устройства {} не поддерживаются", data.kind());
+        return Err((NackCode::Unsupported, reason));
+    }
+
+    Ok((data, codec))
+}
@@ -0,0 +1,439 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fmt::{Display, Write},
+    io,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use tokio::{
+    sync::{broadcast, watch},
+    task::JoinSet,
+};
+
+use crate::device::{DeviceKind, SensorData};
+
+use super::{Registry, ServerEvent, Update, http};
+
+/// When a webhook fires, written as "socket:on", "socket:off",
+/// "termometer:online", "termometer:offline", or a threshold on the power or
+/// temperature such as "termometer>30" or "socket<5". A threshold fires when
+/// a reading crosses it, not on every reading past it.
+#[derive(Debug, Clone, Copy)]
+pub enum WebhookTrigger {
+    TurnedOn(DeviceKind),
+    TurnedOff(DeviceKind),
+    Online(DeviceKind),
+    Offline(DeviceKind),
+    Above(DeviceKind, f32),
+    Below(DeviceKind, f32),
+}
+
+impl WebhookTrigger {
+    fn device(&self) -> DeviceKind {
+        match self {
+            WebhookTrigger::TurnedOn(device)
+            | WebhookTrigger::TurnedOff(device)
+            | WebhookTrigger::Online(device)
+            | WebhookTrigger::Offline(device)
+            | WebhookTrigger::Above(device, _)
+            | WebhookTrigger::Below(device, _) => *device,
+        }
+    }
+}
+
+// Triggers are compared by their text, so thresholds can be hashed along
+// with the rest of the config.
+impl PartialEq for WebhookTrigger {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+impl Eq for WebhookTrigger {}
+
+impl std::hash::Hash for WebhookTrigger {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.to_string().hash(state);
+    }
+}
+
+impl Display for WebhookTrigger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebhookTrigger::TurnedOn(device) => write!(f, "{device}:on"),
+            WebhookTrigger::TurnedOff(device) => write!(f, "{device}:off"),
+            WebhookTrigger::Online(device) => write!(f, "{device}:online"),
+            WebhookTrigger::Offline(device) => write!(f, "{device}:offline"),
+            WebhookTrigger::Above(device, limit) => write!(f, "{device}>{limit}"),
+            WebhookTrigger::Below(device, limit) => write!(f, "{device}<{limit}"),
+        }
+    }
+}
+
+impl FromStr for WebhookTrigger {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((device, limit)) = s.split_once('>') {
+            return Ok(WebhookTrigger::Above(device.parse()?, limit.parse()?));
+        }
+
+        if let Some((device, limit)) = s.split_once('<') {
+            return Ok(WebhookTrigger::Below(device.parse()?, limit.parse()?));
+        }
+
+        let (device, event) = s.split_once(':').ok_or("unknown trigger")?;
+        let device = device.parse()?;
+
+        match event {
+            "on" => Ok(WebhookTrigger::TurnedOn(device)),
+            "off" => Ok(WebhookTrigger::TurnedOff(device)),
+            "online" => Ok(WebhookTrigger::Online(device)),
+            "offline" => Ok(WebhookTrigger::Offline(device)),
+            _ => Err("unknown trigger".into()),
+        }
+    }
+}
+
+/// An HTTP endpoint the server POSTs to when one of its triggers fires. The
+/// body is JSON:
+///
+/// ```json
+/// {"event": "termometer>30", "device": "termometer", "id": "termo-1",
+///  "on": true, "value": 30.5, "at": 1700000000000}
+/// ```
+///
+/// `value` is the power or temperature of the latest reading, `null` if
+/// there is none yet. A `template` replaces the body; "{{event}}",
+/// "{{device}}", "{{id}}", "{{on}}", "{{value}}" and "{{at}}" in it are
+/// filled in. The first three are escaped to go inside a JSON string. With a `secret` every request carries
+/// "X-Signature: sha256=<hex>", the HMAC-SHA256 of the body.
+///
+/// A delivery is retried `retries` times, waiting `retry_delay` and then
+/// twice as long each time. The outcome goes to `Registry::deliveries`.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// use otus_iced::server::{DeviceServer, WebhookConfig};
+///
+/// let mut webhook = WebhookConfig::new("http://localhost:9000/hooks/house")?;
+/// webhook.triggers.push("termometer>30".parse()?);
+/// webhook.secret = Some("s3cret".into());
+///
+/// let (server, events) = DeviceServer::new().webhook(webhook).start().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WebhookConfig {
+    url: String,
+    address: String,
+    path: String,
+    pub triggers: Vec<WebhookTrigger>,
+    pub template: Option<String>,
+    pub secret: Option<String>,
+    pub retries: u32,
+    pub retry_delay: Duration,
+    /// How long one attempt may take before it counts as failed.
+    pub timeout: Duration,
+}
+
+impl WebhookConfig {
+    /// Takes an "http://host:port/path" URL; HTTPS is not supported.
+    pub fn new(url: impl Into<String>) -> Result<Self, Box<dyn Error>> {
+        let url = url.into();
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or("only http:// URLs are supported")?;
+
+        let (address, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+
+        if address.is_empty() {
+            return Err("URL needs a host".into());
+        }
+
+        Ok(Self {
+            address: address.into(),
+            path: path.into(),
+            url,
+            triggers: Vec::new(),
+            template: None,
+            secret: None,
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn body(&self, event: &Event) -> String {
+        let Some(template) = &self.template else {
+            return json!({
+                "event": event.trigger.to_string(),
+                "device": event.trigger.device().to_string(),
+                "id": event.id,
+                "on": event.on,
+                "value": event.value,
+                "at": event.at,
+            })
+            .to_string();
+        };
+
+        let or_null = |value: Option<String>| value.unwrap_or_else(|| "null".into());
+
+        template
+            .replace("{{event}}", &escaped(&event.trigger.to_string()))
+            .replace("{{device}}", &escaped(&event.trigger.device().to_string()))
+            .replace("{{id}}", &escaped(&event.id))
+            .replace("{{on}}", &or_null(event.on.map(|on| on.to_string())))
+            .replace("{{value}}", &or_null(event.value.map(|v| v.to_string())))
+            .replace("{{at}}", &event.at.to_string())
+    }
+
+    fn signature(&self, body: &[u8]) -> Option<String> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_ref()?.as_bytes()).ok()?;
+        mac.update(body);
+
+        let mut hex = String::from("sha256=");
+        for byte in mac.finalize().into_bytes() {
+            let _ = write!(hex, "{byte:02x}");
+        }
+
+        Some(hex)
+    }
+}
+
+// The text as it appears between the quotes of a JSON string, so a quote or
+// backslash in a device id can't break out of it.
+fn escaped(text: &str) -> String {
+    let quoted = serde_json::Value::from(text).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// The outcome of delivering one event to a webhook, as `Registry::deliveries`
+/// and `GET /webhooks/deliveries` list it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    pub url: String,
+    pub event: String,
+    pub delivered: bool,
+    /// Status of the last attempt, if the endpoint answered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    pub attempts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// When the event happened, in milliseconds since the Unix epoch.
+    pub at: u64,
+}
+
+// A trigger that fired, with what the device looked like at that moment.
+struct Event {
+    trigger: WebhookTrigger,
+    id: String,
+    on: Option<bool>,
+    value: Option<f32>,
+    at: u64,
+}
+
+// Fires the webhooks until `stopped` turns true; deliveries still being
+// retried then are abandoned. `updates` is subscribed to when the server
+// starts.
+pub(crate) async fn dispatch(
+    registry: Arc<Registry>,
+    mut updates: broadcast::Receiver<Update>,
+    kinds: BTreeSet<DeviceKind>,
+    webhooks: Vec<WebhookConfig>,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let mut deliveries = JoinSet::new();
+    // Which side of each threshold the latest reading was on, by webhook and
+    // trigger.
+    let mut past: BTreeMap<(usize, usize), bool> = BTreeMap::new();
+    // Toggles aren't broadcast; they are told from consecutive readings.
+    let mut on: BTreeMap<DeviceKind, bool> = kinds
+        .iter()
+        .filter_map(|kind| Some((*kind, registry.latest(*kind)?.reading.is_on())))
+        .collect();
+
+    loop {
+        let update = tokio::select! {
+            _ = async { stopped.wait_for(|stopped| *stopped).await.is_ok() } => break,
+            update = updates.recv() => match update {
+                Ok(update) => update,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    registry.report(ServerEvent::Notice(format!(
+                        "Вебхуки: пропущено обновлений: {missed}"
+                    )));
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            Some(_) = deliveries.join_next(), if !deliveries.is_empty() => continue,
+        };
+
+        let (reading, toggled) = match &update {
+            Update::Reading(data) => {
+                let was = on.insert(data.kind(), data.is_on());
+                (Some(data), was.is_some_and(|was| was != data.is_on()))
+            }
+            _ => (None, false),
+        };
+
+        for (webhook, trigger) in fired(&webhooks, &kinds, &mut past, &update, toggled) {
+            let event = event(&registry, trigger, reading);
+            deliveries.spawn(deliver(registry.clone(), webhooks[webhook].clone(), event));
+        }
+    }
+
+    deliveries.abort_all();
+}
+
+// The webhooks and triggers the update fires, by index. `toggled` tells
+// whether a reading switched its device on or off.
+fn fired(
+    webhooks: &[WebhookConfig],
+    kinds: &BTreeSet<DeviceKind>,
+    past: &mut BTreeMap<(usize, usize), bool>,
+    update: &Update,
+    toggled: bool,
+) -> Vec<(usize, WebhookTrigger)> {
+    let mut fired = Vec::new();
+
+    for (index, webhook) in webhooks.iter().enumerate() {
+        for (position, trigger) in webhook.triggers.iter().enumerate() {
+            if !kinds.contains(&trigger.device()) {
+                continue;
+            }
+
+            let fires = match (trigger, update) {
+                (WebhookTrigger::TurnedOn(device), Update::Reading(data)) => {
+                    *device == data.kind() && toggled && data.is_on()
+                }
+                (WebhookTrigger::TurnedOff(device), Update::Reading(data)) => {
+                    *device == data.kind() && toggled && !data.is_on()
+                }
+                (WebhookTrigger::Online(device), Update::Connection(changed, connected)) => {
+                    device == changed && *connected
+                }
+                (WebhookTrigger::Offline(device), Update::Connection(changed, connected)) => {
+                    device == changed && !*connected
+                }
+                (
+                    WebhookTrigger::Above(device, limit) | WebhookTrigger::Below(device, limit),
+                    Update::Reading(data),
+                ) if *device == data.kind() => {
+                    let value = value(data);
+                    let beyond = match trigger {
+                        WebhookTrigger::Above(..) => value > *limit,
+                        _ => value < *limit,
+                    };
+
+                    let was = past.insert((index, position), beyond).unwrap_or(false);
+                    beyond && !was
+                }
+                _ => false,
+            };
+
+            if fires {
+                fired.push((index, *trigger));
+            }
+        }
+    }
+
+    fired
+}
+
+fn value(data: &SensorData) -> f32 {
+    match data {
+        SensorData::SocketIndicator(s) => s.power().get(),
+        SensorData::TermoIndicator(t) => t.temperature().get(),
+    }
+}
+
+// A threshold event carries the reading that crossed it, the others the
+// device's latest one.
+fn event(registry: &Registry, trigger: WebhookTrigger, reading: Option<&SensorData>) -> Event {
+    let device = trigger.device();
+    let reading = reading
+        .cloned()
+        .or_else(|| registry.latest(device).map(|sample| sample.reading));
+
+    Event {
+        trigger,
+        id: registry
+            .devices()
+            .into_iter()
+            .find(|info| info.kind == device)
+            .map_or_else(|| device.to_string(), |info| info.id),
+        on: reading.as_ref().map(SensorData::is_on),
+        value: reading.as_ref().map(value),
+        at: http::millis(SystemTime::now()),
+    }
+}
+
+async fn deliver(registry: Arc<Registry>, webhook: WebhookConfig, event: Event) {
+    let body = webhook.body(&event);
+
+    let mut headers = vec![("Content-Type", "application/json".to_string())];
+
+    if let Some(signature) = webhook.signature(body.as_bytes()) {
+        headers.push(("X-Signature", signature));
+    }
+
+    let mut delivery = WebhookDelivery {
+        url: webhook.url.clone(),
+        event: event.trigger.to_string(),
+        delivered: false,
+        status: None,
+        attempts: 0,
+        error: None,
+        at: event.at,
+    };
+    let mut delay = webhook.retry_delay;
+
+    loop {
+        delivery.attempts += 1;
+
+        let posted = tokio::time::timeout(
+            webhook.timeout,
+            http::post(&webhook.address, &webhook.path, &headers, body.as_bytes()),
+        )
+        .await
+        .unwrap_or_else(|_| Err(io::Error::other("нет ответа")));
+
+        match posted {
+            Ok(status) => {
+                delivery.status = Some(status);
+                delivery.delivered = (200..300).contains(&status);
+                delivery.error = (!delivery.delivered).then(|| format!("ответ {status}"));
+            }
+            Err(e) => {
+                delivery.status = None;
+                delivery.error = Some(e.to_string());
+            }
+        }
+
+        if delivery.delivered || delivery.attempts > webhook.retries {
+            break;
+        }
+
+        tokio::time::sleep(delay).await;
+        delay = delay.saturating_mul(2);
+    }
+
+    registry.record_delivery(delivery);
+}
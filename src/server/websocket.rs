@@ -0,0 +1,451 @@
+use std::{collections::BTreeSet, io, sync::Arc};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::AsyncWriteExt,
+    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+    sync::{broadcast, mpsc, watch},
+};
+
+use crate::{
+    device::{Command, DeviceKind},
+    lines::LineReader,
+    state::DeviceState,
+};
+
+use super::{
+    Config, DeviceResource, Registry, ServerEvent, Update, WebhookDelivery,
+    http::{device, find},
+};
+
+// RFC 6455: appended to the client's key to prove the server understood the
+// handshake.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// What the WebSocket feed at `/ws` pushes to browsers, one JSON text
+/// message each, told apart by `type`:
+///
+/// ```json
+/// {"type": "snapshot", "devices": [{"kind": "socket", ...}]}
+/// {"type": "device", "device": {"kind": "socket", ...}}
+/// {"type": "toggled", "device": "socket", "on": false}
+/// {"type": "event", "message": "Команда отправлена: socket off"}
+/// {"type": "result", "id": 3, "status": "done", "state": "off"}
+/// {"type": "result", "id": 4, "status": "failed", "error": "..."}
+/// {"type": "error", "error": "устройство kettle не найдено"}
+/// {"type": "delivery", "delivery": {"url": "http://...", "event": "socket:on", ...}}
+/// ```
+///
+/// A snapshot comes first and again after every `subscribe`; then `device`
+/// carries the whole `DeviceResource` each time anything about the device
+/// changed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FeedMessage {
+    Snapshot {
+        devices: Vec<DeviceResource>,
+    },
+    Device {
+        device: DeviceResource,
+    },
+    Toggled {
+        device: String,
+        on: bool,
+    },
+    Event {
+        message: String,
+    },
+    Result {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        status: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        state: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Error {
+        error: String,
+    },
+    Delivery {
+        delivery: WebhookDelivery,
+    },
+}
+
+/// What browsers send on the feed:
+///
+/// ```json
+/// {"type": "subscribe", "devices": ["socket"]}
+/// {"type": "command", "device": "socket", "state": "off", "id": 3}
+/// ```
+///
+/// Devices are named by id or kind; an empty `devices` list subscribes to
+/// all of them, which is also where a feed starts. A command is answered
+/// with a `result` carrying its `id` once the device carried it out or it
+/// failed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FeedRequest {
+    Subscribe {
+        #[serde(default)]
+        devices: Vec<String>,
+    },
+    Command {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        device: String,
+        state: String,
+    },
+}
+
+// Whole messages assembled from the client's frames.
+enum Incoming {
+    Text(String),
+    Ping(Vec<u8>),
+    Close,
+}
+
+fn accept_key(key: &str) -> String {
+    let digest = Sha1::digest(format!("{key}{HANDSHAKE_GUID}").as_bytes());
+
+    STANDARD.encode(digest)
+}
+
+// Completes the handshake and serves the feed until either side closes it.
+// Frames are read by a separate task, so a partially received frame never
+// gets lost to `select!`.
+pub(crate) async fn feed(
+    lines: LineReader<OwnedReadHalf>,
+    mut writer: OwnedWriteHalf,
+    key: &str,
+    registry: Arc<Registry>,
+    config: Arc<Config>,
+    mut closing: watch::Receiver<bool>,
+) {
+    let handshake = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+
+    if writer.write_all(handshake.as_bytes()).await.is_err() {
+        return;
+    }
+
+    let (messages, mut incoming) = mpsc::channel(config.limits.command_buffer.max(1));
+    let reader = tokio::spawn(read_messages(
+        lines,
+        config.limits.max_line_length,
+        messages,
+    ));
+
+    let (results, mut finished) = mpsc::unbounded_channel();
+    let mut subscribed: Option<BTreeSet<DeviceKind>> = None;
+
+    let (mut updates, _) = registry.subscribe();
+    let mut message = Some(snapshot(&registry, &config, &subscribed));
+
+    loop {
+        if let Some(message) = &message
+            && send(&mut writer, message).await.is_err()
+        {
+            break;
+        }
+
+        // `None` when nothing is to be sent back, e.g. after a pong.
+        message = tokio::select! {
+            _ = closing.changed() => {
+                let _ = write_frame(&mut writer, OP_CLOSE, &[]).await;
+                break;
+            }
+            update = updates.recv() => match update {
+                Ok(update) => feed_message(&registry, &subscribed, update),
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    updates = registry.subscribe().0;
+                    Some(snapshot(&registry, &config, &subscribed))
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            Some(result) = finished.recv() => Some(result),
+            received = incoming.recv() => match received {
+                Some(Incoming::Text(text)) => {
+                    request(&registry, &config, &text, &mut subscribed, &results)
+                }
+                Some(Incoming::Ping(payload)) => {
+                    if write_frame(&mut writer, OP_PONG, &payload).await.is_err() {
+                        break;
+                    }
+
+                    None
+                }
+                Some(Incoming::Close) | None => {
+                    let _ = write_frame(&mut writer, OP_CLOSE, &[]).await;
+                    break;
+                }
+            },
+        };
+    }
+
+    reader.abort();
+}
+
+fn snapshot(
+    registry: &Registry,
+    config: &Config,
+    subscribed: &Option<BTreeSet<DeviceKind>>,
+) -> FeedMessage {
+    let devices = config
+        .kinds
+        .iter()
+        .filter(|kind| subscribed.as_ref().is_none_or(|s| s.contains(kind)))
+        .map(|kind| device(registry, *kind))
+        .collect();
+
+    FeedMessage::Snapshot { devices }
+}
+
+// Sequence counters are left to the line API; notices, rejections and
+// webhook deliveries go to every subscriber since they aren't tied to one
+// device.
+fn feed_message(
+    registry: &Registry,
+    subscribed: &Option<BTreeSet<DeviceKind>>,
+    update: Update,
+) -> Option<FeedMessage> {
+    let kind = match &update {
+        Update::Reading(data) => data.kind(),
+        Update::Event(ServerEvent::Toggled { device, .. }) => *device,
+        Update::Event(event) => {
+            return Some(FeedMessage::Event {
+                message: event.to_string(),
+            });
+        }
+        Update::Sample(_) | Update::Sequence(..) => return None,
+        Update::Device(info) => info.kind,
+        Update::Config(kind, _) | Update::Pending(kind, _) | Update::Connection(kind, _) => *kind,
+        Update::Delivery(delivery) => {
+            return Some(FeedMessage::Delivery {
+                delivery: delivery.clone(),
+            });
+        }
+    };
+
+    if subscribed.as_ref().is_some_and(|s| !s.contains(&kind)) {
+        return None;
+    }
+
+    match update {
+        Update::Event(ServerEvent::Toggled { device, on }) => Some(FeedMessage::Toggled {
+            device: device.to_string(),
+            on,
+        }),
+        _ => Some(FeedMessage::Device {
+            device: device(registry, kind),
+        }),
+    }
+}
+
+// Returns what to answer right away; command results come later through
+// `results`.
+fn request(
+    registry: &Registry,
+    config: &Config,
+    text: &str,
+    subscribed: &mut Option<BTreeSet<DeviceKind>>,
+    results: &mpsc::UnboundedSender<FeedMessage>,
+) -> Option<FeedMessage> {
+    let request = match serde_json::from_str::<FeedRequest>(text) {
+        Ok(request) => request,
+        Err(e) => {
+            return Some(FeedMessage::Error {
+                error: format!("некорректный запрос: {e}"),
+            });
+        }
+    };
+
+    match request {
+        FeedRequest::Subscribe { devices } => {
+            let mut kinds = BTreeSet::new();
+
+            for id in &devices {
+                match find(registry, config, id) {
+                    Some(kind) => kinds.insert(kind),
+                    None => return Some(not_found(id)),
+                };
+            }
+
+            *subscribed = (!kinds.is_empty()).then_some(kinds);
+
+            Some(snapshot(registry, config, subscribed))
+        }
+        FeedRequest::Command { id, device, state } => {
+            let Some(kind) = find(registry, config, &device) else {
+                return Some(not_found(&device));
+            };
+
+            let on = match state.as_str() {
+                "on" => true,
+                "off" => false,
+                _ => {
+                    return Some(FeedMessage::Error {
+                        error: "state должно быть \"on\" или \"off\"".into(),
+                    });
+                }
+            };
+
+            let outcome = registry.submit(&Command::new(kind, DeviceState::new(on)));
+            let results = results.clone();
+
+            tokio::spawn(async move {
+                let result = match outcome.await {
+                    Ok(state) => FeedMessage::Result {
+                        id,
+                        status: "done".into(),
+                        state: Some(state.to_string()),
+                        error: None,
+                    },
+                    Err(e) => FeedMessage::Result {
+                        id,
+                        status: "failed".into(),
+                        state: None,
+                        error: Some(e.to_string()),
+                    },
+                };
+
+                let _ = results.send(result);
+            });
+
+            None
+        }
+    }
+}
+
+fn not_found(id: &str) -> FeedMessage {
+    FeedMessage::Error {
+        error: format!("устройство {id} не найдено"),
+    }
+}
+
+async fn send(writer: &mut OwnedWriteHalf, message: &FeedMessage) -> io::Result<()> {
+    let text = serde_json::to_string(message).map_err(io::Error::other)?;
+
+    write_frame(writer, OP_TEXT, text.as_bytes()).await
+}
+
+// Server frames are never masked or fragmented.
+async fn write_frame(writer: &mut OwnedWriteHalf, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await
+}
+
+// Client frames must be masked. Messages longer than `limit` end the feed,
+// like overlong lines end a device session.
+async fn read_frame(
+    lines: &mut LineReader<OwnedReadHalf>,
+    limit: usize,
+) -> io::Result<(bool, u8, Vec<u8>)> {
+    let head = lines.read_bytes(2).await?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+
+    if head[1] & 0x80 == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unmasked frame"));
+    }
+
+    let len = match head[1] & 0x7F {
+        126 => u16::from_be_bytes(lines.read_bytes(2).await?.try_into().unwrap_or_default()) as u64,
+        127 => u64::from_be_bytes(lines.read_bytes(8).await?.try_into().unwrap_or_default()),
+        len => len as u64,
+    };
+
+    if len > limit as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too long"));
+    }
+
+    let mask = lines.read_bytes(4).await?;
+    let mut payload = lines.read_bytes(len as usize).await?;
+
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok((fin, opcode, payload))
+}
+
+async fn read_messages(
+    mut lines: LineReader<OwnedReadHalf>,
+    limit: usize,
+    messages: mpsc::Sender<Incoming>,
+) {
+    let mut text = Vec::new();
+    // The opcode of the message whose fragments are still coming.
+    let mut fragmented = None;
+
+    loop {
+        let Ok((fin, opcode, payload)) = read_frame(&mut lines, limit).await else {
+            break;
+        };
+
+        let message = match opcode {
+            OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+                // A continuation must follow an unfinished message, and a new
+                // message must not start inside one.
+                let kind = match (opcode, fragmented) {
+                    (OP_CONTINUATION, Some(kind)) => kind,
+                    (OP_CONTINUATION, None) | (_, Some(_)) => break,
+                    (kind, None) => kind,
+                };
+                fragmented = (!fin).then_some(kind);
+
+                // Binary messages mean nothing to the feed.
+                if kind == OP_BINARY {
+                    continue;
+                }
+
+                text.extend_from_slice(&payload);
+
+                if text.len() > limit {
+                    break;
+                }
+
+                if !fin {
+                    continue;
+                }
+
+                Incoming::Text(String::from_utf8_lossy(&std::mem::take(&mut text)).into_owned())
+            }
+            OP_PING => Incoming::Ping(payload),
+            OP_CLOSE => break,
+            // Pongs mean nothing to the feed either.
+            _ => continue,
+        };
+
+        if messages.send(message).await.is_err() {
+            return;
+        }
+    }
+
+    let _ = messages.send(Incoming::Close).await;
+}
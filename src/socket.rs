@@ -4,7 +4,7 @@ use regex::Regex;
 
 use crate::{power::Power, state::DeviceState};
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Socket {
     power: Power,
     state: DeviceState,
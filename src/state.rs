@@ -1,6 +1,6 @@
 use std::{error::Error, fmt::Display, ops::Deref, str::FromStr};
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct DeviceState(bool);
 
 impl DeviceState {
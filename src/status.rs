@@ -0,0 +1,80 @@
+//! The device side of polling: a device that can't reach the server listens
+//! for it instead and answers every `STATUS_QUERY` with its latest reading.
+
+use std::{
+    io,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{device::SensorData, lines::LineReader, listener, protocol::STATUS_QUERY};
+
+const MAX_LINE_LENGTH: usize = 1024;
+
+/// Answers "status?" with the reading last passed to `set`, e.g.
+/// "Socket 1500W State: on", or with "ERROR ..." before there is one. Clones
+/// share the same reading.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use otus_iced::status::StatusResponder;
+/// use tokio::net::TcpListener;
+///
+/// let responder = StatusResponder::new();
+/// responder.set("Socket 1500W State: on".parse().unwrap());
+///
+/// responder.serve(TcpListener::bind("127.0.0.1:9000").await?).await
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct StatusResponder {
+    latest: Arc<Mutex<Option<SensorData>>>,
+}
+
+impl StatusResponder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn latest(&self) -> MutexGuard<'_, Option<SensorData>> {
+        self.latest.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set(&self, reading: SensorData) {
+        *self.latest() = Some(reading);
+    }
+
+    /// Answers the servers that poll on `listener`; dropping the future hangs
+    /// up on them.
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        listener::serve(listener, |tcp| self.clone().answer(tcp)).await
+    }
+
+    async fn answer(self, tcp: TcpStream) {
+        let (reader, mut writer) = tcp.into_split();
+        let mut lines = LineReader::new(reader, MAX_LINE_LENGTH);
+
+        while let Ok(Some(line)) = lines.next_line().await {
+            let answer = match line.trim() {
+                "" => continue,
+                STATUS_QUERY => match self.latest().as_ref() {
+                    Some(reading) => reading.to_string(),
+                    None => "ERROR нет показаний".to_string(),
+                },
+                _ => "ERROR неизвестный запрос".to_string(),
+            };
+
+            if writer
+                .write_all(format!("{answer}\n").as_bytes())
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use std::{error::Error, fmt::Display, str::FromStr};
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Temperature(f32);
 
 impl Temperature {
@@ -4,7 +4,7 @@ use regex::Regex;
 
 use crate::{state::DeviceState, temperature::Temperature};
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Termometer {
     temperature: Temperature,
     state: DeviceState,
@@ -0,0 +1,282 @@
+#[cfg(test)]
+mod client_tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use otus_iced::{
+        client::{ClientEvent, ClientEvents, ConnectionStatus, DeliveryError, DeviceClient},
+        device::{Capability, Command, DeviceConfig, DeviceKind, SensorData},
+        protocol::{Hello, NackCode, PROTOCOL_VERSIONS},
+        server::{DeviceServer, EventStream},
+        state::DeviceState,
+    };
+    use tokio::time::timeout;
+
+    async fn next_event(events: &mut ClientEvents) -> ClientEvent {
+        timeout(Duration::from_secs(5), events.next())
+            .await
+            .expect("event in time")
+            .expect("client is running")
+    }
+
+    async fn next_reading(events: &mut EventStream) -> SensorData {
+        loop {
+            let frame = timeout(Duration::from_secs(5), events.next())
+                .await
+                .expect("frame in time")
+                .expect("server is running");
+
+            if let Some(reading) = frame.readings.into_iter().last() {
+                return reading;
+            }
+        }
+    }
+
+    fn reading(power: &str) -> SensorData {
+        format!("Socket {power}W State: on").parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn readings_are_buffered_until_server_is_up() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let (client, mut events) = DeviceClient::new(address.to_string())
+            .backoff(Duration::from_millis(20), Duration::from_millis(100))
+            .start();
+        client.send(reading("1500"));
+
+        while !matches!(
+            next_event(&mut events).await,
+            ClientEvent::Status(ConnectionStatus::Unreachable { .. })
+        ) {}
+
+        assert_eq!(client.buffered(), 1, "Reading waits for the server");
+
+        let (server, mut readings) = DeviceServer::new()
+            .bind(address.to_string())
+            .start()
+            .await
+            .unwrap();
+
+        assert!(
+            matches!(next_reading(&mut readings).await, SensorData::SocketIndicator(s) if s.power().get() == 1500.0),
+            "Buffered reading is delivered after reconnect"
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn oldest_readings_are_dropped_when_buffer_is_full() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let (client, _events) = DeviceClient::new(address.to_string()).buffer(2).start();
+
+        for power in ["100", "200", "300"] {
+            client.send(reading(power));
+        }
+
+        assert_eq!(client.buffered(), 2);
+        assert_eq!(client.dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn delivery_is_reported() {
+        let (server, mut readings) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let (client, _events) = DeviceClient::new(server.local_addr().to_string()).start();
+
+        let delivered = timeout(Duration::from_secs(5), client.deliver(reading("1500")))
+            .await
+            .expect("delivered in time");
+
+        assert_eq!(delivered, Ok(()));
+        next_reading(&mut readings).await;
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn pushed_out_reading_is_reported_as_dropped() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let (client, _events) = DeviceClient::new(address.to_string()).buffer(1).start();
+
+        let first = client.deliver(reading("100"));
+        client.send(reading("200"));
+
+        assert_eq!(first.await, Err(DeliveryError::Dropped));
+    }
+
+    #[tokio::test]
+    async fn nack_is_reported_as_rejected() {
+        let (server, _readings) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .device_kinds([DeviceKind::Termometer])
+            .start()
+            .await
+            .unwrap();
+
+        let (client, _events) = DeviceClient::new(server.local_addr().to_string()).start();
+
+        let delivered = timeout(Duration::from_secs(5), client.deliver(reading("1500")))
+            .await
+            .expect("answered in time");
+
+        assert!(
+            matches!(
+                delivered,
+                Err(DeliveryError::Rejected {
+                    code: NackCode::Unsupported,
+                    ..
+                })
+            ),
+            "Socket readings are refused"
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn client_introduces_itself() {
+        let (server, _readings) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let hello = Hello {
+            kind: DeviceKind::Socket,
+            id: "socket-7".into(),
+            firmware: "1.0".into(),
+            protocols: PROTOCOL_VERSIONS.to_vec(),
+            capabilities: vec![Capability::Switch],
+        };
+
+        let (_client, mut events) = DeviceClient::new(server.local_addr().to_string())
+            .hello(hello)
+            .start();
+
+        while !matches!(
+            next_event(&mut events).await,
+            ClientEvent::Status(ConnectionStatus::Connected)
+        ) {}
+
+        let devices = server.registry().devices();
+        assert!(matches!(&devices[..], [info] if info.id == "socket-7"));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn command_reaches_client() {
+        let (server, mut readings) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let (client, mut events) = DeviceClient::new(server.local_addr().to_string()).start();
+        client.send(reading("1500"));
+        next_reading(&mut readings).await;
+
+        server
+            .send(&Command::new(DeviceKind::Socket, DeviceState::new(false)))
+            .unwrap();
+
+        loop {
+            if let ClientEvent::Command(request) = next_event(&mut events).await {
+                assert!(!request.state.get(), "Device is asked to switch off");
+                break;
+            }
+        }
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn config_reaches_client() {
+        let (server, mut readings) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let (client, mut events) = DeviceClient::new(server.local_addr().to_string()).start();
+        client.send(reading("1500"));
+        next_reading(&mut readings).await;
+
+        let config: DeviceConfig = "interval=1 deadband=0.5".parse().unwrap();
+        server
+            .registry()
+            .configure(DeviceKind::Socket, config)
+            .await;
+
+        loop {
+            if let ClientEvent::Config(received) = next_event(&mut events).await {
+                assert_eq!(received, config);
+                break;
+            }
+        }
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn device_confirms_command() {
+        let (server, _readings) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let hello = Hello {
+            kind: DeviceKind::Socket,
+            id: "socket-1".into(),
+            firmware: "1.0".into(),
+            protocols: PROTOCOL_VERSIONS.to_vec(),
+            capabilities: vec![Capability::Switch],
+        };
+
+        let (client, mut events) = DeviceClient::new(server.local_addr().to_string())
+            .hello(hello)
+            .start();
+
+        while !matches!(
+            next_event(&mut events).await,
+            ClientEvent::Status(ConnectionStatus::Connected)
+        ) {}
+
+        let confirmed = server.submit(&Command::new(DeviceKind::Socket, DeviceState::new(false)));
+
+        loop {
+            if let ClientEvent::Command(request) = next_event(&mut events).await {
+                assert!(request.id.is_some(), "Command carries an id");
+                client.reply(&request, Ok(request.state.clone()));
+                break;
+            }
+        }
+
+        let state = timeout(Duration::from_secs(5), confirmed)
+            .await
+            .expect("confirmed in time")
+            .unwrap();
+
+        assert!(!state.get(), "Device reports it is off");
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,104 @@
+// Helpers shared by the integration tests; each test crate uses only some.
+#![allow(dead_code)]
+
+use std::{io, time::Duration};
+
+use otus_iced::{
+    device::DeviceKind,
+    server::{DeviceServer, ServerHandle},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    task::JoinHandle,
+    time::timeout,
+};
+
+// Runs `serve` on a free local port, e.g. a Modbus simulator or a status
+// responder the server polls; returns the address and the serving task.
+pub async fn serve<F>(serve: impl FnOnce(TcpListener) -> F) -> (String, JoinHandle<io::Result<()>>)
+where
+    F: Future<Output = io::Result<()>> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+
+    (address, tokio::spawn(serve(listener)))
+}
+
+// Starts a server on a free local port with what `sources` adds to it, e.g.
+// the devices it polls.
+pub async fn start(sources: impl FnOnce(DeviceServer) -> DeviceServer) -> ServerHandle {
+    let (server, _events) = sources(DeviceServer::new().bind("127.0.0.1:0"))
+        .start()
+        .await
+        .unwrap();
+
+    server
+}
+
+pub async fn reading(server: &ServerHandle, device: DeviceKind, expected: &str) {
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let latest = server.registry().latest(device);
+
+            if latest.is_some_and(|sample| sample.reading.to_string() == expected) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("no reading {expected}"));
+}
+
+pub async fn connected(server: &ServerHandle, device: DeviceKind, expected: bool) {
+    timeout(Duration::from_secs(5), async {
+        while server.registry().is_connected(device) != expected {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .expect("connection changes in time");
+}
+
+// Stands in for an HTTP endpoint the server posts to, such as InfluxDB's
+// write endpoint or a webhook: takes one request and returns its head and
+// body after answering with `status`.
+pub async fn http_request(listener: &TcpListener, status: &str) -> (String, String) {
+    let (tcp, _) = timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .expect("request arrives in time")
+        .unwrap();
+    let mut reader = BufReader::new(tcp);
+
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+
+        if line == "\r\n" {
+            break;
+        }
+        head.push_str(&line);
+    }
+
+    let len: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).await.unwrap();
+
+    let response = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n");
+    reader
+        .get_mut()
+        .write_all(response.as_bytes())
+        .await
+        .unwrap();
+
+    (head, String::from_utf8(body).unwrap())
+}
@@ -0,0 +1,327 @@
+#[cfg(test)]
+mod http_tests {
+    use std::time::{Duration, SystemTime};
+
+    use jsonschema::Resource;
+    use otus_iced::{
+        device::DeviceKind,
+        server::{
+            CommandResource, DeviceResource, DeviceServer, ErrorResource, HealthResource, Limits,
+            ServerHandle,
+        },
+    };
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpStream,
+        time::timeout,
+    };
+
+    async fn start() -> ServerHandle {
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .http("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        server
+    }
+
+    fn schema(name: &str) -> serde_json::Value {
+        let path = format!("{}/schemas/{name}", env!("CARGO_MANIFEST_DIR"));
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    // Checks a body against its JSON Schema in `schemas/`.
+    fn assert_conforms(name: &str, body: &str) {
+        let device = Resource::from_contents(schema("device.json")).unwrap();
+        let validator = jsonschema::options()
+            .with_resource("json-schema:///device.json", device)
+            .build(&schema(name))
+            .unwrap();
+
+        let body = serde_json::from_str(body).unwrap();
+
+        if let Err(e) = validator.validate(&body) {
+            panic!("{body} does not match {name}: {e}");
+        }
+    }
+
+    // Sends one request and returns the status code and the body.
+    async fn request(server: &ServerHandle, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut tcp = TcpStream::connect(server.http_addr().unwrap())
+            .await
+            .unwrap();
+
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        tcp.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        timeout(Duration::from_secs(5), tcp.read_to_string(&mut response))
+            .await
+            .expect("response in time")
+            .unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+
+        (status, body.to_string())
+    }
+
+    #[tokio::test]
+    async fn health_is_reported() {
+        let server = start().await;
+
+        let (status, body) = request(&server, "GET", "/health", "").await;
+        assert_conforms("health.json", &body);
+        let health: HealthResource = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(status, 200);
+        assert_eq!(health.status, "ok");
+        assert_eq!(health.connected, 0);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn device_shows_latest_reading() {
+        let server = start().await;
+        let registry = server.registry();
+        registry.register(
+            "kind=socket id=socket-1 firmware=0.1.0 protocol=2 capabilities=switch,power"
+                .parse()
+                .unwrap(),
+        );
+        registry
+            .configure(
+                DeviceKind::Socket,
+                "interval=10 deadband=0.5 max_power=2000".parse().unwrap(),
+            )
+            .await;
+        registry.publish("Socket 1500W State: on".parse().unwrap());
+
+        let (status, body) = request(&server, "GET", "/devices/socket-1", "").await;
+        assert_conforms("device.json", &body);
+        let device: DeviceResource = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(status, 200);
+        assert_eq!(device.kind, "socket");
+
+        let reading = device.reading.expect("socket has reported");
+        assert!(reading.on);
+        assert_eq!(reading.power, Some(1500.0));
+        assert_eq!(reading.temperature, None);
+
+        let (status, body) = request(&server, "GET", "/devices", "").await;
+        assert_conforms("devices.json", &body);
+        let devices: Vec<DeviceResource> = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(status, 200);
+        assert_eq!(devices.len(), DeviceKind::ALL.len());
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn dashboard_page_is_served() {
+        let server = start().await;
+
+        let (status, body) = request(&server, "GET", "/", "").await;
+
+        assert_eq!(status, 200);
+        assert!(body.contains("<title>Устройства</title>"));
+        assert!(body.contains("/ws"), "Page follows the live feed");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn metrics_show_devices_and_counters() {
+        let server = start().await;
+
+        let mut device = TcpStream::connect(server.local_addr()).await.unwrap();
+        device
+            .write_all(b"Socket 1500W State: on\nnonsense\n")
+            .await
+            .unwrap();
+
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        server.registry().publish_stamped(
+            "Termometer 20C State: on".parse().unwrap(),
+            None,
+            Some(hour_ago),
+        );
+
+        while server.registry().counters().parse_failures == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let (status, body) = request(&server, "GET", "/metrics", "").await;
+        let lines: Vec<&str> = body.lines().collect();
+
+        assert_eq!(status, 200);
+        assert!(lines.contains(&"# TYPE otus_device_power_watts gauge"));
+        assert!(lines.contains(&"otus_device_connected{device=\"socket\"} 1"));
+        assert!(lines.contains(&"otus_device_connected{device=\"termometer\"} 0"));
+        assert!(lines.contains(&"otus_device_on{device=\"socket\"} 1"));
+        assert!(lines.contains(&"otus_device_power_watts{device=\"socket\"} 1500"));
+        assert!(lines.contains(&"otus_connections_accepted_total 1"));
+        assert!(lines.contains(&"otus_messages_parsed_total{type=\"reading\"} 1"));
+        assert!(lines.contains(&"otus_parse_failures_total 1"));
+        assert!(
+            lines
+                .iter()
+                .any(|line| line.starts_with("otus_device_last_seen_seconds{device=\"socket\"} "))
+        );
+        assert!(
+            !body.contains("otus_device_temperature_celsius{"),
+            "Termometer only uploaded an old reading"
+        );
+
+        let seen: f64 = lines
+            .iter()
+            .find_map(|line| {
+                line.strip_prefix("otus_device_last_seen_seconds{device=\"termometer\"} ")
+            })
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(seen < 60.0, "Seen when it arrived, not when it was taken");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn unknown_device_is_not_found() {
+        let server = start().await;
+
+        let (status, body) = request(&server, "GET", "/devices/kettle", "").await;
+
+        assert_eq!(status, 404);
+        assert_conforms("error.json", &body);
+        assert!(serde_json::from_str::<ErrorResource>(&body).is_ok());
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn command_for_disconnected_device_is_queued() {
+        let server = start().await;
+
+        let body = r#"{"state":"off"}"#;
+        assert_conforms("command.json", body);
+
+        let (status, body) = request(&server, "POST", "/devices/socket/commands", body).await;
+        assert_conforms("command-result.json", &body);
+        let command: CommandResource = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(status, 202);
+        assert_eq!(command.status, "queued");
+        assert_eq!(server.registry().pending(DeviceKind::Socket).len(), 1);
+
+        let (_, body) = request(&server, "GET", "/devices/socket", "").await;
+        assert_conforms("device.json", &body);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn command_waits_for_confirmation() {
+        let server = start().await;
+
+        let device = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (reader, mut writer) = device.into_split();
+        writer.write_all(b"Socket 1500W State: on\n").await.unwrap();
+
+        while !server.registry().is_connected(DeviceKind::Socket) {
+            tokio::task::yield_now().await;
+        }
+
+        let response = tokio::spawn({
+            let server = server.clone();
+
+            async move {
+                request(
+                    &server,
+                    "POST",
+                    "/devices/socket/commands",
+                    r#"{"state":"off"}"#,
+                )
+                .await
+            }
+        });
+
+        let mut line = String::new();
+        let mut reader = BufReader::new(reader);
+        timeout(Duration::from_secs(5), reader.read_line(&mut line))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(line, "SET off\n");
+        writer.write_all(b"Socket 0W State: off\n").await.unwrap();
+
+        let (status, body) = response.await.unwrap();
+        assert_conforms("command-result.json", &body);
+        let command: CommandResource = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(status, 200);
+        assert_eq!(command.status, "done");
+        assert_eq!(command.state, "off");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn slow_request_times_out() {
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .http("127.0.0.1:0")
+            .limits(Limits {
+                request_timeout: Duration::from_millis(100),
+                ..Limits::default()
+            })
+            .start()
+            .await
+            .unwrap();
+
+        let mut tcp = TcpStream::connect(server.http_addr().unwrap())
+            .await
+            .unwrap();
+        tcp.write_all(b"POST /devices/socket/commands HTTP/1.1\r\nContent-Length: 20\r\n\r\n{")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        timeout(Duration::from_secs(5), tcp.read_to_string(&mut response))
+            .await
+            .expect("response in time")
+            .unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 408 "), "{response}");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn malformed_command_is_refused() {
+        let server = start().await;
+
+        let (status, _) = request(&server, "POST", "/devices/socket/commands", "off").await;
+        assert_eq!(status, 400);
+
+        let (status, _) = request(
+            &server,
+            "POST",
+            "/devices/socket/commands",
+            r#"{"state":"dim"}"#,
+        )
+        .await;
+        assert_eq!(status, 422);
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,201 @@
+mod common;
+
+#[cfg(test)]
+mod influx_tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use otus_iced::server::{DeviceServer, InfluxConfig, InfluxSink, ServerHandle};
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader},
+        net::{TcpListener, UdpSocket},
+        time::timeout,
+    };
+
+    use crate::common::http_request;
+
+    async fn start(influx: InfluxConfig) -> ServerHandle {
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .influx(influx)
+            .start()
+            .await
+            .unwrap();
+
+        server
+    }
+
+    #[test]
+    fn sinks_and_lines_are_written_as_influx_expects() {
+        assert_eq!(
+            "udp://localhost:8089".parse::<InfluxSink>().unwrap(),
+            InfluxSink::Udp("localhost:8089".into())
+        );
+        assert_eq!(
+            "http://localhost:8086".parse::<InfluxSink>().unwrap(),
+            InfluxSink::Http {
+                address: "localhost:8086".into(),
+                path: "/api/v2/write".into(),
+            }
+        );
+        assert!("ftp://localhost".parse::<InfluxSink>().is_err());
+
+        let influx = InfluxConfig::new(InfluxSink::File("readings.lp".into()));
+        let at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+
+        assert_eq!(
+            influx.line(&"Termometer 21.5C State: on".parse().unwrap(), at),
+            "devices,device=termometer on=true,temperature=21.5 1700000000000000000"
+        );
+    }
+
+    #[tokio::test]
+    async fn readings_are_sent_to_ipv6_udp_sink() {
+        let sink = UdpSocket::bind("[::1]:0").await.unwrap();
+
+        let mut influx = InfluxConfig::new(InfluxSink::Udp(sink.local_addr().unwrap().to_string()));
+        influx.flush_interval = Duration::from_millis(20);
+
+        let server = start(influx).await;
+        server
+            .registry()
+            .publish("Socket 1500W State: on".parse().unwrap());
+
+        let mut datagram = vec![0; 1024];
+        let len = timeout(Duration::from_secs(5), sink.recv(&mut datagram))
+            .await
+            .expect("datagram in time")
+            .unwrap();
+        let datagram = String::from_utf8_lossy(&datagram[..len]);
+
+        assert!(
+            datagram.starts_with("devices,device=socket on=true,power=1500 "),
+            "{datagram}"
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn readings_stream_to_tcp_sink() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut influx =
+            InfluxConfig::new(InfluxSink::Tcp(listener.local_addr().unwrap().to_string()));
+        influx.flush_interval = Duration::from_millis(20);
+
+        let server = start(influx).await;
+        server
+            .registry()
+            .publish("Socket 1500W State: on".parse().unwrap());
+
+        let (tcp, _) = timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut lines = BufReader::new(tcp).lines();
+
+        let line = timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        assert!(
+            line.starts_with("devices,device=socket on=true,power=1500 "),
+            "{line}"
+        );
+
+        server
+            .registry()
+            .publish("Socket 0W State: off".parse().unwrap());
+
+        let line = timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        assert!(line.starts_with("devices,device=socket on=false,power=0 "));
+
+        let taken = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        server.registry().publish_stamped(
+            "Termometer 21.5C State: on".parse().unwrap(),
+            None,
+            Some(taken),
+        );
+
+        let line = timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            line, "devices,device=termometer on=true,temperature=21.5 1700000000000000000",
+            "Backfilled reading is stored with the time it was taken"
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn failed_batch_is_retried_with_oldest_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut influx = InfluxConfig::new(
+            format!(
+                "http://{}/api/v2/write?bucket=house",
+                listener.local_addr().unwrap()
+            )
+            .parse()
+            .unwrap(),
+        );
+        influx.token = Some("secret".into());
+        influx.flush_interval = Duration::from_millis(20);
+        influx.retry_delay = Duration::from_millis(300);
+        influx.buffer = 2;
+
+        let server = start(influx).await;
+        let registry = server.registry();
+
+        registry.publish("Socket 100W State: on".parse().unwrap());
+
+        let (head, body) = http_request(&listener, "503 Service Unavailable").await;
+
+        assert!(head.starts_with("POST /api/v2/write?bucket=house HTTP/1.1\r\n"));
+        assert!(head.contains("Authorization: Token secret\r\n"));
+        assert!(body.contains("power=100 "));
+
+        registry.publish("Socket 200W State: on".parse().unwrap());
+        registry.publish("Socket 300W State: on".parse().unwrap());
+
+        let (_, body) = http_request(&listener, "204 No Content").await;
+        let lines: Vec<&str> = body.lines().collect();
+
+        assert_eq!(lines.len(), 2, "Buffer holds two lines: {body}");
+        assert!(lines[0].contains("power=200 "));
+        assert!(lines[1].contains("power=300 "));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn buffered_lines_are_flushed_on_shutdown() {
+        let path = std::env::temp_dir().join(format!("otus-influx-{}.lp", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut influx = InfluxConfig::new(InfluxSink::File(path.clone()));
+        influx.flush_interval = Duration::from_secs(60);
+
+        let server = start(influx).await;
+        server
+            .registry()
+            .publish("Termometer 21.5C State: on".parse().unwrap());
+        server.shutdown().await;
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert!(written.starts_with("devices,device=termometer on=true,temperature=21.5 "));
+    }
+}
@@ -0,0 +1,189 @@
+mod common;
+
+#[cfg(test)]
+mod modbus_tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use otus_iced::{
+        device::{Capability, Command, DeviceKind},
+        modbus::ModbusSimulator,
+        server::{CommandError, DeviceServer, ModbusConfig, ModbusRegister},
+        state::DeviceState,
+    };
+    use tokio::{net::TcpListener, time::timeout};
+
+    use crate::common::{connected, reading, serve, start};
+
+    #[test]
+    fn registers_are_parsed() {
+        for register in ["holding:0", "input:3*0.1", "holding:40001*0.5"] {
+            let parsed: ModbusRegister = register.parse().unwrap();
+            assert_eq!(parsed.to_string(), register);
+        }
+
+        assert_eq!(
+            "holding:7".parse::<ModbusRegister>().unwrap(),
+            ModbusRegister::holding(7)
+        );
+        assert_ne!(
+            "holding:7*0.5".parse::<ModbusRegister>().unwrap(),
+            ModbusRegister::holding(7)
+        );
+        assert!("coil:1".parse::<ModbusRegister>().is_err());
+        assert!("input:x".parse::<ModbusRegister>().is_err());
+    }
+
+    #[tokio::test]
+    async fn socket_reads_registers_and_switches_the_coil() {
+        let simulator = ModbusSimulator::new();
+        simulator.set_holding(0, 1500);
+        simulator.set_coil(3, true);
+        let (address, _serving) = serve(|listener| simulator.clone().serve(listener)).await;
+
+        let mut modbus = ModbusConfig::new(DeviceKind::Socket, address);
+        modbus.coil = Some(3);
+        modbus.interval = Duration::from_millis(20);
+
+        let server = start(|server| server.modbus(modbus)).await;
+        reading(&server, DeviceKind::Socket, "Socket 1500W State: on").await;
+
+        let off = Command::new(DeviceKind::Socket, DeviceState::new(false));
+        let state = timeout(Duration::from_secs(5), server.submit(&off))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(!state.get());
+        assert!(!simulator.coil(3), "Command wrote the coil");
+
+        simulator.set_holding(0, 0);
+        reading(&server, DeviceKind::Socket, "Socket 0W State: off").await;
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn termometer_without_coil_is_scaled_and_refuses_commands() {
+        let simulator = ModbusSimulator::new();
+        simulator.set_input(2, -55i16 as u16);
+        let (address, _serving) = serve(|listener| simulator.clone().serve(listener)).await;
+
+        let mut modbus = ModbusConfig::new(DeviceKind::Termometer, address);
+        modbus.register = "input:2*0.5".parse().unwrap();
+        modbus.interval = Duration::from_millis(20);
+
+        let server = start(|server| server.modbus(modbus)).await;
+        reading(
+            &server,
+            DeviceKind::Termometer,
+            "Termometer -27.500C State: on",
+        )
+        .await;
+
+        let info = server
+            .registry()
+            .devices()
+            .into_iter()
+            .find(|info| info.kind == DeviceKind::Termometer)
+            .unwrap();
+
+        assert!(info.can(Capability::ReportsTemperature));
+        assert!(!info.can(Capability::Switch));
+
+        let off = Command::new(DeviceKind::Termometer, DeviceState::new(false));
+        assert!(matches!(
+            server.submit(&off).await,
+            Err(CommandError::Unsupported(DeviceKind::Termometer))
+        ));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn second_source_for_a_device_is_refused() {
+        let first = ModbusSimulator::new();
+        first.set_holding(0, 100);
+        let (first_address, _first) = serve(|listener| first.clone().serve(listener)).await;
+
+        // The second simulator starts serving once the first one is attached.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second_address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let mut modbus = ModbusConfig::new(DeviceKind::Socket, first_address.clone());
+        modbus.interval = Duration::from_millis(20);
+
+        let mut other = ModbusConfig::new(DeviceKind::Socket, second_address.clone());
+        other.interval = Duration::from_millis(20);
+        other.reconnect_delay = Duration::from_millis(20);
+
+        let (server, mut events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .modbus(modbus)
+            .modbus(other)
+            .start()
+            .await
+            .unwrap();
+        reading(&server, DeviceKind::Socket, "Socket 100W State: on").await;
+
+        let second = ModbusSimulator::new();
+        second.set_holding(0, 900);
+        let listener = TcpListener::bind(&second_address).await.unwrap();
+        let _second = tokio::spawn(second.clone().serve(listener));
+
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let frame = events.next().await.expect("server is running");
+
+                if frame
+                    .events
+                    .iter()
+                    .any(|event| event.to_string().contains("другого источника"))
+                {
+                    return;
+                }
+            }
+        })
+        .await
+        .expect("second source is refused");
+
+        let info = server
+            .registry()
+            .devices()
+            .into_iter()
+            .find(|info| info.kind == DeviceKind::Socket)
+            .unwrap();
+
+        assert_eq!(info.id, format!("modbus-{first_address}/1"));
+        reading(&server, DeviceKind::Socket, "Socket 100W State: on").await;
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn lost_device_is_detached_and_polled_again() {
+        let simulator = ModbusSimulator::new();
+        simulator.set_holding(0, 100);
+        let (address, serving) = serve(|listener| simulator.clone().serve(listener)).await;
+
+        let mut modbus = ModbusConfig::new(DeviceKind::Socket, address.clone());
+        modbus.interval = Duration::from_millis(20);
+        modbus.reconnect_delay = Duration::from_millis(20);
+
+        let server = start(|server| server.modbus(modbus)).await;
+        connected(&server, DeviceKind::Socket, true).await;
+
+        serving.abort();
+        connected(&server, DeviceKind::Socket, false).await;
+
+        simulator.set_holding(0, 200);
+        let listener = TcpListener::bind(&address).await.unwrap();
+        let _serving = tokio::spawn(simulator.clone().serve(listener));
+
+        connected(&server, DeviceKind::Socket, true).await;
+        reading(&server, DeviceKind::Socket, "Socket 200W State: on").await;
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,331 @@
+#[cfg(test)]
+mod mqtt_tests {
+    use std::time::Duration;
+
+    use otus_iced::{
+        device::DeviceKind,
+        server::{DeviceServer, MqttConfig, QoS, ServerHandle},
+    };
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+        time::timeout,
+    };
+
+    // Stands in for a broker: accepts the bridge, acknowledges its CONNECT
+    // and SUBSCRIBE and lets the test see and send PUBLISH packets.
+    struct Broker {
+        tcp: TcpStream,
+        subscriptions: Vec<String>,
+        last_id: Option<[u8; 2]>,
+    }
+
+    impl Broker {
+        async fn accept(listener: &TcpListener) -> Self {
+            let (mut tcp, _) = timeout(Duration::from_secs(5), listener.accept())
+                .await
+                .expect("bridge connects in time")
+                .unwrap();
+
+            let (header, _) = read_packet(&mut tcp).await;
+            assert_eq!(header, 0x10, "CONNECT comes first");
+            tcp.write_all(&[0x20, 2, 0, 0]).await.unwrap();
+
+            let (header, body) = read_packet(&mut tcp).await;
+            assert_eq!(header, 0x82, "then SUBSCRIBE");
+
+            let mut subscriptions = Vec::new();
+            let mut rest = &body[2..];
+
+            while !rest.is_empty() {
+                let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                subscriptions.push(String::from_utf8(rest[2..2 + len].to_vec()).unwrap());
+                rest = &rest[2 + len + 1..];
+            }
+
+            tcp.write_all(&[0x90, 3, body[0], body[1], 0])
+                .await
+                .unwrap();
+
+            Self {
+                tcp,
+                subscriptions,
+                last_id: None,
+            }
+        }
+
+        // The next PUBLISH from the bridge as topic and payload.
+        async fn next_publish(&mut self) -> (String, String) {
+            let (_, topic, payload) = self.next_packet().await;
+            (topic, payload)
+        }
+
+        // Like `next_publish`, with the fixed header.
+        async fn next_packet(&mut self) -> (u8, String, String) {
+            loop {
+                let (header, body) = read_packet(&mut self.tcp).await;
+
+                if header & 0xF0 != 0x30 {
+                    continue;
+                }
+
+                let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                let topic = String::from_utf8(body[2..2 + len].to_vec()).unwrap();
+                let id_len = if header & 0x06 != 0 { 2 } else { 0 };
+                let payload = String::from_utf8(body[2 + len + id_len..].to_vec()).unwrap();
+
+                if id_len != 0 {
+                    self.last_id = Some([body[2 + len], body[3 + len]]);
+                }
+
+                return (header, topic, payload);
+            }
+        }
+
+        // Acknowledges the last QoS 1 publish.
+        async fn puback(&mut self) {
+            let [high, low] = self.last_id.expect("QoS 1 publish");
+            self.tcp.write_all(&[0x40, 2, high, low]).await.unwrap();
+        }
+
+        async fn wait_for(&mut self, topic: &str) -> String {
+            loop {
+                let (published, payload) = self.next_publish().await;
+
+                if published == topic {
+                    return payload;
+                }
+            }
+        }
+
+        async fn publish(&mut self, topic: &str, payload: &str) {
+            let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+            body.extend_from_slice(topic.as_bytes());
+            body.extend_from_slice(payload.as_bytes());
+
+            let mut packet = vec![0x30, body.len() as u8];
+            packet.extend(body);
+
+            self.tcp.write_all(&packet).await.unwrap();
+        }
+    }
+
+    async fn read_packet(tcp: &mut TcpStream) -> (u8, Vec<u8>) {
+        timeout(Duration::from_secs(5), async {
+            let header = tcp.read_u8().await.unwrap();
+
+            let mut len = 0;
+            for shift in (0..4).map(|i| i * 7) {
+                let byte = tcp.read_u8().await.unwrap();
+                len |= ((byte & 0x7F) as usize) << shift;
+
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+
+            let mut body = vec![0; len];
+            tcp.read_exact(&mut body).await.unwrap();
+
+            (header, body)
+        })
+        .await
+        .expect("packet in time")
+    }
+
+    async fn start(mqtt: impl FnOnce(&mut MqttConfig)) -> (ServerHandle, TcpListener) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut config = MqttConfig::new(listener.local_addr().unwrap().to_string());
+        config.reconnect_delay = Duration::from_millis(50);
+        mqtt(&mut config);
+
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .mqtt(config)
+            .start()
+            .await
+            .unwrap();
+
+        (server, listener)
+    }
+
+    #[tokio::test]
+    async fn readings_are_published_to_device_topics() {
+        let (server, listener) = start(|mqtt| {
+            mqtt.rooms.insert(DeviceKind::Socket, "kitchen".into());
+        })
+        .await;
+
+        let mut broker = Broker::accept(&listener).await;
+        assert!(
+            broker
+                .subscriptions
+                .contains(&"home/kitchen/socket/set".to_string())
+        );
+        assert_eq!(broker.wait_for("home/server/status").await, "online");
+
+        server
+            .registry()
+            .publish("Socket 1500W State: on".parse().unwrap());
+
+        assert_eq!(broker.wait_for("home/kitchen/socket/state").await, "on");
+        assert_eq!(broker.wait_for("home/kitchen/socket/power").await, "1500");
+
+        server
+            .registry()
+            .publish("Termometer 21.5C State: on".parse().unwrap());
+
+        assert_eq!(
+            broker.wait_for("home/default/termometer/temperature").await,
+            "21.5"
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn home_assistant_discovery_is_published() {
+        let (server, listener) = start(|mqtt| {
+            mqtt.discovery = Some("homeassistant".into());
+        })
+        .await;
+
+        let mut broker = Broker::accept(&listener).await;
+
+        let switch = broker
+            .wait_for("homeassistant/switch/otus-iced/socket/config")
+            .await;
+        let switch: serde_json::Value = serde_json::from_str(&switch).unwrap();
+
+        assert_eq!(switch["command_topic"], "home/default/socket/set");
+        assert_eq!(switch["state_topic"], "home/default/socket/state");
+
+        let power = broker
+            .wait_for("homeassistant/sensor/otus-iced/socket_power/config")
+            .await;
+        let power: serde_json::Value = serde_json::from_str(&power).unwrap();
+
+        assert_eq!(power["device_class"], "power");
+        assert_eq!(power["unit_of_measurement"], "W");
+        assert_eq!(
+            power["device"], switch["device"],
+            "One device in Home Assistant"
+        );
+
+        let temperature = broker
+            .wait_for("homeassistant/sensor/otus-iced/termometer_temperature/config")
+            .await;
+        let temperature: serde_json::Value = serde_json::from_str(&temperature).unwrap();
+
+        assert_eq!(temperature["device_class"], "temperature");
+        assert_eq!(temperature["unit_of_measurement"], "°C");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn set_topic_switches_socket() {
+        let (server, listener) = start(|_| {}).await;
+        let mut broker = Broker::accept(&listener).await;
+
+        let device = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (reader, mut writer) = device.into_split();
+        writer.write_all(b"Socket 1500W State: on\n").await.unwrap();
+
+        broker.wait_for("home/default/socket/state").await;
+        broker.publish("home/default/socket/set", "off").await;
+
+        let mut line = String::new();
+        let mut reader = BufReader::new(reader);
+        timeout(Duration::from_secs(5), reader.read_line(&mut line))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(line, "SET off\n");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn bridge_reconnects_after_broker_drops() {
+        let (server, listener) = start(|_| {}).await;
+
+        let broker = Broker::accept(&listener).await;
+        drop(broker);
+
+        let mut broker = Broker::accept(&listener).await;
+        broker.wait_for("home/server/status").await;
+
+        server
+            .registry()
+            .publish("Socket 800W State: on".parse().unwrap());
+
+        assert_eq!(broker.wait_for("home/default/socket/power").await, "800");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn zero_keep_alive_turns_pings_off() {
+        let (server, listener) = start(|mqtt| mqtt.keep_alive = Duration::ZERO).await;
+
+        let mut broker = Broker::accept(&listener).await;
+        assert_eq!(broker.wait_for("home/server/status").await, "online");
+
+        server
+            .registry()
+            .publish("Socket 800W State: on".parse().unwrap());
+
+        assert_eq!(broker.wait_for("home/default/socket/power").await, "800");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_publishes_are_sent_again_after_reconnect() {
+        let (server, listener) = start(|mqtt| mqtt.qos = QoS::AtLeastOnce).await;
+
+        let mut broker = Broker::accept(&listener).await;
+        assert_eq!(broker.wait_for("home/server/status").await, "online");
+        broker.puback().await;
+
+        // Packets are handled in order: once the command is queued the
+        // PUBACK before it was seen too.
+        broker.publish("home/default/socket/set", "off").await;
+        timeout(Duration::from_secs(5), async {
+            while server.registry().pending(DeviceKind::Socket).is_empty() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("command queued in time");
+
+        server
+            .registry()
+            .publish("Socket 800W State: on".parse().unwrap());
+        assert_eq!(broker.wait_for("home/default/socket/power").await, "800");
+        drop(broker);
+
+        let mut broker = Broker::accept(&listener).await;
+        let (header, topic, payload) = broker.next_packet().await;
+
+        assert_eq!(header & 0x0E, 0x0A, "QoS 1 publish sent again with DUP");
+        assert_eq!(
+            (topic.as_str(), payload.as_str()),
+            ("home/default/socket/state", "on"),
+            "Acknowledged status is not sent again"
+        );
+        assert_eq!(
+            broker.next_publish().await,
+            ("home/default/socket/power".into(), "800".into())
+        );
+
+        let (header, topic, _) = broker.next_packet().await;
+        assert_eq!(header & 0x08, 0, "New publishes are not duplicates");
+        assert_eq!(topic, "home/server/status");
+
+        server.shutdown().await;
+    }
+}
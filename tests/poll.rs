@@ -0,0 +1,152 @@
+mod common;
+
+#[cfg(test)]
+mod poll_tests {
+    use std::time::Duration;
+
+    use otus_iced::{
+        device::{Capability, Command, DeviceKind},
+        server::{CommandError, PollConfig},
+        state::DeviceState,
+        status::StatusResponder,
+    };
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+        time::timeout,
+    };
+
+    use crate::common::{connected, reading, serve, start};
+
+    fn polled(address: String) -> PollConfig {
+        let mut poll = PollConfig::new(address);
+        poll.interval = Duration::from_millis(20);
+        poll.reconnect_delay = Duration::from_millis(20);
+        poll
+    }
+
+    #[tokio::test]
+    async fn responder_answers_status_queries() {
+        let responder = StatusResponder::new();
+        let (address, _serving) = serve(|listener| responder.clone().serve(listener)).await;
+
+        let tcp = TcpStream::connect(address).await.unwrap();
+        let mut lines = BufReader::new(tcp);
+        let mut ask = async |query: &str| {
+            lines
+                .get_mut()
+                .write_all(format!("{query}\n").as_bytes())
+                .await
+                .unwrap();
+
+            let mut line = String::new();
+            lines.read_line(&mut line).await.unwrap();
+            line
+        };
+
+        assert_eq!(ask("status?").await, "ERROR нет показаний\n");
+
+        responder.set("Socket 1500W State: on".parse().unwrap());
+        assert_eq!(ask("status?").await, "Socket 1500W State: on\n");
+        assert_eq!(ask("hello").await, "ERROR неизвестный запрос\n");
+    }
+
+    #[tokio::test]
+    async fn polled_device_reports_but_takes_no_commands() {
+        let responder = StatusResponder::new();
+        responder.set("Socket 1500W State: on".parse().unwrap());
+        let (address, _serving) = serve(|listener| responder.clone().serve(listener)).await;
+
+        let server = start(|server| server.poll(polled(address.clone()))).await;
+        reading(&server, DeviceKind::Socket, "Socket 1500W State: on").await;
+
+        responder.set("Socket 0W State: off".parse().unwrap());
+        reading(&server, DeviceKind::Socket, "Socket 0W State: off").await;
+
+        let info = server
+            .registry()
+            .devices()
+            .into_iter()
+            .find(|info| info.kind == DeviceKind::Socket)
+            .unwrap();
+
+        assert_eq!(info.id, format!("poll-{address}"));
+        assert!(!info.can(Capability::Switch));
+
+        let on = Command::new(DeviceKind::Socket, DeviceState::new(true));
+        assert!(matches!(
+            server.submit(&on).await,
+            Err(CommandError::Unsupported(DeviceKind::Socket))
+        ));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn unparsable_answer_is_rejected_and_polling_goes_on() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = start(|server| server.poll(polled(address))).await;
+
+        let (tcp, _) = timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .expect("server connects in time")
+            .unwrap();
+        let mut lines = BufReader::new(tcp);
+
+        for answer in ["nonsense", "Termometer 21.5C State: on"] {
+            let mut query = String::new();
+            lines.read_line(&mut query).await.unwrap();
+            assert_eq!(query, "status?\n");
+
+            lines
+                .get_mut()
+                .write_all(format!("{answer}\n").as_bytes())
+                .await
+                .unwrap();
+        }
+
+        reading(
+            &server,
+            DeviceKind::Termometer,
+            "Termometer 21.500C State: on",
+        )
+        .await;
+        assert_eq!(server.registry().counters().parse_failures, 1);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn lost_responder_is_detached_and_polled_again() {
+        let responder = StatusResponder::new();
+        responder.set("Termometer 20C State: on".parse().unwrap());
+        let (address, serving) = serve(|listener| responder.clone().serve(listener)).await;
+
+        let server = start(|server| server.poll(polled(address.clone()))).await;
+        reading(
+            &server,
+            DeviceKind::Termometer,
+            "Termometer 20.000C State: on",
+        )
+        .await;
+
+        serving.abort();
+
+        connected(&server, DeviceKind::Termometer, false).await;
+
+        responder.set("Termometer 22C State: on".parse().unwrap());
+        let listener = TcpListener::bind(&address).await.unwrap();
+        let _serving = tokio::spawn(responder.clone().serve(listener));
+
+        reading(
+            &server,
+            DeviceKind::Termometer,
+            "Termometer 22.000C State: on",
+        )
+        .await;
+        assert!(server.registry().is_connected(DeviceKind::Termometer));
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,866 @@
+#[cfg(test)]
+mod server_tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use futures::StreamExt;
+    use otus_iced::{
+        device::{Command, DeviceConfig, DeviceKind, SensorData},
+        server::{
+            CommandError, DeviceServer, Dispatch, EventStream, Frame, Limits, SequenceStats,
+            ServerEvent,
+        },
+        state::DeviceState,
+    };
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpStream,
+        time::timeout,
+    };
+
+    async fn next_frame(events: &mut EventStream) -> Frame {
+        timeout(Duration::from_secs(5), events.next())
+            .await
+            .expect("frame in time")
+            .expect("server is running")
+    }
+
+    async fn next_reading(events: &mut EventStream) -> SensorData {
+        loop {
+            if let Some(reading) = next_frame(events).await.readings.pop() {
+                return reading;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reading_reaches_event_stream() {
+        let (server, mut events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let mut device = TcpStream::connect(server.local_addr()).await.unwrap();
+        device
+            .write_all(b"Termometer 21.5C State: on\n")
+            .await
+            .unwrap();
+
+        let frame = next_frame(&mut events).await;
+
+        assert!(
+            matches!(&frame.readings[..], [SensorData::TermoIndicator(t)] if t.temperature().get() == 21.5),
+            "Temperature is correct"
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn reading_without_newline_is_accepted() {
+        let (server, mut events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let mut device = TcpStream::connect(server.local_addr()).await.unwrap();
+        device.write_all(b"Socket 1500W State: on").await.unwrap();
+        drop(device);
+
+        assert!(
+            matches!(next_reading(&mut events).await, SensorData::SocketIndicator(s) if s.power().get() == 1500.0),
+            "Power is correct"
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn readings_are_coalesced_between_frames() {
+        let (server, mut events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .frame_interval(Duration::from_millis(200))
+            .start()
+            .await
+            .unwrap();
+
+        let mut device = TcpStream::connect(server.local_addr()).await.unwrap();
+        device
+            .write_all(b"Socket 500W State: on\nSocket 700W State: on\nSocket 900W State: on\n")
+            .await
+            .unwrap();
+        drop(device);
+
+        let mut power = 0.0;
+        let mut coalesced = 0;
+
+        while power != 900.0 {
+            let frame = next_frame(&mut events).await;
+
+            if let Some(SensorData::SocketIndicator(s)) = frame.readings.last() {
+                power = s.power().get();
+            }
+            coalesced = frame.stats.coalesced;
+        }
+
+        assert!(coalesced > 0, "... older readings were coalesced");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn flooding_device_is_counted_as_coalesced_and_dropped() {
+        let (server, mut events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .limits(Limits {
+                event_queue: 4,
+                ..Limits::default()
+            })
+            .start()
+            .await
+            .unwrap();
+
+        // Twenty readings switching on and off, nobody reading frames.
+        let flood: String = (0..20)
+            .map(|i| match i % 2 {
+                0 => "Socket 500W State: on\n",
+                _ => "Socket 0W State: off\n",
+            })
+            .collect();
+
+        let mut device = TcpStream::connect(server.local_addr()).await.unwrap();
+        device.write_all(flood.as_bytes()).await.unwrap();
+
+        timeout(Duration::from_secs(5), async {
+            while server.registry().counters().readings < 20 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("flood is read in time");
+
+        let frame = next_frame(&mut events).await;
+
+        assert_eq!(frame.readings.len(), 1, "One reading per device");
+        assert_eq!(frame.stats.coalesced, 19);
+        assert_eq!(frame.events.len(), 4, "Event queue holds four");
+        assert_eq!(frame.stats.dropped, 15, "19 toggles, 4 kept");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn command_is_delivered_to_connected_device() {
+        let (server, mut events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .limits(Limits {
+                command_ttl: Duration::ZERO,
+                ..Limits::default()
+            })
+            .start()
+            .await
+            .unwrap();
+
+        let command = Command::new(DeviceKind::Socket, DeviceState::new(false));
+
+        assert!(
+            server.send(&command).is_err(),
+            "Socket is not connected yet"
+        );
+
+        let device = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (reader, mut writer) = device.into_split();
+        writer.write_all(b"Socket 1500W State: on\n").await.unwrap();
+        next_reading(&mut events).await;
+
+        server.send(&command).unwrap();
+
+        let mut line = String::new();
+        let mut reader = BufReader::new(reader);
+        timeout(Duration::from_secs(5), reader.read_line(&mut line))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(line, "SET off\n");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn queued_command_is_delivered_on_connect() {
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let command = Command::new(DeviceKind::Socket, DeviceState::new(false));
+        let delivered = server.submit(&command);
+
+        assert_eq!(server.registry().pending(DeviceKind::Socket).len(), 1);
+
+        let device = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (reader, mut writer) = device.into_split();
+        writer.write_all(b"Socket 1500W State: on\n").await.unwrap();
+
+        let mut line = String::new();
+        let mut reader = BufReader::new(reader);
+        timeout(Duration::from_secs(5), reader.read_line(&mut line))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(line, "SET off\n");
+
+        // A device without command ids confirms by reporting the new state.
+        writer.write_all(b"Socket 0W State: off\n").await.unwrap();
+
+        let delivered = timeout(Duration::from_secs(5), delivered)
+            .await
+            .expect("confirmed in time");
+        assert!(
+            delivered.is_ok(),
+            "Issuer learns the command was carried out"
+        );
+        assert!(server.registry().pending(DeviceKind::Socket).is_empty());
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn reading_taken_before_the_command_does_not_confirm_it() {
+        let (server, mut events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let device = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (reader, mut writer) = device.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        writer.write_all(b"Socket 0W State: off\n").await.unwrap();
+        next_reading(&mut events).await;
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let taken = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let command = Command::new(DeviceKind::Socket, DeviceState::new(false));
+        let mut confirmed = Box::pin(server.submit(&command));
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "SET off");
+
+        writer
+            .write_all(format!("Socket 0W State: off | seq=1 ts={taken}\n").as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "ACK 1");
+        assert!(
+            timeout(Duration::from_millis(100), &mut confirmed)
+                .await
+                .is_err(),
+            "Reading was taken before the device got the command"
+        );
+
+        writer.write_all(b"Socket 0W State: off\n").await.unwrap();
+        let confirmed = timeout(Duration::from_secs(5), confirmed)
+            .await
+            .expect("confirmed in time");
+        assert!(confirmed.is_ok());
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn device_with_command_ids_confirms_by_id_only() {
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let device = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (reader, mut writer) = device.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        writer
+            .write_all(
+                b"HELLO kind=socket id=s-1 firmware=2.0 protocols=1,2 capabilities=switch,power\n",
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "WELCOME protocol=2"
+        );
+
+        let command = Command::new(DeviceKind::Socket, DeviceState::new(false));
+        let mut confirmed = Box::pin(server.submit(&command));
+
+        let line = lines.next_line().await.unwrap().unwrap();
+        let id = line.strip_prefix("SET off | id=").expect("command with id");
+
+        writer.write_all(b"Socket 0W State: off\n").await.unwrap();
+        assert!(
+            timeout(Duration::from_millis(100), &mut confirmed)
+                .await
+                .is_err(),
+            "Device answers by id"
+        );
+
+        writer
+            .write_all(format!("DONE {id} off\n").as_bytes())
+            .await
+            .unwrap();
+        let confirmed = timeout(Duration::from_secs(5), confirmed)
+            .await
+            .expect("confirmed in time");
+        assert!(confirmed.is_ok());
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn queued_command_expires() {
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .limits(Limits {
+                command_ttl: Duration::from_millis(100),
+                ..Limits::default()
+            })
+            .start()
+            .await
+            .unwrap();
+
+        let command = Command::new(DeviceKind::Socket, DeviceState::new(false));
+
+        assert_eq!(server.send(&command).unwrap(), Dispatch::Queued);
+
+        let expired = timeout(Duration::from_secs(5), server.submit(&command))
+            .await
+            .expect("expired in time");
+
+        assert!(matches!(
+            expired,
+            Err(CommandError::Expired(DeviceKind::Socket))
+        ));
+        assert!(server.registry().pending(DeviceKind::Socket).is_empty());
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_command_times_out() {
+        let (server, mut events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .limits(Limits {
+                command_timeout: Duration::from_millis(100),
+                ..Limits::default()
+            })
+            .start()
+            .await
+            .unwrap();
+
+        let mut device = TcpStream::connect(server.local_addr()).await.unwrap();
+        device.write_all(b"Socket 1500W State: on\n").await.unwrap();
+        next_reading(&mut events).await;
+
+        let command = Command::new(DeviceKind::Socket, DeviceState::new(false));
+        let outcome = timeout(Duration::from_secs(5), server.submit(&command))
+            .await
+            .expect("settled in time");
+
+        assert!(matches!(
+            outcome,
+            Err(CommandError::TimedOut(DeviceKind::Socket))
+        ));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn config_is_pushed_on_connect_and_saved() {
+        let path = std::env::temp_dir().join(format!("otus-iced-{}.conf", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .config_file(&path)
+            .start()
+            .await
+            .unwrap();
+
+        let config: DeviceConfig = "interval=5 deadband=10 max_power=2000".parse().unwrap();
+        server
+            .registry()
+            .configure(DeviceKind::Socket, config)
+            .await;
+
+        let device = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (reader, mut writer) = device.into_split();
+        writer.write_all(b"Socket 1500W State: on\n").await.unwrap();
+
+        let mut line = String::new();
+        let mut reader = BufReader::new(reader);
+        timeout(Duration::from_secs(5), reader.read_line(&mut line))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(line, "CONFIG interval=5 deadband=10 max_power=2000\n");
+
+        server.shutdown().await;
+
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .config_file(&path)
+            .start()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            server.registry().config(DeviceKind::Socket),
+            Some(config),
+            "Configuration survives a restart"
+        );
+
+        server.shutdown().await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn sequenced_reading_is_acknowledged_once() {
+        let (server, mut events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let device = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (reader, mut writer) = device.into_split();
+        let mut replies = BufReader::new(reader).lines();
+
+        writer
+            .write_all(b"Socket 1500W State: on | seq=5\nSocket 1500W State: on | seq=5\nSocket 1500W | seq=6\n")
+            .await
+            .unwrap();
+
+        for expected in ["ACK 5", "ACK 5"] {
+            let reply = timeout(Duration::from_secs(5), replies.next_line())
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(reply.as_deref(), Some(expected));
+        }
+
+        let nack = replies.next_line().await.unwrap().unwrap();
+        assert!(
+            nack.starts_with("NACK 6 400 "),
+            "Malformed reading is refused"
+        );
+
+        let mut duplicates = 0;
+
+        while duplicates == 0 {
+            duplicates = next_frame(&mut events).await.stats.duplicates;
+        }
+
+        assert_eq!(duplicates, 1, "Retry is not applied twice");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn stale_reading_does_not_replace_newer_one() {
+        let (server, mut events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .frame_interval(Duration::from_millis(100))
+            .start()
+            .await
+            .unwrap();
+
+        let mut device = TcpStream::connect(server.local_addr()).await.unwrap();
+        device
+            .write_all(b"Socket 100W State: on | seq=1\nSocket 300W State: on | seq=3\nSocket 200W State: on | seq=2\n")
+            .await
+            .unwrap();
+
+        let mut stats = SequenceStats::default();
+
+        while stats.out_of_order == 0 {
+            if let Some(sequence) = next_frame(&mut events)
+                .await
+                .sequences
+                .get(&DeviceKind::Socket)
+            {
+                stats = *sequence;
+            }
+        }
+
+        assert_eq!(stats.last, 3);
+        assert_eq!(stats.gaps, 1, "Number 2 was missing when 3 arrived");
+
+        let snapshot = server.registry().snapshot();
+        assert!(
+            matches!(&snapshot[..], [SensorData::SocketIndicator(s)] if s.power().get() == 300.0),
+            "Latest reading is the newest one"
+        );
+        assert_eq!(
+            server.registry().stats().backfilled,
+            0,
+            "Late number is not an old reading"
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn backfilled_readings_go_to_history_only() {
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let hour_ago = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .saturating_sub(Duration::from_secs(3600))
+            .as_millis();
+
+        let device = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (reader, mut writer) = device.into_split();
+        let mut replies = BufReader::new(reader).lines();
+
+        let lines = format!(
+            "Socket 100W State: on | seq=1 ts={}\nSocket 200W State: on | seq=2\nSocket 150W State: on | seq=3 ts={}\n",
+            hour_ago,
+            hour_ago + 1000
+        );
+        writer.write_all(lines.as_bytes()).await.unwrap();
+
+        while timeout(Duration::from_secs(5), replies.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .as_deref()
+            != Some("ACK 3")
+        {}
+
+        let power = |data: &SensorData| match data {
+            SensorData::SocketIndicator(s) => s.power().get(),
+            SensorData::TermoIndicator(_) => f32::NAN,
+        };
+
+        let snapshot = server.registry().snapshot();
+        assert_eq!(power(&snapshot[0]), 200.0, "Live value is the current one");
+
+        let history: Vec<f32> = server
+            .registry()
+            .history(DeviceKind::Socket)
+            .iter()
+            .map(|sample| power(&sample.reading))
+            .collect();
+        assert_eq!(history, [100.0, 150.0, 200.0], "History is ordered by time");
+        assert_eq!(server.registry().stats().backfilled, 2);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn restarted_device_counts_from_the_beginning() {
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let power = || match &server.registry().snapshot()[..] {
+            [SensorData::SocketIndicator(s)] => s.power().get(),
+            _ => f32::NAN,
+        };
+
+        let device = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (reader, mut writer) = device.into_split();
+        let mut replies = BufReader::new(reader).lines();
+
+        writer
+            .write_all(b"Socket 100W State: on | seq=500\nSocket 200W State: on | seq=1\n")
+            .await
+            .unwrap();
+
+        while replies.next_line().await.unwrap().as_deref() != Some("ACK 1") {}
+        assert_eq!(power(), 200.0, "Number far behind is a restart");
+
+        let device = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (reader, mut writer) = device.into_split();
+        let mut replies = BufReader::new(reader).lines();
+
+        writer
+            .write_all(b"HELLO kind=socket id=s-1 firmware=1.0 protocols=1 capabilities=power\nSocket 300W State: on | seq=1\n")
+            .await
+            .unwrap();
+
+        while replies.next_line().await.unwrap().as_deref() != Some("ACK 1") {}
+        assert_eq!(power(), 300.0, "Handshake starts the numbers over");
+
+        let stats = server.registry().stats();
+        assert_eq!((stats.duplicates, stats.backfilled), (0, 0));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn largest_sequence_number_is_taken() {
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let device = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (reader, mut writer) = device.into_split();
+        let mut replies = BufReader::new(reader).lines();
+
+        writer
+            .write_all(
+                b"Socket 100W State: on | seq=18446744073709551615
+Socket 100W State: on | seq=18446744073709551615
+Socket 200W State: on | seq=1
+",
+            )
+            .await
+            .unwrap();
+
+        while timeout(Duration::from_secs(5), replies.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .expect("server keeps the connection")
+            != "ACK 1"
+        {}
+
+        let stats = server.registry().sequences()[&DeviceKind::Socket];
+        assert_eq!((stats.last, stats.duplicates), (1, 1));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn device_changing_kind_leaves_the_old_one() {
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let registry = server.registry();
+
+        let mut device = TcpStream::connect(server.local_addr()).await.unwrap();
+        device
+            .write_all(
+                b"Socket 100W State: on
+",
+            )
+            .await
+            .unwrap();
+
+        while !registry.is_connected(DeviceKind::Socket) {
+            tokio::task::yield_now().await;
+        }
+
+        device
+            .write_all(
+                b"Termometer 20C State: on
+",
+            )
+            .await
+            .unwrap();
+
+        while !registry.is_connected(DeviceKind::Termometer) {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(!registry.is_connected(DeviceKind::Socket));
+
+        let off = Command::new(DeviceKind::Socket, DeviceState::new(false));
+        assert!(matches!(registry.send(&off), Ok(Dispatch::Queued)));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn hello_registers_device() {
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let device = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (reader, mut writer) = device.into_split();
+        let mut replies = BufReader::new(reader).lines();
+
+        writer
+            .write_all(b"HELLO kind=termometer id=t-1 firmware=2.0 protocols=1,9 capabilities=temperature\nSocket 1500W State: on | seq=1\n")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            replies.next_line().await.unwrap().as_deref(),
+            Some("WELCOME protocol=1")
+        );
+        assert!(
+            replies
+                .next_line()
+                .await
+                .unwrap()
+                .unwrap()
+                .starts_with("NACK 1 400 "),
+            "Thermometer can't send socket readings"
+        );
+
+        let devices = server.registry().devices();
+        assert!(
+            matches!(&devices[..], [info] if info.id == "t-1" && info.firmware == "2.0"),
+            "Device is known by its handshake"
+        );
+
+        let command = Command::new(DeviceKind::Termometer, DeviceState::new(false));
+        assert!(
+            server.send(&command).is_err(),
+            "Thermometer doesn't accept commands"
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn hello_without_common_protocol_is_refused() {
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let device = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (reader, mut writer) = device.into_split();
+        let mut replies = BufReader::new(reader).lines();
+
+        writer
+            .write_all(b"HELLO kind=socket id=s-1 firmware=9.0 protocols=9 capabilities=switch\n")
+            .await
+            .unwrap();
+
+        let reply = replies.next_line().await.unwrap().unwrap();
+        assert!(reply.starts_with("REFUSED "));
+        assert_eq!(
+            replies.next_line().await.unwrap(),
+            None,
+            "Session is closed"
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn api_client_gets_snapshot() {
+        let (server, mut events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .api("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        let mut device = TcpStream::connect(server.local_addr()).await.unwrap();
+        device
+            .write_all(b"Socket 1500W State: on | seq=2\n")
+            .await
+            .unwrap();
+        next_reading(&mut events).await;
+
+        let client = TcpStream::connect(server.api_addr().unwrap())
+            .await
+            .unwrap();
+        let mut lines = BufReader::new(client).lines();
+
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "SNAPSHOT");
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "STATE Socket 1500W State: on"
+        );
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "SEQUENCE socket last=2 gaps=0 out_of_order=0 duplicates=0"
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn disabled_device_kind_is_rejected() {
+        let (server, mut events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .device_kinds([DeviceKind::Termometer])
+            .start()
+            .await
+            .unwrap();
+
+        let mut device = TcpStream::connect(server.local_addr()).await.unwrap();
+        device.write_all(b"Socket 1500W State: on\n").await.unwrap();
+
+        let frame = next_frame(&mut events).await;
+
+        assert!(frame.readings.is_empty(), "Socket reading is ignored");
+        assert!(matches!(&frame.events[..], [ServerEvent::Rejected(_)]));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn too_long_line_is_rejected() {
+        let limits = Limits {
+            max_line_length: 32,
+            ..Limits::default()
+        };
+
+        let (server, mut events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .limits(limits)
+            .start()
+            .await
+            .unwrap();
+
+        let mut device = TcpStream::connect(server.local_addr()).await.unwrap();
+        device.write_all(&[b'1'; 64]).await.unwrap();
+
+        let frame = next_frame(&mut events).await;
+
+        assert!(matches!(&frame.events[..], [ServerEvent::Rejected(_)]));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn event_stream_ends_after_shutdown() {
+        let (server, mut events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        server.shutdown().await;
+
+        let end = timeout(Duration::from_secs(5), async {
+            while events.next().await.is_some() {}
+        })
+        .await;
+
+        assert!(end.is_ok(), "Stream is finished");
+    }
+}
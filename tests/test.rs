@@ -35,7 +35,7 @@ mod socket_tests {
 
         let result = Socket::from_str(message);
 
-        assert!(!result.is_ok(), "Got an error");
+        assert!(result.is_err(), "Got an error");
     }
 }
 
@@ -103,6 +103,132 @@ mod termometer_test {
 
         let termometer = Termometer::from_str(message);
 
-        assert!(!termometer.is_ok(), "Got an error");
+        assert!(termometer.is_err(), "Got an error");
+    }
+}
+
+#[cfg(test)]
+mod protocol_tests {
+    use otus_iced::{
+        device::{Capability, DeviceConfig, DeviceKind},
+        protocol::{CommandReply, Envelope, Hello, HelloReply, NackCode, Reply},
+    };
+
+    #[test]
+    fn envelope_with_sequence_number() {
+        let envelope: Envelope = "Socket 1500W State: on | seq=12".parse().unwrap();
+
+        assert_eq!(envelope.payload, "Socket 1500W State: on");
+        assert_eq!(envelope.seq, Some(12), "Sequence number is parsed");
+        assert_eq!(envelope.to_string(), "Socket 1500W State: on | seq=12");
+    }
+
+    #[test]
+    fn envelope_with_device_timestamp() {
+        let line = "Termometer 21.5C State: on | seq=4 ts=1700000000000";
+        let envelope: Envelope = line.parse().unwrap();
+
+        assert_eq!(envelope.ts, Some(1_700_000_000_000), "Timestamp is parsed");
+        assert_eq!(envelope.to_string(), line);
+    }
+
+    #[test]
+    fn legacy_line_has_no_headers() {
+        let envelope: Envelope = "Socket 1500W State: on".parse().unwrap();
+
+        assert_eq!(envelope.payload, "Socket 1500W State: on");
+        assert_eq!(envelope.seq, None);
+    }
+
+    #[test]
+    fn unknown_headers_are_skipped() {
+        let envelope: Envelope = "Socket 1500W State: on | seq=3 zone=kitchen"
+            .parse()
+            .unwrap();
+
+        assert_eq!(envelope.seq, Some(3));
+    }
+
+    #[test]
+    fn nack_carries_code_and_reason() {
+        let reply: Reply = "NACK 7 400 не удалось разобрать сообщение".parse().unwrap();
+
+        assert_eq!(
+            reply,
+            Reply::Nack {
+                seq: 7,
+                code: NackCode::Malformed,
+                reason: "не удалось разобрать сообщение".into()
+            }
+        );
+        assert_eq!("ACK 7".parse::<Reply>().unwrap(), Reply::Ack(7));
+    }
+
+    #[test]
+    fn hello_announces_device() {
+        let line = "HELLO kind=socket id=socket-1 firmware=0.1.0 protocols=1,2 capabilities=switch,power,energy,humidity";
+        let hello: Hello = line.parse().unwrap();
+
+        assert_eq!(hello.kind, DeviceKind::Socket);
+        assert_eq!(hello.id, "socket-1");
+        assert_eq!(hello.protocols, [1, 2]);
+        assert_eq!(
+            hello.capabilities,
+            [
+                Capability::Switch,
+                Capability::ReportsPower,
+                Capability::ReportsEnergy
+            ],
+            "Unknown capabilities are skipped"
+        );
+
+        assert_eq!(hello.negotiate(&[1]), Some(1), "Newest common version wins");
+        assert_eq!(hello.negotiate(&[3]), None);
+    }
+
+    #[test]
+    fn welcome_carries_protocol_version() {
+        assert_eq!(
+            "WELCOME protocol=1".parse::<HelloReply>().unwrap(),
+            HelloReply::Welcome { protocol: 1 }
+        );
+    }
+
+    #[test]
+    fn device_config_round_trips() {
+        let config: DeviceConfig = "interval=10 deadband=0.5 max_power=2000".parse().unwrap();
+
+        assert_eq!(config.interval, 10);
+        assert_eq!(config.max_power, Some(2000.0));
+        assert_eq!(
+            config.to_string(),
+            "interval=10 deadband=0.5 max_power=2000"
+        );
+
+        assert!(
+            "interval=10 deadband=-1".parse::<DeviceConfig>().is_err(),
+            "Deadband can't be negative"
+        );
+
+        for max_power in ["100", "5000", "NaN"] {
+            let config = format!("interval=10 deadband=0.5 max_power={max_power}");
+            assert!(
+                config.parse::<DeviceConfig>().is_err(),
+                "Max power {max_power} is out of range"
+            );
+        }
+    }
+
+    #[test]
+    fn command_reply_carries_id_and_state() {
+        let command: Envelope = "SET off | id=7".parse().unwrap();
+        assert_eq!(command.id, Some(7));
+
+        let reply: CommandReply = "DONE 7 off".parse().unwrap();
+        assert!(matches!(reply, CommandReply::Done { id: 7, ref state } if !state.get()));
+
+        let reply: CommandReply = "FAILED 8 перегрузка".parse().unwrap();
+        assert_eq!(reply.id(), 8);
+        assert_eq!(reply.to_string(), "FAILED 8 перегрузка");
     }
 }
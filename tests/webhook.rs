@@ -0,0 +1,212 @@
+mod common;
+
+#[cfg(test)]
+mod webhook_tests {
+    use std::time::Duration;
+
+    use hmac::{Hmac, KeyInit, Mac};
+    use otus_iced::{
+        device::DeviceKind,
+        server::{ApiLine, DeviceServer, ServerHandle, WebhookConfig, WebhookTrigger},
+    };
+    use sha2::Sha256;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+        time::timeout,
+    };
+
+    use crate::common::http_request;
+
+    async fn start(listener: &TcpListener, setup: impl FnOnce(&mut WebhookConfig)) -> ServerHandle {
+        let url = format!("http://{}/hooks/house", listener.local_addr().unwrap());
+        let mut webhook = WebhookConfig::new(url).unwrap();
+        webhook.retry_delay = Duration::from_millis(10);
+        setup(&mut webhook);
+
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .api("127.0.0.1:0")
+            .webhook(webhook)
+            .start()
+            .await
+            .unwrap();
+
+        server
+    }
+
+    #[test]
+    fn triggers_are_parsed() {
+        for trigger in [
+            "socket:on",
+            "socket:off",
+            "termometer:offline",
+            "termometer>30",
+        ] {
+            let parsed: WebhookTrigger = trigger.parse().unwrap();
+            assert_eq!(parsed.to_string(), trigger);
+        }
+
+        assert_eq!(
+            "socket<5.5".parse::<WebhookTrigger>().unwrap(),
+            WebhookTrigger::Below(DeviceKind::Socket, 5.5)
+        );
+        assert!("socket:boiling".parse::<WebhookTrigger>().is_err());
+        assert!("kettle:on".parse::<WebhookTrigger>().is_err());
+        assert!(WebhookConfig::new("https://example.com/hook").is_err());
+    }
+
+    #[tokio::test]
+    async fn socket_turning_on_posts_signed_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = start(&listener, |webhook| {
+            webhook.triggers.push("socket:on".parse().unwrap());
+            webhook.secret = Some("s3cret".into());
+        })
+        .await;
+
+        let registry = server.registry();
+        registry.publish("Socket 0W State: off".parse().unwrap());
+        registry.publish("Socket 1500W State: on".parse().unwrap());
+
+        let (head, body) = http_request(&listener, "200 OK").await;
+
+        assert!(head.starts_with("POST /hooks/house HTTP/1.1\r\n"));
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+        mac.update(body.as_bytes());
+        let expected: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+
+        assert!(head.contains(&format!("X-Signature: sha256={expected}\r\n")));
+
+        let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(payload["event"], "socket:on");
+        assert_eq!(payload["device"], "socket");
+        assert_eq!(payload["on"], true);
+        assert_eq!(payload["value"], 1500.0);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn threshold_fires_once_per_crossing_with_template() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = start(&listener, |webhook| {
+            webhook.triggers.push("termometer>30".parse().unwrap());
+            webhook.template = Some(r#"{"text": "{{device}}: {{value}} C"}"#.into());
+        })
+        .await;
+
+        let registry = server.registry();
+
+        for temperature in [25.0, 31.0, 32.0, 29.0, 33.0] {
+            registry.publish(
+                format!("Termometer {temperature}C State: on")
+                    .parse()
+                    .unwrap(),
+            );
+        }
+
+        let (_, first) = http_request(&listener, "200 OK").await;
+        let (_, second) = http_request(&listener, "200 OK").await;
+
+        assert_eq!(first, r#"{"text": "termometer: 31 C"}"#);
+        assert_eq!(second, r#"{"text": "termometer: 33 C"}"#);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn template_values_are_escaped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = start(&listener, |webhook| {
+            webhook.triggers.push("socket:on".parse().unwrap());
+            webhook.template = Some(r#"{"text": "{{id}} is on"}"#.into());
+        })
+        .await;
+
+        let registry = server.registry();
+        registry.register(
+            r#"kind=socket id=s"1\x firmware=0.1.0 protocol=1 capabilities=switch"#
+                .parse()
+                .unwrap(),
+        );
+        registry.publish("Socket 0W State: off".parse().unwrap());
+        registry.publish("Socket 1500W State: on".parse().unwrap());
+
+        let (_, body) = http_request(&listener, "200 OK").await;
+        let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(payload["text"], r#"s"1\x is on"#);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_is_retried_and_logged() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = start(&listener, |webhook| {
+            webhook.triggers.push("socket:offline".parse().unwrap());
+        })
+        .await;
+
+        let api = TcpStream::connect(server.api_addr().unwrap())
+            .await
+            .unwrap();
+        let mut api = BufReader::new(api).lines();
+        assert_eq!(api.next_line().await.unwrap().unwrap(), "SNAPSHOT");
+
+        let mut device = TcpStream::connect(server.local_addr()).await.unwrap();
+        device.write_all(b"Socket 1500W State: on\n").await.unwrap();
+
+        while !server.registry().is_connected(DeviceKind::Socket) {
+            tokio::task::yield_now().await;
+        }
+        drop(device);
+
+        let (_, body) = http_request(&listener, "500 Internal Server Error").await;
+        let (_, retried) = http_request(&listener, "204 No Content").await;
+
+        assert_eq!(body, retried, "Same event is retried");
+
+        let delivery = timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(delivery) = server.registry().deliveries().pop() {
+                    return delivery;
+                }
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("delivery is logged");
+
+        assert_eq!(delivery.event, "socket:offline");
+        assert!(delivery.delivered);
+        assert_eq!(delivery.attempts, 2);
+        assert_eq!(delivery.status, Some(204));
+
+        let line = timeout(Duration::from_secs(5), async {
+            loop {
+                let line = api.next_line().await.unwrap().unwrap();
+
+                if line.starts_with("DELIVERY ") {
+                    return line;
+                }
+            }
+        })
+        .await
+        .expect("delivery reaches API clients");
+
+        assert!(
+            matches!(line.parse::<ApiLine>(), Ok(ApiLine::Delivery(sent)) if sent == delivery),
+            "{line}"
+        );
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,311 @@
+#[cfg(test)]
+mod websocket_tests {
+    use std::time::Duration;
+
+    use otus_iced::{
+        device::DeviceKind,
+        server::{DeviceServer, FeedMessage, FeedRequest, ServerEvent, ServerHandle},
+    };
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{
+            TcpStream,
+            tcp::{OwnedReadHalf, OwnedWriteHalf},
+        },
+        time::timeout,
+    };
+
+    // The key and answer from the example in RFC 6455.
+    const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+    const ACCEPT: &str = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+    // Just enough of a browser to talk to the feed.
+    struct FeedClient {
+        reader: BufReader<OwnedReadHalf>,
+        writer: OwnedWriteHalf,
+    }
+
+    impl FeedClient {
+        async fn connect(server: &ServerHandle) -> Self {
+            let tcp = TcpStream::connect(server.http_addr().unwrap())
+                .await
+                .unwrap();
+            let (reader, mut writer) = tcp.into_split();
+
+            let handshake = format!(
+                "GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Key: {KEY}\r\n\
+                 Sec-WebSocket-Version: 13\r\n\r\n"
+            );
+            writer.write_all(handshake.as_bytes()).await.unwrap();
+
+            let mut reader = BufReader::new(reader);
+            let mut head = Vec::new();
+
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+
+                if line == "\r\n" {
+                    break;
+                }
+
+                head.push(line.trim_end().to_string());
+            }
+
+            assert_eq!(head[0], "HTTP/1.1 101 Switching Protocols");
+            assert!(head.contains(&format!("Sec-WebSocket-Accept: {ACCEPT}")));
+
+            Self { reader, writer }
+        }
+
+        async fn send(&mut self, request: &FeedRequest) {
+            let payload = serde_json::to_vec(request).unwrap();
+            self.send_frame(0x81, &payload).await;
+        }
+
+        // `head` is the first byte: FIN and the opcode.
+        async fn send_frame(&mut self, head: u8, payload: &[u8]) {
+            let mask = [0x12, 0x34, 0x56, 0x78];
+
+            assert!(payload.len() < 126, "short frames only");
+
+            let mut frame = vec![head, 0x80 | payload.len() as u8];
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+
+            self.writer.write_all(&frame).await.unwrap();
+        }
+
+        async fn next(&mut self) -> FeedMessage {
+            timeout(Duration::from_secs(5), self.read_message())
+                .await
+                .expect("message in time")
+        }
+
+        // Pongs are skipped.
+        async fn read_message(&mut self) -> FeedMessage {
+            loop {
+                let mut head = [0; 2];
+                self.reader.read_exact(&mut head).await.unwrap();
+
+                let len = match head[1] {
+                    126 => self.reader.read_u16().await.unwrap() as usize,
+                    127 => self.reader.read_u64().await.unwrap() as usize,
+                    len => len as usize,
+                };
+
+                let mut payload = vec![0; len];
+                self.reader.read_exact(&mut payload).await.unwrap();
+
+                if head[0] == 0x8A {
+                    continue;
+                }
+
+                assert_eq!(head[0], 0x81, "unfragmented text frame");
+
+                return serde_json::from_slice(&payload).unwrap();
+            }
+        }
+    }
+
+    async fn start() -> ServerHandle {
+        let (server, _events) = DeviceServer::new()
+            .bind("127.0.0.1:0")
+            .http("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+
+        server
+    }
+
+    #[tokio::test]
+    async fn feed_starts_with_snapshot_and_pushes_changes() {
+        let server = start().await;
+        let mut feed = FeedClient::connect(&server).await;
+
+        assert!(matches!(
+            feed.next().await,
+            FeedMessage::Snapshot { devices } if devices.len() == DeviceKind::ALL.len()
+        ));
+
+        server
+            .registry()
+            .publish("Socket 1500W State: on".parse().unwrap());
+
+        loop {
+            if let FeedMessage::Device { device } = feed.next().await {
+                assert_eq!(device.kind, "socket");
+                assert_eq!(device.reading.unwrap().power, Some(1500.0));
+                break;
+            }
+        }
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn subscription_filters_devices() {
+        let server = start().await;
+        let mut feed = FeedClient::connect(&server).await;
+        feed.next().await;
+
+        feed.send(&FeedRequest::Subscribe {
+            devices: vec!["termometer".into()],
+        })
+        .await;
+
+        assert!(matches!(
+            feed.next().await,
+            FeedMessage::Snapshot { devices } if devices.len() == 1 && devices[0].kind == "termometer"
+        ));
+
+        let registry = server.registry();
+        registry.publish("Socket 1500W State: on".parse().unwrap());
+        registry.publish("Termometer 21.5C State: on".parse().unwrap());
+
+        loop {
+            match feed.next().await {
+                FeedMessage::Device { device } => {
+                    assert_eq!(device.kind, "termometer", "Socket is filtered out");
+                    break;
+                }
+                FeedMessage::Toggled { device, .. } => assert_eq!(device, "termometer"),
+                _ => {}
+            }
+        }
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn every_change_is_sent_once() {
+        let server = start().await;
+        let mut feed = FeedClient::connect(&server).await;
+        feed.next().await;
+
+        // The notice comes after everything the reading changed.
+        let registry = server.registry();
+        registry.publish("Socket 1500W State: on".parse().unwrap());
+        registry.report(ServerEvent::Notice("готово".into()));
+
+        let mut messages = Vec::new();
+        loop {
+            match feed.next().await {
+                FeedMessage::Event { message } if message.contains("готово") => break,
+                message => messages.push(message),
+            }
+        }
+
+        assert!(
+            matches!(messages.as_slice(), [FeedMessage::Device { .. }]),
+            "One message for the reading: {messages:?}"
+        );
+
+        feed.send_frame(0x89, b"ping").await;
+        feed.send(&FeedRequest::Subscribe {
+            devices: vec!["socket".into()],
+        })
+        .await;
+
+        assert!(
+            matches!(feed.next().await, FeedMessage::Snapshot { .. }),
+            "Nothing is sent again after a ping"
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn fragmented_binary_message_is_skipped() {
+        let server = start().await;
+        let mut feed = FeedClient::connect(&server).await;
+        feed.next().await;
+
+        feed.send_frame(0x02, b"\x00\x01").await;
+        feed.send_frame(0x80, b"\x02\x03").await;
+
+        let request = serde_json::to_vec(&FeedRequest::Subscribe {
+            devices: vec!["termometer".into()],
+        })
+        .unwrap();
+        let (first, rest) = request.split_at(request.len() / 2);
+
+        feed.send_frame(0x01, first).await;
+        feed.send_frame(0x80, rest).await;
+
+        assert!(matches!(
+            feed.next().await,
+            FeedMessage::Snapshot { devices } if devices.len() == 1 && devices[0].kind == "termometer"
+        ));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn command_result_is_sent_back() {
+        let server = start().await;
+
+        let device = TcpStream::connect(server.local_addr()).await.unwrap();
+        let (reader, mut writer) = device.into_split();
+        writer.write_all(b"Socket 1500W State: on\n").await.unwrap();
+
+        while !server.registry().is_connected(DeviceKind::Socket) {
+            tokio::task::yield_now().await;
+        }
+
+        let mut feed = FeedClient::connect(&server).await;
+        feed.next().await;
+
+        feed.send(&FeedRequest::Command {
+            id: Some(7),
+            device: "socket".into(),
+            state: "off".into(),
+        })
+        .await;
+
+        let mut line = String::new();
+        let mut reader = BufReader::new(reader);
+        timeout(Duration::from_secs(5), reader.read_line(&mut line))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(line, "SET off\n");
+        writer.write_all(b"Socket 0W State: off\n").await.unwrap();
+
+        loop {
+            if let FeedMessage::Result {
+                id, status, state, ..
+            } = feed.next().await
+            {
+                assert_eq!(id, Some(7));
+                assert_eq!(status, "done");
+                assert_eq!(state.as_deref(), Some("off"));
+                break;
+            }
+        }
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn plain_request_to_feed_is_refused() {
+        let server = start().await;
+
+        let mut tcp = TcpStream::connect(server.http_addr().unwrap())
+            .await
+            .unwrap();
+        tcp.write_all(b"GET /ws HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        tcp.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 426"));
+
+        server.shutdown().await;
+    }
+}